@@ -0,0 +1,114 @@
+#[cfg(not(target_os = "linux"))]
+compile_error!("bandwidth uses tc and ifb devices, which are only available on Linux");
+
+use cni_plugin::{
+	config::{BandwidthLimits, NetworkConfig},
+	error::CniError,
+	logger,
+	reply::{reply, Interface, SuccessReply},
+	Cni, Command, Inputs,
+};
+use log::{debug, error, info};
+
+mod tc;
+
+fn main() {
+	logger::install(env!("CARGO_PKG_NAME"));
+	debug!(
+		"{} (CNI post plugin) version {}",
+		env!("CARGO_PKG_NAME"),
+		env!("CARGO_PKG_VERSION")
+	);
+
+	// UNWRAP: unreachable due to using load()
+	let Inputs {
+		command, config, ..
+	} = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
+
+	let cni_version = config.cni_version.clone(); // for error
+	info!(
+		"{} serving spec v{} for command={}",
+		env!("CARGO_PKG_NAME"),
+		cni_version,
+		command
+	);
+
+	match run(command, &config) {
+		Ok(res) => reply(res),
+		Err(res) => {
+			error!("error: {}", res);
+			reply(res.into_reply(cni_version))
+		}
+	}
+}
+
+fn run(command: Command, config: &NetworkConfig) -> Result<SuccessReply, CniError> {
+	let reply = SuccessReply::passthrough(config)?;
+
+	if matches!(command, Command::Del) {
+		// limits may have been applied by a previous configuration, so always clean up
+		match host_interface(&reply.interfaces) {
+			Some(host) => {
+				tc::remove_bandwidth(&host.name);
+				info!("removed bandwidth limits from {}", host.name);
+			}
+			None => info!("no host interface in prevResult, nothing to remove"),
+		}
+
+		return Ok(reply);
+	}
+
+	let limits = match config.runtime.as_ref().and_then(|rt| rt.bandwidth.as_ref()) {
+		Some(limits) if has_limits(limits) => limits,
+		_ => {
+			info!("no bandwidth limits, nothing to do");
+			return Ok(reply);
+		}
+	};
+	debug!("limits={:?}", limits);
+
+	let host = host_interface(&reply.interfaces)
+		.ok_or_else(|| CniError::MissingInterface("host end of the veth pair".into()))?;
+
+	if matches!(command, Command::Check) {
+		tc::check_bandwidth(&host.name, limits)?;
+		info!("bandwidth limits are in place on {}", host.name);
+		return Ok(reply);
+	}
+
+	tc::apply_bandwidth(&host.name, limits)?;
+	info!("applied bandwidth limits on {}", host.name);
+
+	Ok(reply)
+}
+
+fn has_limits(limits: &BandwidthLimits) -> bool {
+	limits.ingress_rate.is_some()
+		|| limits.ingress_burst.is_some()
+		|| limits.egress_rate.is_some()
+		|| limits.egress_burst.is_some()
+}
+
+/// The host end of the veth pair, which is the interface without a sandbox.
+fn host_interface(interfaces: &[Interface]) -> Option<&Interface> {
+	interfaces
+		.iter()
+		.find(|iface| iface.sandbox.as_os_str().is_empty())
+}
+
+#[test]
+fn test_host_interface() {
+	let iface = |name: &str, sandbox: &str| Interface {
+		name: name.into(),
+		mac: None,
+		sandbox: sandbox.into(),
+	};
+	let interfaces = vec![
+		iface("eth0", "/var/run/netns/test"),
+		iface("veth1234abcd", ""),
+	];
+	assert_eq!(host_interface(&interfaces).unwrap().name, "veth1234abcd");
+	assert!(host_interface(&interfaces[..1]).is_none());
+}
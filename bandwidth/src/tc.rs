@@ -0,0 +1,313 @@
+//! Traffic control with the `tc` and `ip` tools from iproute2.
+//!
+//! These are shelled out to, and must be on the `PATH`, as rtnetlink doesn't
+//! yet expose qdisc creation.
+//!
+//! Limits are given from the point of view of the container, and applied on
+//! the host end of its veth pair:
+//! - ingress limits shape traffic sent by the host end (so received by the
+//!   container), with a `tbf` root qdisc;
+//! - egress limits shape traffic received by the host end (so sent by the
+//!   container), by redirecting it to an `ifb` device which has a `tbf` root
+//!   qdisc.
+
+use std::process::Command;
+
+use cni_plugin::{config::BandwidthLimits, error::CniError};
+use log::{debug, warn};
+
+/// Latency parameter for the `tbf` qdiscs, as used by the reference bandwidth plugin.
+const TBF_LATENCY: &str = "25ms";
+
+/// Rate and burst for a `tbf` qdisc, formatted for `tc`.
+type TbfParams = (String, String);
+
+/// Validates the limits, returning the ingress and egress `tbf` parameters.
+///
+/// Only the directions which have a rate set are limited.
+pub fn tbf_limits(
+	limits: &BandwidthLimits,
+) -> Result<(Option<TbfParams>, Option<TbfParams>), CniError> {
+	Ok((
+		tbf_params(
+			limits.ingress_rate,
+			limits.ingress_burst,
+			"runtimeConfig.bandwidth.ingressBurst",
+			"runtimeConfig.bandwidth.ingressRate",
+		)?,
+		tbf_params(
+			limits.egress_rate,
+			limits.egress_burst,
+			"runtimeConfig.bandwidth.egressBurst",
+			"runtimeConfig.bandwidth.egressRate",
+		)?,
+	))
+}
+
+/// Applies bandwidth limits on the host end of a container's veth pair.
+///
+/// Any existing limits set by this function on the interface are replaced.
+///
+/// # Errors
+///
+/// This errors if:
+/// - a rate is set without its burst, or the reverse;
+/// - the `tc` or `ip` tools can't be run, or fail.
+pub fn apply_bandwidth(host_ifname: &str, limits: &BandwidthLimits) -> Result<(), CniError> {
+	let (ingress, egress) = tbf_limits(limits)?;
+
+	if let Some((rate, burst)) = ingress {
+		debug!(
+			"limiting traffic to the container via {} to rate={} burst={}",
+			host_ifname, rate, burst
+		);
+		tbf(host_ifname, &rate, &burst)?;
+	}
+
+	if let Some((rate, burst)) = egress {
+		debug!(
+			"limiting traffic from the container via {} to rate={} burst={}",
+			host_ifname, rate, burst
+		);
+		let ifb = ifb_name(host_ifname);
+
+		if run("ip", &["link", "show", "dev", &ifb]).is_err() {
+			run("ip", &["link", "add", &ifb, "type", "ifb"])?;
+		}
+		run("ip", &["link", "set", &ifb, "up"])?;
+
+		// replacing the ingress qdisc would keep its filters, so start afresh
+		run("tc", &["qdisc", "del", "dev", host_ifname, "ingress"]).ok();
+		run("tc", &["qdisc", "add", "dev", host_ifname, "ingress"])?;
+		run(
+			"tc",
+			&[
+				"filter",
+				"add",
+				"dev",
+				host_ifname,
+				"parent",
+				"ffff:",
+				"protocol",
+				"all",
+				"u32",
+				"match",
+				"u32",
+				"0",
+				"0",
+				"action",
+				"mirred",
+				"egress",
+				"redirect",
+				"dev",
+				&ifb,
+			],
+		)?;
+
+		tbf(&ifb, &rate, &burst)?;
+	}
+
+	Ok(())
+}
+
+/// Checks that the limits applied by [`apply_bandwidth`] are in place.
+///
+/// This only checks that the `tbf` qdiscs exist, not their parameters.
+pub fn check_bandwidth(host_ifname: &str, limits: &BandwidthLimits) -> Result<(), CniError> {
+	let (ingress, egress) = tbf_limits(limits)?;
+
+	if ingress.is_some() {
+		check_tbf(host_ifname)?;
+	}
+
+	if egress.is_some() {
+		check_tbf(&ifb_name(host_ifname))?;
+	}
+
+	Ok(())
+}
+
+/// Removes bandwidth limits applied by [`apply_bandwidth`].
+///
+/// This is best-effort, for use with the `DEL` command: failures (including
+/// there being no limits to remove) are logged but not returned.
+///
+/// Qdiscs which this didn't create are left alone: the root qdisc is only
+/// removed if it's a `tbf`, and the ingress qdisc only if it redirects to the
+/// interface's ifb device.
+pub fn remove_bandwidth(host_ifname: &str) {
+	let ifb = ifb_name(host_ifname);
+
+	if check_tbf(host_ifname).is_ok() {
+		if let Err(err) = run("tc", &["qdisc", "del", "dev", host_ifname, "root"]) {
+			warn!("while removing bandwidth limits: {}", err);
+		}
+	} else {
+		debug!("no tbf root qdisc on {}, leaving it", host_ifname);
+	}
+
+	match output(
+		"tc",
+		&["filter", "show", "dev", host_ifname, "parent", "ffff:"],
+	) {
+		Ok(filters) if redirects_to(&filters, &ifb) => {
+			if let Err(err) = run("tc", &["qdisc", "del", "dev", host_ifname, "ingress"]) {
+				warn!("while removing bandwidth limits: {}", err);
+			}
+		}
+		Ok(_) => debug!("no redirect to {} on {}, leaving it", ifb, host_ifname),
+		Err(err) => debug!("while listing filters: {}", err),
+	}
+
+	if let Err(err) = run("ip", &["link", "del", &ifb]) {
+		warn!("while removing bandwidth limits: {}", err);
+	}
+}
+
+/// Validates a rate and burst pair, and formats them for `tc`.
+///
+/// Rate is in bits per second, burst is in bits, and `tc` wants bytes for the
+/// latter.
+fn tbf_params(
+	rate: Option<usize>,
+	burst: Option<usize>,
+	burst_field: &'static str,
+	rate_field: &'static str,
+) -> Result<Option<TbfParams>, CniError> {
+	match (rate, burst) {
+		(None, None) => Ok(None),
+		(Some(_), None) => Err(CniError::MissingField(burst_field)),
+		(None, Some(_)) => Err(CniError::MissingField(rate_field)),
+		(Some(rate), Some(burst)) => Ok(Some((
+			format!("{}bit", rate),
+			// tc rejects a zero-sized bucket
+			format!("{}", (burst / 8).max(1)),
+		))),
+	}
+}
+
+fn tbf(ifname: &str, rate: &str, burst: &str) -> Result<(), CniError> {
+	run(
+		"tc",
+		&[
+			"qdisc",
+			"replace",
+			"dev",
+			ifname,
+			"root",
+			"tbf",
+			"rate",
+			rate,
+			"burst",
+			burst,
+			"latency",
+			TBF_LATENCY,
+		],
+	)
+}
+
+fn check_tbf(ifname: &str) -> Result<(), CniError> {
+	let qdiscs = output("tc", &["qdisc", "show", "dev", ifname, "root"])?;
+	if is_tbf(&qdiscs) {
+		Ok(())
+	} else {
+		Err(CniError::Generic(format!(
+			"no bandwidth limit on {}, found: {}",
+			ifname,
+			qdiscs.trim()
+		)))
+	}
+}
+
+/// Whether `tc qdisc show ... root` output is a `tbf` qdisc.
+fn is_tbf(qdiscs: &str) -> bool {
+	qdiscs.contains("qdisc tbf ")
+}
+
+/// Whether `tc filter show` output has a redirect to the `ifb` device.
+fn redirects_to(filters: &str, ifb: &str) -> bool {
+	filters.contains(&format!("Redirect to device {})", ifb))
+}
+
+/// The name of the ifb device used for egress limits on an interface.
+///
+/// Interface names are limited to 15 bytes, so rather than include the name
+/// (and truncate it, mapping interfaces with a common prefix to the same ifb),
+/// this is `ifb` followed by 12 hex digits of a hash of the whole name. The
+/// hash is FNV-1a, which unlike the std hasher is stable across builds, as the
+/// DEL may be handled by a different build of the plugin than the ADD was.
+fn ifb_name(ifname: &str) -> String {
+	let hash = ifname
+		.bytes()
+		.fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+			(hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+		});
+	format!("ifb{:012x}", hash >> 16)
+}
+
+fn run(prog: &str, args: &[&str]) -> Result<(), CniError> {
+	output(prog, args).map(drop)
+}
+
+fn output(prog: &str, args: &[&str]) -> Result<String, CniError> {
+	debug!("running {} {}", prog, args.join(" "));
+	let output = Command::new(prog).args(args).output()?;
+	if output.status.success() {
+		Ok(String::from_utf8_lossy(&output.stdout).into())
+	} else {
+		Err(CniError::Generic(format!(
+			"{} {} failed with {}: {}",
+			prog,
+			args.join(" "),
+			output.status,
+			String::from_utf8_lossy(&output.stderr).trim()
+		)))
+	}
+}
+
+#[test]
+fn test_tbf_params() {
+	assert_eq!(tbf_params(None, None, "b", "r").unwrap(), None);
+	assert_eq!(
+		tbf_params(Some(1_000_000), Some(80_000), "b", "r").unwrap(),
+		Some(("1000000bit".into(), "10000".into()))
+	);
+	assert!(matches!(
+		tbf_params(Some(1_000_000), None, "b", "r"),
+		Err(CniError::MissingField("b"))
+	));
+	assert!(matches!(
+		tbf_params(None, Some(80_000), "b", "r"),
+		Err(CniError::MissingField("r"))
+	));
+}
+
+#[test]
+fn test_ifb_name() {
+	let name = ifb_name("veth1234abcd");
+	assert_eq!(name.len(), 15);
+	assert!(name.starts_with("ifb"));
+	assert_eq!(name, ifb_name("veth1234abcd"));
+
+	// a shared prefix beyond the length limit doesn't collide
+	assert_ne!(name, ifb_name("veth1234abce"));
+	assert_ne!(ifb_name("veth0123456789ab"), ifb_name("veth0123456789ac"));
+}
+
+#[test]
+fn test_tc_output() {
+	assert!(is_tbf(
+		"qdisc tbf 8001: root refcnt 2 rate 1Mbit burst 10000b lat 25.0ms \n"
+	));
+	assert!(!is_tbf("qdisc noqueue 0: root refcnt 2 \n"));
+	assert!(!is_tbf(
+		"qdisc fq_codel 0: root refcnt 2 limit 10240p flows 1024 \n"
+	));
+
+	let filters = "filter parent ffff: protocol all pref 49152 u32 chain 0 fh 800::800 order 2048 key ht 800 bkt 0 terminal flowid ??? not_in_hw
+  match 00000000/00000000 at 0
+	action order 1: mirred (Egress Redirect to device ifb0123456789ab) stolen
+";
+	assert!(!redirects_to(filters, "ifb0123456789ac"));
+	assert!(redirects_to(filters, "ifb0123456789ab"));
+}
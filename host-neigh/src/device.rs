@@ -0,0 +1,60 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum Device {
+	Index(u32),
+	Name(String),
+}
+
+impl fmt::Display for Device {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Index(i) => write!(f, "#{}", i),
+			Self::Name(n) => n.fmt(f),
+		}
+	}
+}
+
+impl From<&str> for Device {
+	fn from(name: &str) -> Self {
+		Self::Name(name.into())
+	}
+}
+
+#[test]
+fn test_with_index() {
+	use crate::Neigh;
+
+	let s = Neigh {
+		address: "1.2.3.4".parse().unwrap(),
+		device: Device::Index(3),
+		lladdr: None,
+	};
+
+	let j = serde_json::json!({
+		"address": "1.2.3.4",
+		"device": 3,
+	});
+
+	assert_eq!(serde_json::to_value(&s).unwrap(), j);
+	assert_eq!(serde_json::from_value::<Neigh>(j).unwrap(), s);
+}
+
+#[test]
+fn test_with_numeric_name() {
+	use crate::Neigh;
+
+	// interface names can be all digits, so strings are always names
+	let j = serde_json::json!({
+		"address": "1.2.3.4",
+		"device": "3",
+	});
+
+	assert_eq!(
+		serde_json::from_value::<Neigh>(j).unwrap().device,
+		Device::Name("3".into())
+	);
+}
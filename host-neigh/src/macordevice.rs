@@ -1,12 +1,15 @@
-use std::{convert::TryInto, fmt};
+use std::{collections::HashMap, fmt, sync::Arc};
 
-use cni_plugin::{error::CniError, macaddr::MacAddr};
-use futures::stream::TryStreamExt;
-use log::{debug, info};
-use macaddr::MacAddr6;
-use rtnetlink::LinkHandle;
+use async_std::sync::Mutex;
+
+use cni_plugin::{error::CniError, macaddr::MacAddr, netlink::resolve_link};
+use log::debug;
+use rtnetlink::Handle;
 use serde::{Deserialize, Serialize};
 
+/// Device names resolved to their MAC address, shared by all trials of a run.
+pub type MacCache = Arc<Mutex<HashMap<String, MacAddr>>>;
+
 #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 #[serde(untagged)]
 pub enum MacOrDevice {
@@ -31,55 +34,56 @@ impl MacOrDevice {
 		}
 	}
 
-	pub async fn resolve(&mut self, nllh: &mut LinkHandle) -> Result<(), CniError> {
-		use rtnetlink::packet::rtnl::link::nlas::Nla;
+	/// Like [`resolve()`](Self::resolve), but looks up and fills the cache.
+	///
+	/// The cache is locked while resolving, so concurrent trials don't resolve
+	/// the same device twice.
+	pub async fn resolve_cached(
+		&mut self,
+		netlink: &Handle,
+		cache: &MacCache,
+	) -> Result<(), CniError> {
+		let name = match self {
+			Self::Device(name) => name.clone(),
+			Self::Mac(_) => return Ok(()),
+		};
+
+		let mut cache = cache.lock().await;
+		if let Some(mac) = cache.get(&name) {
+			debug!("got mac address for device {} from cache: {}", name, mac);
+			*self = Self::Mac(*mac);
+			return Ok(());
+		}
+
+		self.resolve(netlink).await?;
+		if let Self::Mac(mac) = self {
+			cache.insert(name, *mac);
+		}
 
+		Ok(())
+	}
+
+	pub async fn resolve(&mut self, netlink: &Handle) -> Result<(), CniError> {
 		if let Self::Device(ref name) = self {
 			debug!("resolving device {} to mac address", name);
 
-			let mut linklist = nllh.get().set_name_filter(name.clone()).execute();
-			if let Some(link) = linklist.try_next().await.map_err(crate::nlerror)? {
-				info!("link: {:?}", link.header);
-				let addr = link
-					.nlas
-					.iter()
-					.filter_map(|n| {
-						if let Nla::Address(bytes) = n {
-							Some(bytes.clone())
-						} else {
-							None
-						}
-					})
-					.next()
-					.ok_or_else(|| CniError::Generic(format!("no address on link {}", name)))?;
-
-				let addr: [u8; 6] = addr.try_into().map_err(|addr| {
-					CniError::Generic(format!(
-						"address of link {} is not 6 bytes: {:?}",
-						name, addr
-					))
-				})?;
-
-				let addr = MacAddr6::from(addr).into();
-				debug!("got mac address for device {}: {}", name, addr);
-				*self = Self::Mac(addr);
-
-				Ok(())
-			} else {
-				Err(CniError::Generic(format!(
-					"interface not found for name {}",
-					name
-				)))
-			}
-		} else {
-			Ok(())
+			let addr = resolve_link(netlink, name)
+				.await?
+				.mac
+				.ok_or_else(|| CniError::Generic(format!("no mac address on link {}", name)))?;
+
+			debug!("got mac address for device {}: {}", name, addr);
+			*self = Self::Mac(addr);
 		}
+
+		Ok(())
 	}
 }
 
 #[test]
 fn test_with_mac() {
 	use crate::Neigh;
+	use macaddr::MacAddr6;
 
 	let s = Neigh {
 		address: "1.2.3.4".parse().unwrap(),
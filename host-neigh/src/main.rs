@@ -1,7 +1,11 @@
+#[cfg(not(target_os = "linux"))]
+compile_error!("host-neigh uses netlink, which is only available on Linux");
+
 use std::{
 	convert::TryFrom,
 	net::IpAddr,
-	time::{Duration, Instant},
+	sync::Arc,
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use async_std::{
@@ -11,6 +15,7 @@ use async_std::{
 use cni_plugin::{
 	error::CniError,
 	logger,
+	netlink::{nlerror, resolve_link},
 	reply::{reply, SuccessReply},
 	Cni, Command, Inputs,
 };
@@ -20,15 +25,21 @@ use futures::{
 };
 use log::{debug, error, info, warn};
 use rtnetlink::{
-	packet::rtnl::neighbour::nlas::Nla, Handle, IpVersion, LinkHandle, NeighbourHandle,
+	packet::{rtnl::neighbour::nlas::Nla, NeighbourMessage},
+	Handle, IpVersion, NeighbourHandle,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::macordevice::MacOrDevice;
+use crate::device::Device;
+use crate::macordevice::{MacCache, MacOrDevice};
 
+mod device;
 mod macordevice;
 
+/// Wait between tries, unless `retryBackoffMs` is set.
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+
 fn main() {
 	let mut logconfig = logger::default_config();
 	logconfig.add_filter_ignore_str("netlink_proto");
@@ -43,28 +54,47 @@ fn main() {
 	// UNWRAP: unreachable due to using load()
 	let Inputs {
 		command, config, ..
-	} = Cni::load().into_inputs().unwrap();
+	} = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
 
 	let cni_version = config.cni_version.clone(); // for error
 	info!(
-		"{} serving spec v{} for command={:?}",
+		"{} serving spec v{} for command={}",
 		env!("CARGO_PKG_NAME"),
 		cni_version,
 		command
 	);
 
 	let res: Result<SuccessReply, CniError> = block_on(async move {
-		if matches!(command, Command::Check) {
-			return Err(CniError::Generic("TODO".into()));
-		}
-
 		let tries = config
 			.specific
-			.get("neigh")
+			.get("tries")
 			.and_then(|val| val.as_u64())
 			.and_then(|n| u8::try_from(n).ok())
 			.map(|n| if n == 0 || n > 10 { 10 } else { n })
 			.unwrap_or(3);
+		let backoff = config
+			.specific
+			.get("retryBackoffMs")
+			.and_then(|val| val.as_u64())
+			.map(Duration::from_millis)
+			.unwrap_or(DEFAULT_BACKOFF);
+		debug!("tries={} backoff={:?}", tries, backoff);
+
+		let failure_mode: FailureMode = config
+			.specific
+			.get("failureMode")
+			.map(|val| {
+				serde_json::from_value(val.clone()).map_err(|_| CniError::InvalidField {
+					field: "failureMode",
+					expected: "\"all\" or \"best-effort\"",
+					value: val.clone(),
+				})
+			})
+			.transpose()?
+			.unwrap_or_default();
+		debug!("failure-mode={:?}", failure_mode);
 
 		let expr = config
 			.specific
@@ -98,45 +128,56 @@ fn main() {
 		debug!("initialising netlink");
 		let (nlconn, nl, _) = rtnetlink::new_connection()?;
 
+		let macs = MacCache::default();
 		let neighs: Vec<Neigh> = serde_json::from_str(&eval)?;
 		info!("got {} neighs from jq expression", neighs.len());
 		let trials: Vec<Trial> = neighs
 			.into_iter()
-			.map(|n| Trial::new(n, nl.clone(), command, tries))
+			.map(|n| Trial::new(n, nl.clone(), macs.clone(), command, tries, backoff))
 			.collect::<Result<_, _>>()?;
 
 		debug!("starting netlink connection task");
 		spawn(nlconn);
 
+		let snapshot = Arc::new(
+			Snapshot::dump(
+				&mut NeighbourHandle::new(nl.clone()),
+				trials.iter().map(|t| &t.neigh),
+			)
+			.await?,
+		);
+
 		let mut outcomes = trials
 			.into_iter()
-			.map(Trial::run)
+			.map(|t| t.with_snapshot(snapshot.clone()).run())
 			.collect::<FuturesOrdered<_>>()
 			.collect::<Vec<Trial>>()
 			.await;
 
-		let error = outcomes
-			.iter_mut()
-			.filter_map(|t| t.last_error.take().map(|e| e.to_string()))
-			.collect::<Vec<String>>()
-			.join("\n");
-		if !error.is_empty() {
-			return Err(CniError::Generic(error));
+		let errors = outcomes
+			.iter()
+			.filter_map(|t| t.last_error.as_ref().map(|e| e.to_string()))
+			.collect::<Vec<String>>();
+		if !errors.is_empty() {
+			// checks are always strict, they'd be meaningless otherwise
+			if failure_mode == FailureMode::BestEffort && !matches!(command, Command::Check) {
+				warn!(
+					"{} of {} failed, continuing in best-effort mode:\n{}",
+					errors.len(),
+					outcomes.len(),
+					errors.join("\n")
+				);
+			} else {
+				return Err(CniError::Generic(errors.join("\n")));
+			}
 		}
 
-		let cni_version = config.cni_version.clone();
-		let mut reply = config
-			.prev_result
-			.map(|val| serde_json::from_value(val).map_err(CniError::Json))
-			.transpose()?
-			.unwrap_or_else(|| SuccessReply {
-				cni_version,
-				interfaces: Default::default(),
-				ips: Default::default(),
-				routes: Default::default(),
-				dns: Default::default(),
-				specific: Default::default(),
-			});
+		let mut reply = SuccessReply::from_prev_or_default(&config)?;
+
+		if matches!(command, Command::Check) {
+			info!("all {} neighbours are present", outcomes.len());
+			return Ok(reply);
+		}
 
 		let existing_neighs = reply
 			.specific
@@ -145,11 +186,15 @@ fn main() {
 
 		if let Some(r) = existing_neighs.as_array_mut() {
 			debug!("existing host neighbours: {:?}", r);
-			info!("returning {} applied neighs", outcomes.len());
+			info!(
+				"returning {} neighs, {} failed",
+				outcomes.len(),
+				errors.len()
+			);
 			r.extend(
 				outcomes
 					.into_iter()
-					.map(|o| serde_json::to_value(o.neigh))
+					.map(|o| serde_json::to_value(o.applied()))
 					.collect::<Result<Vec<Value>, _>>()?,
 			);
 		} else {
@@ -175,9 +220,14 @@ fn main() {
 #[derive(Debug)]
 struct Trial {
 	pub netlink: Handle,
+	pub macs: MacCache,
+	pub snapshot: Option<Arc<Snapshot>>,
 	pub command: Command,
 	pub neigh: Neigh,
 	pub tries: u8,
+	pub backoff: Duration,
+	pub attempts: u8,
+	pub applied_at: Option<SystemTime>,
 	pub link: Option<u32>,
 	pub last_error: Option<CniError>,
 }
@@ -186,31 +236,48 @@ impl Trial {
 	pub fn new(
 		neigh: Neigh,
 		netlink: Handle,
+		macs: MacCache,
 		command: Command,
 		tries: u8,
+		backoff: Duration,
 	) -> Result<Self, CniError> {
 		Ok(Self {
 			netlink,
+			macs,
+			snapshot: None,
 			command,
 			neigh: neigh.validate(command)?,
 			tries,
+			backoff,
+			attempts: 0,
+			applied_at: None,
 			link: None,
 			last_error: None,
 		})
 	}
 
+	/// Uses a snapshot of the neighbour tables for the first try, instead of
+	/// dumping them for this neighbour alone.
+	pub fn with_snapshot(mut self, snapshot: Arc<Snapshot>) -> Self {
+		self.snapshot = Some(snapshot);
+		self
+	}
+
 	pub async fn run(mut self) -> Self {
 		for _ in 0..self.tries {
+			self.attempts += 1;
 			if let Err(err) = self.try_once().await {
 				self.last_error = Some(err);
 
-				let nap = Duration::from_millis(50);
+				let nap = self.backoff;
 				warn!(
 					"got an error applying {:?}, waiting {:?} before next try",
 					self.neigh, nap
 				);
 				sleep(nap).await;
 			} else {
+				self.last_error = None;
+				self.applied_at = Some(SystemTime::now());
 				break;
 			}
 		}
@@ -218,58 +285,119 @@ impl Trial {
 		self
 	}
 
+	/// The neigh as returned in the result, with how it went.
+	pub fn applied(self) -> Applied<Neigh> {
+		Applied {
+			item: self.neigh,
+			attempts: self.attempts,
+			error: self.last_error.map(|err| err.to_string()),
+			applied_at: self
+				.applied_at
+				.and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+				.map(|d| d.as_secs()),
+		}
+	}
+
 	async fn try_once(&mut self) -> Result<(), CniError> {
-		let mut nllh = LinkHandle::new(self.netlink.clone());
 		let mut nlnh = NeighbourHandle::new(self.netlink.clone());
 
 		let link = if let Some(link) = self.link {
 			link
 		} else {
-			let link = self.neigh.link_index(&mut nllh).await?;
+			let link = self.neigh.link_index(&self.netlink).await?;
 			self.link = Some(link);
 			link
 		};
 
 		if let Some(lladdr) = &mut self.neigh.lladdr {
-			lladdr.resolve(&mut nllh).await?;
+			lladdr.resolve_cached(&self.netlink, &self.macs).await?;
 		}
 
-		if matches!(self.command, Command::Del) {
-			debug!("deleting {:?}", self.neigh);
-			self.neigh.del(&mut nlnh, link).await?;
-			info!("deleted {} neighbour from {}", self.neigh.address, link);
-		} else {
-			debug!("adding {:?}", self.neigh);
-			self.neigh.add(&mut nlnh, link).await?;
-			info!("added {} neighbour to {}", self.neigh.address, link);
+		// a failed try may have changed the table, so only the first uses the snapshot
+		let snapshot = self.snapshot.take();
+		let snapshot = snapshot.as_deref();
+
+		match self.command {
+			Command::Del => {
+				debug!("deleting {:?}", self.neigh);
+				self.neigh.del(&mut nlnh, link, snapshot).await?;
+				info!("deleted {} neighbour from {}", self.neigh.address, link);
+			}
+			Command::Check => {
+				debug!("checking {:?}", self.neigh);
+				self.neigh.check(&mut nlnh, link, snapshot).await?;
+				info!("found {} neighbour on {}", self.neigh.address, link);
+			}
+			_ => {
+				debug!("adding {:?}", self.neigh);
+				self.neigh.add(&mut nlnh, link, snapshot).await?;
+				info!("added {} neighbour to {}", self.neigh.address, link);
+			}
 		}
 
 		Ok(())
 	}
 }
 
+/// Whether any failure fails the whole run.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum FailureMode {
+	/// Fail if anything fails to apply.
+	All,
+
+	/// Apply what can be, and return the failures as warnings.
+	BestEffort,
+}
+
+impl Default for FailureMode {
+	fn default() -> Self {
+		Self::All
+	}
+}
+
+/// An item returned in `hostNeighbours`, with how many tries it took to apply.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Applied<T> {
+	#[serde(flatten)]
+	pub item: T,
+	pub attempts: u8,
+	/// Set when it failed to apply, in best-effort mode.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	/// Seconds since the unix epoch.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub applied_at: Option<u64>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 struct Neigh {
 	pub address: IpAddr,
-	pub device: String,
+	pub device: Device,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub lladdr: Option<MacOrDevice>,
 }
 
 impl Neigh {
 	pub fn validate(self, command: Command) -> Result<Self, CniError> {
-		if self.lladdr.is_none() && !matches!(command, Command::Del) {
+		if self.lladdr.is_none() && matches!(command, Command::Add) {
 			Err(CniError::Generic(
-				"lladdr is required when command is not DEL".into(),
+				"lladdr is required when command is ADD".into(),
 			))
 		} else {
 			Ok(self)
 		}
 	}
 
-	pub async fn add(&self, nlnh: &mut NeighbourHandle, link: u32) -> Result<(), CniError> {
+	pub async fn add(
+		&self,
+		nlnh: &mut NeighbourHandle,
+		link: u32,
+		snapshot: Option<&Snapshot>,
+	) -> Result<(), CniError> {
 		debug!("first, attempting to delete neighbour {:?}", self);
-		if let Err(err) = self.del(nlnh, link).await {
+		if let Err(err) = self.del(nlnh, link, snapshot).await {
 			warn!("pre-emptive delete of neighbour {:?} failed: {}", self, err);
 		}
 
@@ -289,57 +417,88 @@ impl Neigh {
 		Ok(())
 	}
 
-	pub async fn del(&self, nlnh: &mut NeighbourHandle, link: u32) -> Result<(), CniError> {
-		let ipv = match self.address {
-			IpAddr::V4(_) => IpVersion::V4,
-			IpAddr::V6(_) => IpVersion::V6,
-		};
+	pub async fn del(
+		&self,
+		nlnh: &mut NeighbourHandle,
+		link: u32,
+		snapshot: Option<&Snapshot>,
+	) -> Result<(), CniError> {
+		for neigh in self.find(nlnh, link, snapshot).await? {
+			info!("deleting found neighbour {:?}", neigh);
+			nlnh.del(neigh).execute().await.map_err(nlerror)?;
+		}
+
+		Ok(())
+	}
 
-		debug!("getting all {:?} neighbours", ipv);
-		let mut neighs = nlnh.get().set_family(ipv).execute();
+	pub async fn check(
+		&self,
+		nlnh: &mut NeighbourHandle,
+		link: u32,
+		snapshot: Option<&Snapshot>,
+	) -> Result<(), CniError> {
+		if self.find(nlnh, link, snapshot).await?.is_empty() {
+			Err(CniError::Generic(format!(
+				"neighbour {} (device={}, lladdr={:?}) is missing",
+				self.address, self.device, self.lladdr
+			)))
+		} else {
+			Ok(())
+		}
+	}
 
-		debug!("iterating neighbours");
-		let mut n = 0;
-		while let Some(neigh) = neighs.try_next().await.map_err(nlerror)? {
-			n += 1;
+	fn ip_version(&self) -> IpVersion {
+		match self.address {
+			IpAddr::V4(_) => IpVersion::V4,
+			IpAddr::V6(_) => IpVersion::V6,
+		}
+	}
 
+	async fn find(
+		&self,
+		nlnh: &mut NeighbourHandle,
+		link: u32,
+		snapshot: Option<&Snapshot>,
+	) -> Result<Vec<NeighbourMessage>, CniError> {
+		let found: Vec<NeighbourMessage> = if let Some(snapshot) = snapshot {
 			debug!(
-				"neigh {}: link index={}, query={}",
-				n, neigh.header.ifindex, link
+				"matching against {} neighbours in snapshot",
+				snapshot.neighbours.len()
 			);
-			if neigh.header.ifindex != link {
-				continue;
-			}
+			snapshot
+				.neighbours
+				.iter()
+				.filter(|neigh| self.matches(neigh, link))
+				.cloned()
+				.collect()
+		} else {
+			let ipv = self.ip_version();
+			debug!("getting all {:?} neighbours", ipv);
+			nlnh.get()
+				.set_family(ipv)
+				.execute()
+				.try_filter(|neigh| futures::future::ready(self.matches(neigh, link)))
+				.try_collect()
+				.await
+				.map_err(nlerror)?
+		};
 
-			if let Some(lladdr) = &self.lladdr {
-				let ll = match neigh
-					.nlas
-					.iter()
-					.filter_map(|n| {
-						if let Nla::LinkLocalAddress(d) = n {
-							Some(d)
-						} else {
-							None
-						}
-					})
-					.next()
-				{
-					Some(l) => l,
-					None => continue,
-				};
-
-				debug!("neigh {}: lladdr={:?}, query={}", n, ll, lladdr);
-				// UNWRAP: already normalised to mac address at this point
-				if lladdr.as_mac().unwrap().0.as_bytes() != ll {
-					continue;
-				}
-			}
+		debug!("found {} matching neighbours", found.len());
+		Ok(found)
+	}
+
+	fn matches(&self, neigh: &NeighbourMessage, link: u32) -> bool {
+		debug!("neigh: link index={}, query={}", neigh.header.ifindex, link);
+		if neigh.header.ifindex != link {
+			return false;
+		}
 
-			let dest = match neigh
+		if let Some(lladdr) = &self.lladdr {
+			let ll = match neigh
 				.nlas
 				.iter()
 				.filter_map(|n| {
-					if let Nla::Destination(d) = n {
+					if let Nla::LinkLocalAddress(d) = n {
 						Some(d)
 					} else {
 						None
@@ -347,46 +506,91 @@ impl Neigh {
 				})
 				.next()
 			{
-				Some(d) => d,
-				None => continue,
+				Some(l) => l,
+				None => return false,
 			};
 
-			debug!("neigh {}: address={:?}, query={}", n, dest, self.address);
-			match self.address {
-				IpAddr::V4(v4) => {
-					if &v4.octets()[..] != dest {
-						continue;
-					}
-				}
-				IpAddr::V6(v6) => {
-					if &v6.octets()[..] != dest {
-						continue;
-					}
-				}
+			debug!("neigh: lladdr={:?}, query={}", ll, lladdr);
+			// UNWRAP: already normalised to mac address at this point
+			if lladdr.as_mac().unwrap().0.as_bytes() != ll {
+				return false;
 			}
-
-			info!("deleting found neighbour {:?}", neigh);
-			nlnh.del(neigh).execute().await.map_err(nlerror)?;
 		}
 
-		debug!("iterated {} neighbours", n);
-		Ok(())
-	}
+		let dest = match neigh
+			.nlas
+			.iter()
+			.filter_map(|n| {
+				if let Nla::Destination(d) = n {
+					Some(d)
+				} else {
+					None
+				}
+			})
+			.next()
+		{
+			Some(d) => d,
+			None => return false,
+		};
 
-	pub async fn link_index(&self, nllh: &mut LinkHandle) -> Result<u32, CniError> {
-		let mut linklist = nllh.get().set_name_filter(self.device.clone()).execute();
-		if let Some(link) = linklist.try_next().await.map_err(nlerror)? {
-			info!("link: {:?}", link.header);
-			Ok(link.header.index)
-		} else {
-			Err(CniError::Generic(format!(
-				"interface not found for route {:?}",
-				self
-			)))
+		debug!("neigh: address={:?}, query={}", dest, self.address);
+		match self.address {
+			IpAddr::V4(v4) => &v4.octets()[..] == dest,
+			IpAddr::V6(v6) => &v6.octets()[..] == dest,
 		}
 	}
+
+	pub async fn link_index(&self, netlink: &Handle) -> Result<u32, CniError> {
+		let name = match &self.device {
+			Device::Index(index) => {
+				debug!("using device index {} as given", index);
+				return Ok(*index);
+			}
+			Device::Name(name) => name,
+		};
+
+		Ok(resolve_link(netlink, name).await?.index)
+	}
 }
 
-fn nlerror(err: rtnetlink::Error) -> CniError {
-	CniError::Generic(format!("netlink: {}", err))
+/// The neighbour tables, dumped once for all trials of a run.
+#[derive(Debug, Default)]
+struct Snapshot {
+	pub neighbours: Vec<NeighbourMessage>,
+}
+
+impl Snapshot {
+	/// Dumps the neighbours of the IP versions used by the neighs.
+	pub async fn dump<'n>(
+		nlnh: &mut NeighbourHandle,
+		neighs: impl Iterator<Item = &'n Neigh>,
+	) -> Result<Self, CniError> {
+		let (mut v4, mut v6) = (false, false);
+		for neigh in neighs {
+			match neigh.ip_version() {
+				IpVersion::V4 => v4 = true,
+				IpVersion::V6 => v6 = true,
+			}
+		}
+
+		let mut neighbours = Vec::new();
+		for (ipv, wanted) in [(IpVersion::V4, v4), (IpVersion::V6, v6)].iter().cloned() {
+			if !wanted {
+				continue;
+			}
+
+			debug!("getting all {:?} neighbours for snapshot", ipv);
+			let mut dump: Vec<NeighbourMessage> = nlnh
+				.get()
+				.set_family(ipv)
+				.execute()
+				.try_collect()
+				.await
+				.map_err(nlerror)?;
+			neighbours.append(&mut dump);
+		}
+
+		info!("snapshotted {} neighbours", neighbours.len());
+		Ok(Self { neighbours })
+	}
 }
@@ -1,7 +1,12 @@
+#[cfg(not(target_os = "linux"))]
+compile_error!("host-routes uses netlink, which is only available on Linux");
+
 use std::{
+	collections::HashMap,
 	convert::TryFrom,
 	net::IpAddr,
-	time::{Duration, Instant},
+	sync::Arc,
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use async_std::{
@@ -11,6 +16,7 @@ use async_std::{
 use cni_plugin::{
 	error::CniError,
 	logger,
+	netlink::{nlerror, resolve_link},
 	reply::{reply, SuccessReply},
 	Cni, Command, Inputs,
 };
@@ -20,10 +26,19 @@ use futures::{
 };
 use ipnetwork::IpNetwork;
 use log::{debug, error, info, warn};
-use rtnetlink::{Handle, IpVersion, LinkHandle, RouteHandle};
+use rtnetlink::{
+	packet::{
+		rtnl::route::{nlas::Nla, RouteFlags},
+		RouteMessage,
+	},
+	Handle, IpVersion, RouteHandle,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+/// Wait between tries, unless `retryBackoffMs` is set.
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+
 fn main() {
 	let mut logconfig = logger::default_config();
 	logconfig.add_filter_ignore_str("netlink_proto");
@@ -38,28 +53,47 @@ fn main() {
 	// UNWRAP: unreachable due to using load()
 	let Inputs {
 		command, config, ..
-	} = Cni::load().into_inputs().unwrap();
+	} = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
 
 	let cni_version = config.cni_version.clone(); // for error
 	info!(
-		"{} serving spec v{} for command={:?}",
+		"{} serving spec v{} for command={}",
 		env!("CARGO_PKG_NAME"),
 		cni_version,
 		command
 	);
 
 	let res: Result<SuccessReply, CniError> = block_on(async move {
-		if matches!(command, Command::Check) {
-			return Err(CniError::Generic("TODO".into()));
-		}
-
 		let tries = config
 			.specific
-			.get("neigh")
+			.get("tries")
 			.and_then(|val| val.as_u64())
 			.and_then(|n| u8::try_from(n).ok())
 			.map(|n| if n == 0 || n > 10 { 10 } else { n })
 			.unwrap_or(3);
+		let backoff = config
+			.specific
+			.get("retryBackoffMs")
+			.and_then(|val| val.as_u64())
+			.map(Duration::from_millis)
+			.unwrap_or(DEFAULT_BACKOFF);
+		debug!("tries={} backoff={:?}", tries, backoff);
+
+		let failure_mode: FailureMode = config
+			.specific
+			.get("failureMode")
+			.map(|val| {
+				serde_json::from_value(val.clone()).map_err(|_| CniError::InvalidField {
+					field: "failureMode",
+					expected: "\"all\" or \"best-effort\"",
+					value: val.clone(),
+				})
+			})
+			.transpose()?
+			.unwrap_or_default();
+		debug!("failure-mode={:?}", failure_mode);
 
 		let expr = config
 			.specific
@@ -97,41 +131,51 @@ fn main() {
 		info!("got {} routings from jq expression", routing.len());
 		let trials: Vec<_> = routing
 			.into_iter()
-			.map(|n| Trial::new(n, nl.clone(), command, tries))
+			.map(|n| Trial::new(n, nl.clone(), command, tries, backoff))
 			.collect::<Result<_, _>>()?;
 
 		debug!("starting netlink connection task");
 		spawn(nlconn);
 
+		let snapshot = Arc::new(
+			Snapshot::dump(
+				&mut RouteHandle::new(nl.clone()),
+				trials.iter().map(|t| &t.route),
+			)
+			.await?,
+		);
+
 		let mut outcomes = trials
 			.into_iter()
-			.map(Trial::run)
+			.map(|t| t.with_snapshot(snapshot.clone()).run())
 			.collect::<FuturesOrdered<_>>()
 			.collect::<Vec<Trial>>()
 			.await;
 
-		let error = outcomes
-			.iter_mut()
-			.filter_map(|t| t.last_error.take().map(|e| e.to_string()))
-			.collect::<Vec<String>>()
-			.join("\n");
-		if !error.is_empty() {
-			return Err(CniError::Generic(error));
+		let errors = outcomes
+			.iter()
+			.filter_map(|t| t.last_error.as_ref().map(|e| e.to_string()))
+			.collect::<Vec<String>>();
+		if !errors.is_empty() {
+			// checks are always strict, they'd be meaningless otherwise
+			if failure_mode == FailureMode::BestEffort && !matches!(command, Command::Check) {
+				warn!(
+					"{} of {} failed, continuing in best-effort mode:\n{}",
+					errors.len(),
+					outcomes.len(),
+					errors.join("\n")
+				);
+			} else {
+				return Err(CniError::Generic(errors.join("\n")));
+			}
 		}
 
-		let cni_version = config.cni_version.clone();
-		let mut reply = config
-			.prev_result
-			.map(|val| serde_json::from_value(val).map_err(CniError::Json))
-			.transpose()?
-			.unwrap_or_else(|| SuccessReply {
-				cni_version,
-				interfaces: Default::default(),
-				ips: Default::default(),
-				routes: Default::default(),
-				dns: Default::default(),
-				specific: Default::default(),
-			});
+		let mut reply = SuccessReply::from_prev_or_default(&config)?;
+
+		if matches!(command, Command::Check) {
+			info!("all {} routes are present", outcomes.len());
+			return Ok(reply);
+		}
 
 		let existing_routes = reply
 			.specific
@@ -140,11 +184,15 @@ fn main() {
 
 		if let Some(r) = existing_routes.as_array_mut() {
 			debug!("existing host routes: {:?}", r);
-			info!("returning {} applied routes", outcomes.len());
+			info!(
+				"returning {} routes, {} failed",
+				outcomes.len(),
+				errors.len()
+			);
 			r.extend(
 				outcomes
 					.into_iter()
-					.map(|o| serde_json::to_value(o.route))
+					.map(|o| serde_json::to_value(o.applied()))
 					.collect::<Result<Vec<Value>, _>>()?,
 			);
 		} else {
@@ -170,9 +218,13 @@ fn main() {
 #[derive(Debug)]
 struct Trial {
 	pub netlink: Handle,
+	pub snapshot: Option<Arc<Snapshot>>,
 	pub command: Command,
 	pub route: Routing,
 	pub tries: u8,
+	pub backoff: Duration,
+	pub attempts: u8,
+	pub applied_at: Option<SystemTime>,
 	pub link: Option<Option<u32>>,
 	pub last_error: Option<CniError>,
 }
@@ -183,29 +235,44 @@ impl Trial {
 		netlink: Handle,
 		command: Command,
 		tries: u8,
+		backoff: Duration,
 	) -> Result<Self, CniError> {
 		Ok(Self {
 			netlink,
+			snapshot: None,
 			command,
 			route: route.validate()?,
 			tries,
+			backoff,
+			attempts: 0,
+			applied_at: None,
 			link: None,
 			last_error: None,
 		})
 	}
 
+	/// Uses a snapshot of the routing table for the first try, instead of
+	/// dumping the table for this route alone.
+	pub fn with_snapshot(mut self, snapshot: Arc<Snapshot>) -> Self {
+		self.snapshot = Some(snapshot);
+		self
+	}
+
 	pub async fn run(mut self) -> Self {
 		for _ in 0..self.tries {
+			self.attempts += 1;
 			if let Err(err) = self.try_once().await {
 				self.last_error = Some(err);
 
-				let nap = Duration::from_millis(50);
+				let nap = self.backoff;
 				warn!(
 					"got an error applying {:?}, waiting {:?} before next try",
 					self.route, nap
 				);
 				sleep(nap).await;
 			} else {
+				self.last_error = None;
+				self.applied_at = Some(SystemTime::now());
 				break;
 			}
 		}
@@ -213,39 +280,100 @@ impl Trial {
 		self
 	}
 
+	/// The route as returned in the result, with how it went.
+	pub fn applied(self) -> Applied<Routing> {
+		Applied {
+			item: self.route,
+			attempts: self.attempts,
+			error: self.last_error.map(|err| err.to_string()),
+			applied_at: self
+				.applied_at
+				.and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+				.map(|d| d.as_secs()),
+		}
+	}
+
 	async fn try_once(&mut self) -> Result<(), CniError> {
-		let mut nllh = LinkHandle::new(self.netlink.clone());
 		let mut nlrh = RouteHandle::new(self.netlink.clone());
 
 		let link = if let Some(link) = self.link {
 			link
 		} else {
-			let link = self.route.link_index(&mut nllh).await?;
+			let link = self.route.link_index(&self.netlink).await?;
 			self.link = Some(link);
 			link
 		};
 
-		if matches!(self.command, Command::Del) {
-			debug!("deleting {:?}", self.route);
-			self.route.del(&mut nlrh, link).await?;
-			info!("deleted route to {}", self.route.prefix);
-		} else {
-			debug!("adding {:?}", self.route);
-			self.route.add(&mut nlrh, link).await?;
-			info!("added route to {}", self.route.prefix);
+		// a failed try may have changed the table, so only the first uses the snapshot
+		let snapshot = self.snapshot.take();
+		let snapshot = snapshot.as_deref();
+
+		match self.command {
+			Command::Del => {
+				debug!("deleting {:?}", self.route);
+				self.route.del(&mut nlrh, link, snapshot).await?;
+				info!("deleted route to {}", self.route.prefix);
+			}
+			Command::Check => {
+				debug!("checking {:?}", self.route);
+				self.route.check(&mut nlrh, link, snapshot).await?;
+				info!("found route to {}", self.route.prefix);
+			}
+			_ => {
+				debug!("adding {:?}", self.route);
+				self.route.add(&mut nlrh, link, snapshot).await?;
+				info!("added route to {}", self.route.prefix);
+			}
 		}
 
 		Ok(())
 	}
 }
 
+/// Whether any failure fails the whole run.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum FailureMode {
+	/// Fail if anything fails to apply.
+	All,
+
+	/// Apply what can be, and return the failures as warnings.
+	BestEffort,
+}
+
+impl Default for FailureMode {
+	fn default() -> Self {
+		Self::All
+	}
+}
+
+/// An item returned in `hostRoutes`, with how many tries it took to apply.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Applied<T> {
+	#[serde(flatten)]
+	pub item: T,
+	pub attempts: u8,
+	/// Set when it failed to apply, in best-effort mode.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	/// Seconds since the unix epoch.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub applied_at: Option<u64>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 struct Routing {
 	pub prefix: IpNetwork,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub device: Option<String>,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub gateway: Option<IpAddr>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub pref_src: Option<IpAddr>,
+	#[serde(default, skip_serializing_if = "is_false")]
+	pub onlink: bool,
 }
 
 impl Routing {
@@ -254,14 +382,24 @@ impl Routing {
 			Err(CniError::Generic(
 				"at least one of device or gateway is required, none provided".into(),
 			))
+		} else if matches!(self.pref_src, Some(src) if src.is_ipv4() != self.prefix.is_ipv4()) {
+			Err(CniError::Generic(format!(
+				"prefSrc {:?} is not of the same family as prefix {}",
+				self.pref_src, self.prefix
+			)))
 		} else {
 			Ok(self)
 		}
 	}
 
-	pub async fn add(&self, nlrh: &mut RouteHandle, link: Option<u32>) -> Result<(), CniError> {
+	pub async fn add(
+		&self,
+		nlrh: &mut RouteHandle,
+		link: Option<u32>,
+		snapshot: Option<&Snapshot>,
+	) -> Result<(), CniError> {
 		debug!("first, attempting to delete route {:?}", self);
-		if let Err(err) = self.del(nlrh, link).await {
+		if let Err(err) = self.del(nlrh, link, snapshot).await {
 			warn!("pre-emptive delete of route {:?} failed: {}", self, err);
 		}
 
@@ -272,6 +410,14 @@ impl Routing {
 			add = add.output_interface(index);
 		}
 
+		if self.onlink {
+			debug!("route add: onlink");
+			add.message_mut()
+				.header
+				.flags
+				.insert(RouteFlags::RTNH_F_ONLINK);
+		}
+
 		match self.prefix {
 			IpNetwork::V4(net) => {
 				debug!("route add: with v4 prefix: {}", net);
@@ -282,6 +428,13 @@ impl Routing {
 					add = add.gateway(gw);
 				}
 
+				if let Some(IpAddr::V4(src)) = self.pref_src {
+					debug!("route add: with preferred source {}", src);
+					add.message_mut()
+						.nlas
+						.push(Nla::PrefSource(src.octets().to_vec()));
+				}
+
 				debug!("route add: execute");
 				add.execute().await.map_err(nlerror)?;
 				debug!("route add: done");
@@ -295,6 +448,13 @@ impl Routing {
 					add = add.gateway(gw);
 				}
 
+				if let Some(IpAddr::V6(src)) = self.pref_src {
+					debug!("route add: with preferred source {}", src);
+					add.message_mut()
+						.nlas
+						.push(Nla::PrefSource(src.octets().to_vec()));
+				}
+
 				debug!("route add: execute");
 				add.execute().await.map_err(nlerror)?;
 				debug!("route add: done");
@@ -304,76 +464,202 @@ impl Routing {
 		Ok(())
 	}
 
-	pub async fn del(&self, nlrh: &mut RouteHandle, link: Option<u32>) -> Result<(), CniError> {
-		let ipv = match self.prefix {
+	pub async fn del(
+		&self,
+		nlrh: &mut RouteHandle,
+		link: Option<u32>,
+		snapshot: Option<&Snapshot>,
+	) -> Result<(), CniError> {
+		for route in self.find(nlrh, link, snapshot).await? {
+			info!("deleting found route\n  input interface: {:?}\n  output interface: {:?}\n  source prefix: {:?}\n  dest prefix: {:?}\n  gateway: {:?}", route.input_interface(), route.output_interface(), route.source_prefix(), route.destination_prefix(), route.gateway());
+			nlrh.del(route).execute().await.map_err(nlerror)?;
+		}
+
+		Ok(())
+	}
+
+	pub async fn check(
+		&self,
+		nlrh: &mut RouteHandle,
+		link: Option<u32>,
+		snapshot: Option<&Snapshot>,
+	) -> Result<(), CniError> {
+		if self.find(nlrh, link, snapshot).await?.is_empty() {
+			Err(CniError::Generic(format!(
+				"route to {} (device={:?}, gateway={:?}, prefSrc={:?}) is missing",
+				self.prefix, self.device, self.gateway, self.pref_src
+			)))
+		} else {
+			Ok(())
+		}
+	}
+
+	fn ip_version(&self) -> IpVersion {
+		match self.prefix {
 			IpNetwork::V4(_) => IpVersion::V4,
 			IpNetwork::V6(_) => IpVersion::V6,
+		}
+	}
+
+	async fn find(
+		&self,
+		nlrh: &mut RouteHandle,
+		link: Option<u32>,
+		snapshot: Option<&Snapshot>,
+	) -> Result<Vec<RouteMessage>, CniError> {
+		let found: Vec<RouteMessage> = if let Some(snapshot) = snapshot {
+			let key = (
+				Some((self.prefix.ip(), self.prefix.prefix())),
+				link,
+				self.gateway,
+			);
+			debug!("looking up {:?} in snapshot", key);
+			snapshot
+				.routes
+				.get(&key)
+				.into_iter()
+				.flatten()
+				.filter(|route| self.matches(route, link))
+				.cloned()
+				.collect()
+		} else {
+			let ipv = self.ip_version();
+			debug!("getting all {:?} routes", ipv);
+			nlrh.get(ipv)
+				.execute()
+				.try_filter(|route| futures::future::ready(self.matches(route, link)))
+				.try_collect()
+				.await
+				.map_err(nlerror)?
 		};
 
-		debug!("getting all {:?} routes", ipv);
-		let mut routes = nlrh.get(ipv).execute();
+		debug!("found {} matching routes", found.len());
+		Ok(found)
+	}
 
-		debug!("iterating routes");
-		let mut n = 0;
-		while let Some(route) = routes.try_next().await.map_err(nlerror)? {
-			n += 1;
-
-			debug!(
-				"route {}: link index={:?}, query={:?}",
-				n,
-				route.output_interface(),
-				link
-			);
-			if route.output_interface() != link {
-				continue;
-			}
+	fn matches(&self, route: &RouteMessage, link: Option<u32>) -> bool {
+		debug!(
+			"route: link index={:?}, query={:?}",
+			route.output_interface(),
+			link
+		);
+		if route.output_interface() != link {
+			return false;
+		}
 
-			debug!(
-				"route {}: prefix={:?}, query={:?}",
-				n,
-				route.destination_prefix(),
-				self.prefix
-			);
-			if route.destination_prefix() != Some((self.prefix.ip(), self.prefix.prefix())) {
-				continue;
-			}
+		debug!(
+			"route: prefix={:?}, query={:?}",
+			route.destination_prefix(),
+			self.prefix
+		);
+		if route.destination_prefix() != Some((self.prefix.ip(), self.prefix.prefix())) {
+			return false;
+		}
 
-			debug!(
-				"route {}: gateway={:?}, query={:?}",
-				n,
-				route.gateway(),
-				self.gateway
-			);
-			if route.gateway() != self.gateway {
-				continue;
-			}
+		debug!(
+			"route: gateway={:?}, query={:?}",
+			route.gateway(),
+			self.gateway
+		);
+		if route.gateway() != self.gateway {
+			return false;
+		}
 
-			info!("deleting found route\n  input interface: {:?}\n  output interface: {:?}\n  source prefix: {:?}\n  dest prefix: {:?}\n  gateway: {:?}", route.input_interface(), route.output_interface(), route.source_prefix(), route.destination_prefix(), route.gateway());
-			nlrh.del(route).execute().await.map_err(nlerror)?;
+		debug!(
+			"route: flags={:?}, query onlink={}",
+			route.header.flags, self.onlink
+		);
+		if self.onlink && !route.header.flags.contains(RouteFlags::RTNH_F_ONLINK) {
+			return false;
 		}
 
-		debug!("iterated {} routes", n);
-		Ok(())
+		if let Some(src) = self.pref_src {
+			let found_src = route.nlas.iter().find_map(|nla| {
+				if let Nla::PrefSource(s) = nla {
+					Some(s.as_slice())
+				} else {
+					None
+				}
+			});
+
+			debug!("route: prefsrc={:?}, query={}", found_src, src);
+			if found_src != Some(&octets(src)[..]) {
+				return false;
+			}
+		}
+
+		true
 	}
 
-	pub async fn link_index(&self, nllh: &mut LinkHandle) -> Result<Option<u32>, CniError> {
+	pub async fn link_index(&self, netlink: &Handle) -> Result<Option<u32>, CniError> {
 		if let Some(ref dev) = self.device {
-			let mut linklist = nllh.get().set_name_filter(dev.clone()).execute();
-			if let Some(link) = linklist.try_next().await.map_err(nlerror)? {
-				info!("link: {:?}", link.header);
-				Ok(Some(link.header.index))
-			} else {
-				Err(CniError::Generic(format!(
-					"interface not found for route {:?}",
-					self
-				)))
-			}
+			Ok(Some(resolve_link(netlink, dev).await?.index))
 		} else {
 			Ok(None)
 		}
 	}
 }
 
-fn nlerror(err: rtnetlink::Error) -> CniError {
-	CniError::Generic(format!("netlink: {}", err))
+/// Destination prefix, output interface, and gateway of a route.
+type RouteKey = (Option<(IpAddr, u8)>, Option<u32>, Option<IpAddr>);
+
+/// The routing table, dumped once for all trials of a run.
+///
+/// Routes are indexed by [`RouteKey`] so that finding a route doesn't iterate
+/// the whole table, which matters on hosts with large tables.
+#[derive(Debug, Default)]
+struct Snapshot {
+	pub routes: HashMap<RouteKey, Vec<RouteMessage>>,
+}
+
+impl Snapshot {
+	/// Dumps the routes of the IP versions used by the routings.
+	pub async fn dump<'r>(
+		nlrh: &mut RouteHandle,
+		routings: impl Iterator<Item = &'r Routing>,
+	) -> Result<Self, CniError> {
+		let (mut v4, mut v6) = (false, false);
+		for routing in routings {
+			match routing.ip_version() {
+				IpVersion::V4 => v4 = true,
+				IpVersion::V6 => v6 = true,
+			}
+		}
+
+		let mut routes: HashMap<RouteKey, Vec<RouteMessage>> = HashMap::new();
+		let mut n = 0;
+		for (ipv, wanted) in [(IpVersion::V4, v4), (IpVersion::V6, v6)].iter().cloned() {
+			if !wanted {
+				continue;
+			}
+
+			debug!("getting all {:?} routes for snapshot", ipv);
+			let mut dump = nlrh.get(ipv).execute();
+			while let Some(route) = dump.try_next().await.map_err(nlerror)? {
+				n += 1;
+				routes
+					.entry((
+						route.destination_prefix(),
+						route.output_interface(),
+						route.gateway(),
+					))
+					.or_default()
+					.push(route);
+			}
+		}
+
+		info!("snapshotted {} routes under {} keys", n, routes.len());
+		Ok(Self { routes })
+	}
+}
+
+fn is_false(b: &bool) -> bool {
+	!*b
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+	match ip {
+		IpAddr::V4(v4) => v4.octets().to_vec(),
+		IpAddr::V6(v6) => v6.octets().to_vec(),
+	}
 }
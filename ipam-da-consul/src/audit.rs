@@ -0,0 +1,89 @@
+use std::{
+	fs::OpenOptions,
+	io::Write,
+	net::IpAddr,
+	path::{Path, PathBuf},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use serde::Serialize;
+
+/// Append-only log of allocations and releases, as JSON lines.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+	path: PathBuf,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuditEntry<'a> {
+	/// Seconds since the unix epoch.
+	timestamp: u64,
+	action: &'static str,
+	container_id: &'a str,
+	pool: &'a str,
+	ip: IpAddr,
+}
+
+impl AuditLog {
+	pub fn new(path: impl Into<PathBuf>) -> Self {
+		Self { path: path.into() }
+	}
+
+	/// Records an allocation. Failures are logged, not returned.
+	pub fn allocated(&self, container_id: &str, pool: &str, ip: IpAddr) {
+		self.record("allocate", container_id, pool, ip);
+	}
+
+	/// Records a release. Failures are logged, not returned.
+	pub fn released(&self, container_id: &str, pool: &str, ip: IpAddr) {
+		self.record("release", container_id, pool, ip);
+	}
+
+	fn record(&self, action: &'static str, container_id: &str, pool: &str, ip: IpAddr) {
+		let entry = AuditEntry {
+			timestamp: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map(|d| d.as_secs())
+				.unwrap_or_default(),
+			action,
+			container_id,
+			pool,
+			ip,
+		};
+		debug!("audit entry={:?}", entry);
+
+		if let Err(err) = append_line(&self.path, &entry) {
+			warn!(
+				"could not write audit entry to {}: {}",
+				self.path.display(),
+				err
+			);
+		}
+	}
+}
+
+fn append_line(path: &Path, entry: &AuditEntry) -> std::io::Result<()> {
+	let mut line = serde_json::to_vec(entry)?;
+	line.push(b'\n');
+
+	// a single write per entry so concurrent plugins don't interleave lines
+	let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+	file.write_all(&line)
+}
+
+#[test]
+fn test_audit_entry() {
+	let entry = AuditEntry {
+		timestamp: 1618185600,
+		action: "allocate",
+		container_id: "abc",
+		pool: "default",
+		ip: "10.0.0.2".parse().unwrap(),
+	};
+	assert_eq!(
+		serde_json::to_string(&entry).unwrap(),
+		r#"{"timestamp":1618185600,"action":"allocate","containerId":"abc","pool":"default","ip":"10.0.0.2"}"#
+	);
+}
@@ -1,11 +1,91 @@
+use std::{fmt, time::Duration};
+
+use async_io::Timer;
 use cni_plugin::error::CniError;
-use log::debug;
-use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
-use surf::Url;
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_json::Value;
+use surf::{Client, RequestBuilder, StatusCode, Url};
 use thiserror::Error;
 
 use crate::error::{AppError, AppResult};
 
+/// How many times to try a write before giving up on server errors.
+const WRITE_TRIES: u32 = 4;
+
+/// Initial wait between write tries, doubled on each subsequent try.
+const WRITE_BACKOFF_MS: u64 = 25;
+
+/// Client and parameters for every request to consul.
+#[derive(Clone, Default)]
+pub struct ConsulParams {
+	/// The HTTP client, configured for TLS.
+	///
+	/// It's built once per run and shared by all requests, so that they reuse
+	/// connections instead of each setting up TCP and TLS.
+	pub client: Client,
+
+	/// The `dc` to target, instead of the server's own.
+	pub datacenter: Option<String>,
+
+	/// The Enterprise `ns` to target, instead of the token's default.
+	pub namespace: Option<String>,
+
+	/// The ACL token, sent as the `X-Consul-Token` header.
+	pub token: Option<String>,
+}
+
+impl fmt::Debug for ConsulParams {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ConsulParams")
+			.field("datacenter", &self.datacenter)
+			.field("namespace", &self.namespace)
+			.field("token", &self.token.as_ref().map(|_| "<redacted>"))
+			.finish()
+	}
+}
+
+impl ConsulParams {
+	/// Starts a GET request, with the token if there is one.
+	pub fn get(&self, url: Url) -> RequestBuilder {
+		self.authed(self.client.get(url))
+	}
+
+	/// Starts a PUT request, with the token if there is one.
+	pub fn put(&self, url: Url) -> RequestBuilder {
+		self.authed(self.client.put(url))
+	}
+
+	/// Starts a DELETE request, with the token if there is one.
+	pub fn delete(&self, url: Url) -> RequestBuilder {
+		self.authed(self.client.delete(url))
+	}
+
+	fn authed(&self, req: RequestBuilder) -> RequestBuilder {
+		if let Some(token) = &self.token {
+			req.header("X-Consul-Token", token.as_str())
+		} else {
+			req
+		}
+	}
+
+	/// Joins a path to the consul server URL and adds the parameters.
+	pub fn url(&self, consul_url: &Url, path: &str) -> AppResult<Url> {
+		let mut url = consul_url.join(path)?;
+		if self.datacenter.is_some() || self.namespace.is_some() {
+			let mut query = url.query_pairs_mut();
+			if let Some(dc) = &self.datacenter {
+				query.append_pair("dc", dc);
+			}
+			if let Some(ns) = &self.namespace {
+				query.append_pair("ns", ns);
+			}
+		}
+
+		Ok(url)
+	}
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ConsulPair<T> {
@@ -73,47 +153,109 @@ impl<T: DeserializeOwned> ConsulPair<T> {
 	}
 }
 
+/// PUTs a value, retrying with backoff on connection and server errors.
+///
+/// Consul briefly answers with 5xx statuses or drops connections during leader
+/// elections, so those are retried a few times. Otherwise this returns what
+/// consul replied: `false` means the write was rejected, e.g. a `cas` index
+/// was stale, and is not retried as the same write would fail again.
+pub async fn put_retrying(params: &ConsulParams, url: Url, body: Value) -> AppResult<bool> {
+	let mut tries = 1;
+	loop {
+		let err = match params.put(url.clone()).body(body.clone()).await {
+			Ok(mut res) if res.status().is_success() => return Ok(res.body_json().await?),
+			Ok(res) if retryable(res.status()) => AppError::from(CniError::Generic(format!(
+				"error status from consul: {}",
+				res.status()
+			))),
+			Ok(res) => {
+				return Err(CniError::Generic(format!(
+					"error status from consul: {}",
+					res.status()
+				))
+				.into())
+			}
+			Err(err) => err.into(),
+		};
+
+		if tries >= WRITE_TRIES {
+			return Err(err);
+		}
+
+		let nap = Duration::from_millis(WRITE_BACKOFF_MS << (tries - 1));
+		warn!(
+			"consul write failed on try {} ({}), waiting {:?} before retrying",
+			tries, err, nap
+		);
+		Timer::after(nap).await;
+		tries += 1;
+	}
+}
+
+fn retryable(status: StatusCode) -> bool {
+	status.is_server_error() || status == StatusCode::TooManyRequests
+}
+
+/// Deletes each key if it hasn't been modified since its index.
+///
+/// This is best-effort: a key which can't be deleted, because it was modified
+/// (e.g. re-allocated to another container, as is common during rolling
+/// updates) or because the request failed, is logged and skipped, and the
+/// others are still deleted. Returns the keys which couldn't be deleted.
 pub async fn delete_all(
 	consul_url: &Url,
+	params: &ConsulParams,
 	keys: impl Iterator<Item = (String, usize)>,
-) -> AppResult<()> {
-	#[derive(Clone, Debug, Serialize)]
-	enum TxnAction {
-		#[serde(rename = "KV")]
-		Kv(TxnKv),
-	}
+) -> AppResult<Vec<String>> {
+	let mut failed = Vec::new();
+	for (key, index) in keys {
+		let mut url = params.url(consul_url, &format!("v1/kv/{}", key))?;
+		url.query_pairs_mut().append_pair("cas", &index.to_string());
 
-	#[derive(Clone, Debug, Serialize)]
-	#[serde(rename_all = "PascalCase")]
-	struct TxnKv {
-		verb: &'static str,
-		key: String,
-		index: usize,
+		debug!("deleting {} at index {}", key, index);
+		match params.delete(url).recv_json::<bool>().await {
+			Ok(true) => {}
+			Ok(false) => {
+				warn!("{} was modified since index {}, not deleting", key, index);
+				failed.push(key);
+			}
+			Err(err) => {
+				warn!("could not delete {}: {}", key, err);
+				failed.push(key);
+			}
+		}
 	}
 
-	let actions = keys
-		.map(|(key, index)| {
-			TxnAction::Kv(TxnKv {
-				verb: "delete-cas",
-				key,
-				index,
-			})
-		})
-		.collect::<Vec<_>>();
-
-	// FIXME!!! We actually want to best-effort this, not to fail the whole thing
-	// on error (because otherwise we'll leave dangling IPs if any in this set
-	// are re-allocated before we get to delete them, as is common in rolling
-	// update situations). So, need to refactor without the transaction.
-	debug!("going to delete {} entries", actions.len());
-	let txn_url = consul_url.join("v1/txn")?;
-	let res = surf::put(txn_url)
-		.body(serde_json::to_value(actions).map_err(CniError::Json)?)
-		.await?;
-
-	match res.status().into() {
-		200 => Ok(()),
-		409 => Err(AppError::ConsulWriteFailed),
-		code => Err(CniError::Generic(format!("invalid txn return status: {}", code)).into()),
-	}
+	Ok(failed)
+}
+
+#[test]
+fn test_consul_params_url() {
+	let base = Url::parse("http://127.0.0.1:8500").unwrap();
+	assert_eq!(
+		ConsulParams::default()
+			.url(&base, "v1/kv/ipam/pool")
+			.unwrap()
+			.as_str(),
+		"http://127.0.0.1:8500/v1/kv/ipam/pool"
+	);
+
+	let params = ConsulParams {
+		datacenter: Some("dc2".into()),
+		namespace: Some("team a".into()),
+		..ConsulParams::default()
+	};
+	assert_eq!(
+		params.url(&base, "v1/kv/ipam/pool").unwrap().as_str(),
+		"http://127.0.0.1:8500/v1/kv/ipam/pool?dc=dc2&ns=team+a"
+	);
+}
+
+#[test]
+fn test_retryable() {
+	assert!(retryable(StatusCode::InternalServerError));
+	assert!(retryable(StatusCode::ServiceUnavailable));
+	assert!(retryable(StatusCode::TooManyRequests));
+	assert!(!retryable(StatusCode::Forbidden));
+	assert!(!retryable(StatusCode::BadRequest));
 }
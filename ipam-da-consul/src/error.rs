@@ -1,7 +1,6 @@
 use std::net::IpAddr;
 
-use cni_plugin::{error::CniError, reply::ErrorReply};
-use semver::Version;
+use cni_plugin::{error::CniError, reply::ErrorReply, CniVersion};
 use thiserror::Error;
 
 pub type AppResult<T> = Result<T, AppError>;
@@ -44,7 +43,7 @@ pub enum AppError {
 }
 
 impl AppError {
-	pub fn into_reply(self, cni_version: Version) -> ErrorReply<'static> {
+	pub fn into_reply(self, cni_version: CniVersion) -> ErrorReply<'static> {
 		match self {
 			Self::Cni(e) => e.into_reply(cni_version),
 			e @ AppError::Url(_) => ErrorReply {
@@ -0,0 +1,621 @@
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	env,
+	net::IpAddr,
+	str::FromStr,
+	time::Duration,
+};
+
+use async_io::Timer;
+use cni_plugin::{
+	config::NetworkConfig,
+	error::CniError,
+	ip_range::AllocationStrategy,
+	net::{first_reachable, TlsOptions},
+	reply::IpamSuccessReply,
+	Command,
+};
+use http_client::isahc::IsahcClient;
+use ipnetwork::IpNetwork;
+use isahc::{
+	config::{CaCertificate, Configurable, SslOption},
+	HttpClient,
+};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surf::StatusCode;
+use url::Url;
+
+use crate::audit::AuditLog;
+use crate::consul::{self, ConsulPair, ConsulParams, ConsulValue};
+use crate::error::{AppError, AppResult};
+use crate::names;
+use crate::source::PoolSource;
+
+/// How many times to go through the list of consul servers before giving up.
+const SERVER_ROUNDS: u32 = 3;
+
+/// Initial wait between rounds, doubled on each subsequent round.
+const SERVER_BACKOFF_MS: u64 = 50;
+
+/// Runs the plugin for a command, without replying.
+///
+/// This doesn't depend on an async runtime: timers and HTTP requests bring
+/// their own threads, so it can be driven by `block_on` from async-std, smol,
+/// or tokio alike.
+pub async fn run(
+	command: Command,
+	container_id: String,
+	config: NetworkConfig,
+) -> AppResult<IpamSuccessReply> {
+	let ipam = config.ipam.clone().ok_or(CniError::MissingField("ipam"))?;
+	debug!("ipam={:?}", ipam);
+
+	let prev_result: Option<IpamSuccessReply> = config
+		.prev_result
+		.map(|p| serde_json::from_value(p).map_err(CniError::Json))
+		.transpose()?;
+	debug!("prevResult={:?}", prev_result);
+
+	let pools: Vec<Pool> = prev_result
+		.as_ref()
+		.map(|p| p.specific.get("pools").cloned())
+		.flatten()
+		.map(|p| serde_json::from_value(p).map_err(CniError::Json))
+		.transpose()?
+		.unwrap_or_default();
+	debug!("pools={:?}", pools);
+	// TODO: support multiple?
+
+	let consul_servers = ipam
+		.specific
+		.get("consul_servers")
+		.ok_or(CniError::MissingField("ipam.consul_servers"))
+		.and_then(|v| -> Result<Vec<Url>, _> {
+			serde_json::from_value(v.to_owned()).map_err(CniError::Json)
+		})?;
+	debug!(
+		"consul-servers={}",
+		consul_servers
+			.iter()
+			.map(ToString::to_string)
+			.collect::<Vec<String>>()
+			.join(",")
+	);
+
+	let tls = TlsOptions::from_specific(&ipam.specific)?;
+	debug!("tls={:?}", tls);
+
+	let consul_params = ConsulParams {
+		client: http_client(&tls)?,
+		datacenter: optional_string(
+			&ipam.specific,
+			"consul_datacenter",
+			"ipam.consul_datacenter",
+		)?,
+		namespace: optional_string(&ipam.specific, "consul_namespace", "ipam.consul_namespace")?,
+		token: optional_string(&ipam.specific, "consul_token", "ipam.consul_token")?
+			.or_else(|| env::var("CONSUL_HTTP_TOKEN").ok().filter(|t| !t.is_empty())),
+	};
+	debug!("consul-params={:?}", consul_params);
+
+	let strategy: AllocationStrategy = ipam
+		.specific
+		.get("allocationStrategy")
+		.map(|v| serde_json::from_value(v.to_owned()).map_err(CniError::Json))
+		.transpose()?
+		.unwrap_or_default();
+	debug!("allocation-strategy={:?}", strategy);
+	if strategy == AllocationStrategy::LeastRecentlyUsed {
+		return Err(CniError::InvalidField {
+			field: "ipam.allocationStrategy",
+			expected: "sequential or random",
+			value: serde_json::to_value(strategy).map_err(CniError::Json)?,
+		}
+		.into());
+	}
+
+	let emit_default_route = ipam
+		.specific
+		.get("emitDefaultRoute")
+		.map(|v| {
+			v.as_bool().ok_or_else(|| CniError::InvalidField {
+				field: "ipam.emitDefaultRoute",
+				expected: "boolean",
+				value: v.clone(),
+			})
+		})
+		.transpose()?
+		.unwrap_or(true);
+	debug!("emit-default-route={}", emit_default_route);
+
+	let register_aliases = ipam
+		.specific
+		.get("registerAliases")
+		.map(|v| {
+			v.as_bool().ok_or_else(|| CniError::InvalidField {
+				field: "ipam.registerAliases",
+				expected: "boolean",
+				value: v.clone(),
+			})
+		})
+		.transpose()?
+		.unwrap_or(false);
+	let aliases = if register_aliases {
+		config
+			.runtime
+			.as_ref()
+			.map(|rt| rt.aliases.clone())
+			.unwrap_or_default()
+	} else {
+		Vec::new()
+	};
+	debug!(
+		"register-aliases={} aliases={:?}",
+		register_aliases, aliases
+	);
+	names::validate(&aliases)?;
+
+	let audit = optional_string(&ipam.specific, "auditLog", "ipam.auditLog")?.map(AuditLog::new);
+	debug!("audit-log={:?}", audit);
+
+	let pool_source = match optional_string(&ipam.specific, "poolFile", "ipam.poolFile")? {
+		Some(path) => PoolSource::File(path.into()),
+		None => PoolSource::Consul,
+	};
+	debug!("pool-source={:?}", pool_source);
+
+	let consul_url = good_server(&consul_servers, &consul_params).await?;
+
+	let selected_pool = pools.first().cloned().ok_or(AppError::MissingResource {
+		remote: "prevResult",
+		resource: "pool",
+		path: "pools[0]".into(),
+	})?;
+	let pool_name = match prev_result.as_ref().and_then(|p| p.pool.clone()) {
+		Some(pool) if matches!(command, Command::Del | Command::Check) => {
+			debug!("using pool={} recorded in prevResult", pool);
+			pool
+		}
+		_ => selected_pool.name,
+	};
+	debug!(
+		"pool name={} requested-ip={:?}",
+		pool_name, selected_pool.requested_ip
+	);
+
+	match command {
+		Command::Add => {
+			let pool = pool_source
+				.pool_def(consul_url, &consul_params, &pool_name)
+				.await?;
+			let pool_known = pool_known(&consul_url, &consul_params, &pool_name).await?;
+
+			let (ip, gateway, existing) =
+				if let Some(ip) = selected_pool.requested_ip {
+					debug!("checking whether requested ip fits in the selected pool");
+
+					let range = pool.0.iter().find(|range| range.contains(ip)).ok_or(
+						AppError::NotInPool {
+							pool: pool_name.clone(),
+							ip,
+						},
+					)?;
+
+					let existing = requested_held(&pool_known, &pool_name, ip, &container_id)?;
+
+					// UNWRAP: panics on invalid prefix, but prefix comes from existing IpNetwork
+					(
+						IpNetwork::new(ip, range.subnet.prefix()).unwrap(),
+						range.gateway_or_first_host(),
+						existing,
+					)
+				} else {
+					debug!("looking for an ip already held by target={}", container_id);
+					let held = pool_known
+						.iter()
+						.filter(|(_, entry)| entry.target == container_id)
+						.find_map(|(ip, _)| {
+							let range = pool.0.iter().find(|range| range.contains(*ip));
+							if range.is_none() {
+								warn!(
+									"held address {} is outside of pool {}, ignoring",
+									ip, pool_name
+								);
+							}
+							range.map(|range| (*ip, range))
+						});
+
+					if let Some((ip, range)) = held {
+						// UNWRAP: panics on invalid prefix, but prefix comes from existing IpNetwork
+						(
+							IpNetwork::new(ip, range.subnet.prefix()).unwrap(),
+							range.gateway_or_first_host(),
+							true,
+						)
+					} else {
+						debug!("none requested, picking next ip in pool");
+						let used: HashSet<IpAddr> = pool_known.keys().copied().collect();
+
+						match strategy {
+							AllocationStrategy::Random => pool.next_free_random(&used),
+							_ => pool.next_free(&used),
+						}
+						.map(|(ip, range)| (ip, range.gateway_or_first_host(), false))
+						.ok_or_else(|| {
+							warn!(
+								"pool {} is full: {} known of {} usable addresses",
+								pool_name,
+								used.len(),
+								pool.usable_count()
+							);
+							AppError::PoolFull(pool_name.clone())
+						})?
+					}
+				};
+
+			debug!("ip={:?}", ip);
+
+			if existing {
+				info!("container already holds address {}, reusing it", ip);
+			} else {
+				let mut assign_url = consul_params
+					.url(consul_url, &format!("v1/kv/ipam/{}/{}", pool_name, ip.ip()))?;
+
+				debug!("creating address"); // cas=0 ensures that it will fail if it's an update
+				assign_url.query_pairs_mut().append_pair("cas", "0");
+
+				let success = consul::put_retrying(
+					&consul_params,
+					assign_url,
+					serde_json::to_value(PoolEntry {
+						target: container_id.clone(),
+					})
+					.map_err(CniError::Json)?,
+				)
+				.await?;
+
+				if !success {
+					// the cas was lost: another container got there first
+					error!("ipam/{}/{} was taken concurrently", pool_name, ip);
+					return Err(AppError::ConsulWriteFailed);
+				}
+
+				info!("allocated address {}", ip);
+				if let Some(audit) = &audit {
+					audit.allocated(&container_id, &pool_name, ip.ip());
+				}
+			}
+
+			if !aliases.is_empty() {
+				names::register(consul_url, &consul_params, &aliases, ip.ip(), &container_id)
+					.await?;
+			}
+
+			let mut reply =
+				IpamSuccessReply::single(config.cni_version, ip, gateway, emit_default_route);
+			reply.pool = Some(pool_name);
+			Ok(reply)
+		}
+		Command::Del => {
+			debug!(
+				"finding all known IPs in pool={} with target={}",
+				pool_name, container_id
+			);
+			let pool_known = pool_known(&consul_url, &consul_params, &pool_name).await?;
+			let rip: Vec<(IpAddr, usize)> = pool_known
+				.into_iter()
+				.filter(|(_, entry)| entry.target == container_id)
+				.map(|(ip, entry)| (ip, entry.index))
+				.collect();
+
+			let failed = consul::delete_all(
+				&consul_url,
+				&consul_params,
+				rip.iter()
+					.map(|(ip, index)| (format!("ipam/{}/{}", pool_name, ip), *index)),
+			)
+			.await?;
+			if !failed.is_empty() {
+				// leaving an IP allocated is better than failing the teardown
+				warn!("could not release {} IPs: {:?}", failed.len(), failed);
+			}
+
+			if let Some(audit) = &audit {
+				for (ip, _) in &rip {
+					if !failed.contains(&format!("ipam/{}/{}", pool_name, ip)) {
+						audit.released(&container_id, &pool_name, *ip);
+					}
+				}
+			}
+
+			if !aliases.is_empty() {
+				// the IPs are released already, so don't fail the whole delete
+				if let Err(err) =
+					names::deregister(consul_url, &consul_params, &aliases, &container_id).await
+				{
+					warn!("could not deregister aliases: {}", err);
+				}
+			}
+
+			Ok(IpamSuccessReply {
+				cni_version: config.cni_version,
+				ips: Vec::new(),
+				routes: Vec::new(),
+				dns: Default::default(),
+				pool: None,
+				specific: Default::default(),
+			})
+		}
+		Command::Check => {
+			let prev_result = prev_result.ok_or(CniError::MissingField("prevResult"))?;
+
+			debug!(
+				"checking IPs in prevResult are held in pool={} by target={}",
+				pool_name, container_id
+			);
+			let pool_known = pool_known(&consul_url, &consul_params, &pool_name).await?;
+			check_held(
+				&pool_known,
+				&pool_name,
+				&container_id,
+				prev_result.ips.iter().map(|ip| ip.address.ip()),
+			)?;
+
+			info!(
+				"all {} addresses in prevResult are held by the container",
+				prev_result.ips.len()
+			);
+			Ok(prev_result)
+		}
+		Command::Version => unreachable!(),
+	}
+}
+
+/// Whether the requested `ip` is already allocated to the container.
+///
+/// This errors if another container holds it, rather than take it over.
+fn requested_held(
+	pool_known: &BTreeMap<IpAddr, KnownPoolEntry>,
+	pool_name: &str,
+	ip: IpAddr,
+	container_id: &str,
+) -> AppResult<bool> {
+	match pool_known.get(&ip) {
+		Some(entry) if entry.target == container_id => Ok(true),
+		Some(entry) => {
+			error!(
+				"requested ip {} in pool {} is held by target={}",
+				ip, pool_name, entry.target
+			);
+			Err(AppError::ConsulWriteFailed)
+		}
+		None => Ok(false),
+	}
+}
+
+/// Checks that all the `ips` are allocated to the container in the pool.
+fn check_held(
+	pool_known: &BTreeMap<IpAddr, KnownPoolEntry>,
+	pool_name: &str,
+	container_id: &str,
+	mut ips: impl Iterator<Item = IpAddr>,
+) -> Result<(), CniError> {
+	ips.try_for_each(|ip| match pool_known.get(&ip) {
+		Some(entry) if entry.target == container_id => Ok(()),
+		Some(entry) => Err(CniError::Generic(format!(
+			"address {} in pool {} is held by {}, not {}",
+			ip, pool_name, entry.target, container_id
+		))),
+		None => Err(CniError::Generic(format!(
+			"address {} is not allocated in pool {}",
+			ip, pool_name
+		))),
+	})
+}
+
+/// Builds the client shared by all requests of the run.
+fn http_client(tls: &TlsOptions) -> AppResult<surf::Client> {
+	let mut builder = HttpClient::builder();
+	if let Some(ca_cert) = &tls.ca_cert {
+		builder = builder.ssl_ca_certificate(CaCertificate::file(ca_cert));
+	}
+	if tls.insecure {
+		warn!("not verifying TLS certificates");
+		builder = builder.ssl_options(
+			SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+		);
+	}
+
+	let client = builder.build().map_err(|err| AppError::Http(err.into()))?;
+	Ok(surf::Client::with_http_client(IsahcClient::from_client(
+		client,
+	)))
+}
+
+/// Reads an optional string field from the ipam config.
+fn optional_string(
+	specific: &HashMap<String, Value>,
+	key: &str,
+	field: &'static str,
+) -> Result<Option<String>, CniError> {
+	specific
+		.get(key)
+		.map(|v| {
+			v.as_str()
+				.map(ToOwned::to_owned)
+				.ok_or_else(|| CniError::InvalidField {
+					field,
+					expected: "string",
+					value: v.clone(),
+				})
+		})
+		.transpose()
+}
+
+async fn good_server<'u>(list: &'u [Url], params: &ConsulParams) -> AppResult<&'u Url> {
+	if list.is_empty() {
+		return Err(AppError::from(CniError::InvalidField {
+			field: "consul_servers",
+			expected: "list of servers",
+			value: serde_json::to_value(list).map_err(CniError::Json)?,
+		}));
+	}
+
+	let mut round = 1;
+	loop {
+		let res = first_reachable(list, |url| async move {
+			let res = params.get(params.url(url, "v1/kv/ipam/")?).await?;
+			if res.status().is_success() {
+				Ok(())
+			} else {
+				Err(AppError::from(CniError::Generic(format!(
+					"error status from consul: {}",
+					res.status()
+				))))
+			}
+		})
+		.await;
+
+		match res {
+			Ok((url, ())) => return Ok(url),
+			Err(err) if round >= SERVER_ROUNDS => return Err(err.into()),
+			Err(_) => {
+				let nap = Duration::from_millis(SERVER_BACKOFF_MS << (round - 1));
+				warn!(
+					"no good consul server in round {}, waiting {:?} before retrying",
+					round, nap
+				);
+				Timer::after(nap).await;
+				round += 1;
+			}
+		}
+	}
+}
+
+async fn pool_known(
+	consul_url: &Url,
+	params: &ConsulParams,
+	name: &str,
+) -> AppResult<BTreeMap<IpAddr, KnownPoolEntry>> {
+	let mut url = params.url(consul_url, &format!("v1/kv/ipam/{}/", name))?;
+	url.query_pairs_mut().append_key_only("recurse");
+	let mut res = params.get(url).await?;
+	if res.status() == StatusCode::NotFound {
+		// consul 404s a prefix without keys, which is just a new pool
+		debug!("pool {} has no keys yet", name);
+		return Ok(BTreeMap::new());
+	}
+
+	let known: Vec<ConsulPair<PoolEntry>> = res.body_json().await?;
+	let known: BTreeMap<IpAddr, KnownPoolEntry> =
+		known
+			.into_iter()
+			.filter(|pair| !pair.value.is_null())
+			.map(|pair| {
+				let key = pair.key.clone(); // for errors
+				pair.parse_value()
+					.map_err(|err| AppError::InvalidResource {
+						remote: "consul",
+						resource: "ip-pool",
+						path: key.clone(),
+						err: Box::new(CniError::Generic(format!(
+							"expected value to be a JSON string; {}",
+							err
+						))),
+					})
+					.and_then(|pair| {
+						let index = pair.modify_index;
+						pair.key
+							.split('/')
+							.last()
+							.ok_or_else(|| {
+								unreachable!("due to how the key is constructed it will always have at least one segment")
+							})
+							.and_then(|ip| {
+								IpAddr::from_str(ip).map_err(|err| AppError::InvalidResource {
+									remote: "consul",
+									resource: "ip-pool",
+									path: key.clone(),
+									err: Box::new(CniError::Generic(format!(
+										"expected key to be an IP address; {}",
+										err
+									))),
+								})
+							})
+							.map(|ip| {
+								(
+									ip,
+									if let ConsulValue::Parsed(v) = pair.value {
+										KnownPoolEntry {
+											target: v.target,
+											index,
+										}
+									} else {
+										unreachable!("consul value should be parsed and nulls already filtered")
+									},
+								)
+							})
+					})
+			})
+			.collect::<AppResult<BTreeMap<_, _>>>()?;
+
+	debug!("pool-known={:?}", known);
+	Ok(known)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PoolEntry {
+	pub target: String,
+}
+
+#[derive(Clone, Debug)]
+struct KnownPoolEntry {
+	pub target: String,
+	pub index: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Pool {
+	name: String,
+	requested_ip: Option<IpAddr>,
+}
+
+#[test]
+fn test_check_held() {
+	let entry = |target: &str| KnownPoolEntry {
+		target: target.into(),
+		index: 1,
+	};
+	let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+	let mut known = BTreeMap::new();
+	known.insert(ip("10.0.0.2"), entry("abc"));
+	known.insert(ip("10.0.0.3"), entry("def"));
+
+	assert!(check_held(&known, "pool", "abc", vec![ip("10.0.0.2")].into_iter()).is_ok());
+	assert!(check_held(&known, "pool", "abc", Vec::new().into_iter()).is_ok());
+	assert!(check_held(&known, "pool", "abc", vec![ip("10.0.0.3")].into_iter()).is_err());
+	assert!(check_held(&known, "pool", "abc", vec![ip("10.0.0.4")].into_iter()).is_err());
+}
+
+#[test]
+fn test_requested_held() {
+	let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+	let mut known = BTreeMap::new();
+	known.insert(
+		ip("10.0.0.2"),
+		KnownPoolEntry {
+			target: "abc".into(),
+			index: 1,
+		},
+	);
+
+	assert!(requested_held(&known, "pool", ip("10.0.0.2"), "abc").unwrap());
+	assert!(!requested_held(&known, "pool", ip("10.0.0.3"), "abc").unwrap());
+	assert!(matches!(
+		requested_held(&known, "pool", ip("10.0.0.2"), "def"),
+		Err(AppError::ConsulWriteFailed)
+	));
+}
@@ -0,0 +1,141 @@
+use std::net::IpAddr;
+
+use cni_plugin::error::CniError;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use surf::{StatusCode, Url};
+
+use crate::consul::{self, ConsulPair, ConsulParams};
+use crate::error::{AppError, AppResult};
+
+/// The value of an `ipam-names/alias` key.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NameEntry {
+	pub ip: IpAddr,
+	pub target: String,
+}
+
+/// Checks that aliases are hostname-like, so they can be used as keys as-is.
+///
+/// They must start and end with an alphanumeric character, so `.` and `..`
+/// (which would resolve outside of `ipam-names/` in the URL) are rejected.
+pub fn validate(aliases: &[String]) -> Result<(), CniError> {
+	for (i, alias) in aliases.iter().enumerate() {
+		let edges_ok = match (alias.chars().next(), alias.chars().last()) {
+			(Some(first), Some(last)) => {
+				first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric()
+			}
+			_ => false,
+		};
+
+		if !edges_ok
+			|| !alias
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+		{
+			return Err(CniError::InvalidFieldPath {
+				path: format!("runtimeConfig.aliases[{}]", i),
+				expected: "a hostname",
+				value: alias.as_str().into(),
+			});
+		}
+	}
+
+	Ok(())
+}
+
+/// Points each alias at the IP, replacing whatever it pointed to before.
+pub async fn register(
+	consul_url: &Url,
+	params: &ConsulParams,
+	aliases: &[String],
+	ip: IpAddr,
+	target: &str,
+) -> AppResult<()> {
+	for alias in aliases {
+		let url = params.url(consul_url, &format!("v1/kv/ipam-names/{}", alias))?;
+		let success = consul::put_retrying(
+			params,
+			url,
+			serde_json::to_value(NameEntry {
+				ip,
+				target: target.into(),
+			})
+			.map_err(CniError::Json)?,
+		)
+		.await?;
+
+		if !success {
+			return Err(AppError::ConsulWriteFailed);
+		}
+
+		info!("registered {} for {}", alias, ip);
+	}
+
+	Ok(())
+}
+
+/// Removes the aliases which still point to the target.
+pub async fn deregister(
+	consul_url: &Url,
+	params: &ConsulParams,
+	aliases: &[String],
+	target: &str,
+) -> AppResult<()> {
+	let mut owned = Vec::with_capacity(aliases.len());
+	for alias in aliases {
+		let url = params.url(consul_url, &format!("v1/kv/ipam-names/{}", alias))?;
+		let mut res = params.get(url).await?;
+		if res.status() == StatusCode::NotFound {
+			debug!("alias {} is not registered", alias);
+			continue;
+		}
+
+		let pairs: Vec<ConsulPair<NameEntry>> = res.body_json().await?;
+		for pair in pairs {
+			let key = pair.key.clone(); // for errors
+			let index = pair.modify_index;
+			let entry = pair
+				.parsed_value()
+				.map_err(|err| AppError::InvalidResource {
+					remote: "consul",
+					resource: "name",
+					path: key.clone(),
+					err: Box::new(err),
+				})?;
+
+			match entry {
+				Some(entry) if entry.target == target => owned.push((key, index)),
+				_ => debug!("alias {} now belongs to someone else, leaving it", alias),
+			}
+		}
+	}
+
+	if owned.is_empty() {
+		return Ok(());
+	}
+
+	let count = owned.len();
+	let failed = consul::delete_all(consul_url, params, owned.into_iter()).await?;
+	info!("deregistered {} aliases", count - failed.len());
+	if failed.is_empty() {
+		Ok(())
+	} else {
+		Err(AppError::ConsulWriteFailed)
+	}
+}
+
+#[test]
+fn test_validate() {
+	assert!(validate(&["web".into(), "web-1.service_a".into()]).is_ok());
+	assert!(matches!(
+		validate(&["web".into(), "../ipam/pool".into()]),
+		Err(CniError::InvalidFieldPath { path, .. }) if path == "runtimeConfig.aliases[1]"
+	));
+	assert!(validate(&["".into()]).is_err());
+	assert!(validate(&[".".into()]).is_err());
+	assert!(validate(&["..".into()]).is_err());
+	assert!(validate(&["-web".into()]).is_err());
+	assert!(validate(&["web.".into()]).is_err());
+	assert!(validate(&["a".into()]).is_ok());
+}
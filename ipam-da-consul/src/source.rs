@@ -0,0 +1,119 @@
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use blocking::unblock;
+use cni_plugin::{error::CniError, ip_range::IpPool};
+use log::debug;
+use url::Url;
+
+use crate::consul::{ConsulPair, ConsulParams};
+use crate::error::{AppError, AppResult};
+
+/// Where pool definitions are read from.
+///
+/// Allocations are always kept in consul, this is only for the ranges.
+#[derive(Clone, Debug)]
+pub enum PoolSource {
+	/// The `ipam/pool-name` key in consul KV.
+	Consul,
+
+	/// A JSON file with an object of pool names to arrays of IP ranges.
+	File(PathBuf),
+}
+
+impl PoolSource {
+	pub async fn pool_def(
+		&self,
+		consul_url: &Url,
+		params: &ConsulParams,
+		name: &str,
+	) -> AppResult<IpPool> {
+		let pool = match self {
+			Self::Consul => consul_pool_def(consul_url, params, name).await?,
+			Self::File(path) => file_pool_def(path, name).await?,
+		};
+
+		debug!("pool={:?}", pool);
+		Ok(pool)
+	}
+}
+
+async fn consul_pool_def(consul_url: &Url, params: &ConsulParams, name: &str) -> AppResult<IpPool> {
+	let pool_url = params.url(consul_url, &format!("v1/kv/ipam/{}", name))?;
+	let pool: Vec<ConsulPair<IpPool>> = params.get(pool_url).recv_json().await?;
+
+	pool.into_iter()
+		.next()
+		.ok_or(AppError::MissingResource {
+			remote: "consul",
+			resource: "pool",
+			path: format!("ipam/{}", name),
+		})?
+		.parsed_value()
+		.map_err(|err| AppError::InvalidResource {
+			remote: "consul",
+			resource: "pool",
+			path: format!("ipam/{}", name),
+			err: Box::new(err),
+		})?
+		.ok_or(AppError::InvalidResource {
+			remote: "consul",
+			resource: "pool",
+			path: format!("ipam/{}", name),
+			err: Box::new(CniError::Generic(
+				"expected IpRange as JSON, got null".into(),
+			)),
+		})
+}
+
+async fn file_pool_def(path: &Path, name: &str) -> AppResult<IpPool> {
+	let contents = {
+		let path = path.to_owned();
+		unblock(move || std::fs::read(path))
+			.await
+			.map_err(CniError::Io)?
+	};
+	let mut pools: HashMap<String, IpPool> =
+		serde_json::from_slice(&contents).map_err(|err| AppError::InvalidResource {
+			remote: "file",
+			resource: "pools",
+			path: path.display().to_string(),
+			err: Box::new(err),
+		})?;
+
+	pools.remove(name).ok_or(AppError::MissingResource {
+		remote: "file",
+		resource: "pool",
+		path: format!("{}#{}", path.display(), name),
+	})
+}
+
+#[test]
+fn test_file_pool_def() {
+	use async_std::task::block_on;
+
+	let path =
+		std::env::temp_dir().join(format!("ipam-da-consul-test-{}.json", std::process::id()));
+	std::fs::write(
+		&path,
+		r#"{"web": [{"subnet": "10.0.0.0/24"}], "db": [{"subnet": "10.0.1.0/24"}]}"#,
+	)
+	.unwrap();
+
+	let pool = block_on(file_pool_def(&path, "db")).unwrap();
+	assert_eq!(pool.0[0].subnet, "10.0.1.0/24".parse().unwrap());
+	assert!(matches!(
+		block_on(file_pool_def(&path, "cache")),
+		Err(AppError::MissingResource { remote: "file", .. })
+	));
+
+	std::fs::write(&path, "[]").unwrap();
+	assert!(matches!(
+		block_on(file_pool_def(&path, "db")),
+		Err(AppError::InvalidResource { remote: "file", .. })
+	));
+
+	std::fs::remove_file(&path).unwrap();
+}
@@ -1,12 +1,14 @@
-use async_std::task::block_on;
+use std::time::{Duration, Instant};
+
+use async_std::{future::timeout, task::block_on};
 use cni_plugin::{
 	delegation::delegate,
 	error::CniError,
 	reply::{reply, IpamSuccessReply},
 	Cni, Command,
 };
-use log::{debug, error, info};
-use serde_json::{from_value, to_value};
+use log::{debug, error, info, warn};
+use serde_json::{from_value, to_value, Value};
 
 fn main() {
 	cni_plugin::logger::install(env!("CARGO_PKG_NAME"));
@@ -16,7 +18,7 @@ fn main() {
 		env!("CARGO_PKG_VERSION")
 	);
 
-	let cni = Cni::load();
+	let cni = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 
 	let (command, config) = match cni {
 		Cni::Add { config, .. } => (Command::Add, config),
@@ -26,17 +28,15 @@ fn main() {
 	};
 	let cni_version = config.cni_version.clone(); // for error
 	info!(
-		"{} serving spec v{} for command={:?}",
+		"{} serving spec v{} for command={}",
 		env!("CARGO_PKG_NAME"),
 		cni_version,
 		command
 	);
 
 	let res: Result<IpamSuccessReply, CniError> = block_on(async move {
-		let delegated_plugins = config
-			.ipam
-			.clone()
-			.ok_or(CniError::MissingField("ipam"))?
+		let ipam = config.ipam.clone().ok_or(CniError::MissingField("ipam"))?;
+		let delegated_plugins = ipam
 			.specific
 			.get("delegates")
 			.ok_or(CniError::MissingField("ipam.delegates"))
@@ -54,44 +54,88 @@ fn main() {
 			});
 		}
 
+		let deadline = ipam
+			.specific
+			.get("deadlineMs")
+			.map(parse_deadline)
+			.transpose()?;
+		debug!("deadline={:?}", deadline);
+
 		let mut config = config;
 		match command {
 			Command::Add => {
-				let mut last_result = None;
+				let started = Instant::now();
+				let mut merged: Option<IpamSuccessReply> = None;
 				let mut undo: Vec<String> = Vec::with_capacity(delegated_plugins.len());
 
 				for plugin in delegated_plugins {
 					undo.push(plugin.clone());
 
-					let result: IpamSuccessReply =
-						match delegate(&plugin, Command::Add, &config).await {
-							Ok(reply) => reply,
-							Err(err) => {
-								let mut errors = Vec::with_capacity(undo.len() + 1);
-								errors.push((plugin.clone(), err));
-
-								for plugin in undo {
-									let result: IpamSuccessReply =
-										match delegate(&plugin, Command::Del, &config).await {
-											Ok(reply) => reply,
-											Err(err) => {
-												errors.push((plugin, err));
-												continue;
-											}
-										};
-
-									config.prev_result = Some(to_value(&result)?);
-								}
+					let delegated = delegate(&plugin, Command::Add, &config);
+					let delegated = if let Some(deadline) = deadline {
+						let left = deadline.checked_sub(started.elapsed()).unwrap_or_default();
+						timeout(left, delegated)
+							.await
+							.unwrap_or(Err(CniError::Timeout(deadline)))
+					} else {
+						delegated.await
+					};
 
-								return Err(multi_error(errors));
+					let result: IpamSuccessReply = match delegated {
+						Ok(reply) => reply,
+						Err(err) => {
+							let timed_out = matches!(err, CniError::Timeout(_));
+							if timed_out {
+								error!(
+									"deadline of {:?} exceeded while running {}",
+									deadline, plugin
+								);
+							}
+
+							let mut errors = Vec::with_capacity(undo.len() + 1);
+							errors.push((plugin.clone(), err));
+
+							for plugin in undo {
+								let result: IpamSuccessReply =
+									match delegate(&plugin, Command::Del, &config).await {
+										Ok(reply) => reply,
+										Err(err) => {
+											errors.push((plugin, err));
+											continue;
+										}
+									};
+
+								config.prev_result = Some(to_value(&result)?);
 							}
-						};
+
+							if timed_out {
+								let mut errors = errors.into_iter();
+								// UNWRAP: the timeout is always the first error
+								let (_, timeout) = errors.next().unwrap();
+								for (plugin, err) in errors {
+									warn!("rolling back {} after timeout: {}", plugin, err);
+								}
+								return Err(timeout);
+							}
+
+							return Err(CniError::DelegatedMany(errors));
+						}
+					};
+
+					// a delegate which doesn't carry the prevResult through mustn't lose it
+					let result = match merged {
+						Some(mut merged) => {
+							merged.merge(result);
+							merged
+						}
+						None => result,
+					};
 
 					config.prev_result = Some(to_value(&result)?);
-					last_result = Some(result);
+					merged = Some(result);
 				}
 
-				if let Some(result) = last_result {
+				if let Some(result) = merged {
 					Ok(result)
 				} else {
 					Err(CniError::Generic("no IPAM delegated plugins ran".into()))
@@ -115,7 +159,7 @@ fn main() {
 				}
 
 				if !errors.is_empty() {
-					Err(multi_error(errors))
+					Err(CniError::DelegatedMany(errors))
 				} else if let Some(result) = last_result {
 					Ok(result)
 				} else {
@@ -135,19 +179,32 @@ fn main() {
 	}
 }
 
-fn multi_error(errors: Vec<(String, CniError)>) -> CniError {
-	CniError::Delegated {
-		err: Box::new(CniError::Generic(
-			errors
-				.iter()
-				.map(|e| e.1.to_string())
-				.collect::<Vec<String>>()
-				.join("\n"),
-		)),
-		plugin: errors
-			.into_iter()
-			.map(|e| e.0)
-			.collect::<Vec<String>>()
-			.join(","),
+/// Parses `ipam.deadlineMs`, which must be a positive number of milliseconds.
+fn parse_deadline(value: &Value) -> Result<Duration, CniError> {
+	value
+		.as_u64()
+		.filter(|ms| *ms > 0)
+		.map(Duration::from_millis)
+		.ok_or_else(|| CniError::InvalidField {
+			field: "ipam.deadlineMs",
+			expected: "milliseconds as a positive integer",
+			value: value.clone(),
+		})
+}
+
+#[test]
+fn test_parse_deadline() {
+	assert_eq!(
+		parse_deadline(&Value::from(1500)).unwrap(),
+		Duration::from_millis(1500)
+	);
+	for invalid in &[Value::from(0), Value::from(-5), Value::from("1500")] {
+		assert!(matches!(
+			parse_deadline(invalid),
+			Err(CniError::InvalidField {
+				field: "ipam.deadlineMs",
+				..
+			})
+		));
 	}
 }
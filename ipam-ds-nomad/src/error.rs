@@ -1,5 +1,4 @@
-use cni_plugin::{error::CniError, reply::ErrorReply};
-use semver::Version;
+use cni_plugin::{error::CniError, reply::ErrorReply, CniVersion};
 use thiserror::Error;
 
 pub type AppResult<T> = Result<T, AppError>;
@@ -31,7 +30,7 @@ pub enum AppError {
 }
 
 impl AppError {
-	pub fn into_reply(self, cni_version: Version) -> ErrorReply<'static> {
+	pub fn into_reply(self, cni_version: CniVersion) -> ErrorReply<'static> {
 		match self {
 			Self::Cni(e) => e.into_reply(cni_version),
 			e @ AppError::Url(_) => ErrorReply {
@@ -1,13 +1,20 @@
-use std::{collections::HashMap, net::IpAddr};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
 
-use async_std::task::block_on;
+use async_std::task::{block_on, sleep};
 use cni_plugin::{
 	error::CniError,
+	net::{first_reachable, TlsOptions},
 	reply::{reply, Dns, IpamSuccessReply},
-	Cni,
+	Cni, CniVersion, Command, Inputs,
+};
+use http_client::isahc::IsahcClient;
+use isahc::{
+	config::{CaCertificate, Configurable, SslOption},
+	HttpClient,
 };
 use log::{debug, error, info, warn};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use url::Url;
 
 use crate::error::{AppError, AppResult};
@@ -16,6 +23,12 @@ use crate::nomad::Alloc;
 mod error;
 mod nomad;
 
+/// How many times to go through the list of nomad servers before giving up.
+const SERVER_ROUNDS: u32 = 3;
+
+/// Initial wait between rounds, doubled on each subsequent round.
+const SERVER_BACKOFF_MS: u64 = 50;
+
 fn main() {
 	cni_plugin::logger::install(env!("CARGO_PKG_NAME"));
 	debug!(
@@ -24,156 +37,322 @@ fn main() {
 		env!("CARGO_PKG_VERSION")
 	);
 
-	match Cni::load() {
-		Cni::Add {
-			container_id,
-			config,
-			..
+	// UNWRAP: None on Version, but Version is handled by load()
+	let inputs = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
+	let alloc_id = inputs.effective_container_id();
+	let Inputs {
+		command, config, ..
+	} = inputs;
+
+	let cni_version = config.cni_version.clone(); // for error
+	info!(
+		"{} serving spec v{} for command={}",
+		env!("CARGO_PKG_NAME"),
+		cni_version,
+		command
+	);
+
+	let res: AppResult<IpamSuccessReply> = block_on(async move {
+		let ipam = config.ipam.clone().ok_or(CniError::MissingField("ipam"))?;
+		debug!("ipam={:?}", ipam);
+
+		let nomad_servers = ipam
+			.specific
+			.get("nomad_servers")
+			.ok_or(CniError::MissingField("ipam.nomad_servers"))
+			.and_then(|v| -> Result<Vec<Url>, _> {
+				serde_json::from_value(v.to_owned()).map_err(CniError::Json)
+			})?;
+		debug!(
+			"nomad-servers={}",
+			nomad_servers
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<String>>()
+				.join(",")
+		);
+
+		let tls = TlsOptions::from_specific(&ipam.specific)?;
+		debug!("tls={:?}", tls);
+		let client = http_client(&tls)?;
+
+		let alloc = fetch_alloc(&client, &nomad_servers, &alloc_id).await?;
+		debug!("alloc={:?}", alloc);
+
+		debug!("checking we have the group definition");
+		let group = alloc
+			.job
+			.task_groups
+			.iter()
+			.find(|g| g.name == alloc.task_group)
+			.ok_or(AppError::InvalidResource {
+				remote: "nomad",
+				resource: "allocation",
+				path: alloc_id.clone(),
+				err: Box::new(CniError::Generic(format!(
+					"alloc {} is for task group {} but its own job definition is missing it",
+					alloc_id, alloc.task_group
+				))),
+			})?
+			.clone();
+
+		debug!("checking group network is a cni network");
+		if let Some(network_mode) = group.networks.first().map(|n| &n.mode) {
+			if !network_mode.starts_with("cni/") {
+				return Err(CniError::InvalidField {
+					field: "alloc.group.networks[0].mode",
+					expected: "cni/<name>",
+					value: network_mode.as_str().into(),
+				}
+				.into());
+			}
+		} else {
+			return Err(CniError::MissingField("alloc.group.networks[0]").into());
 		}
-		| Cni::Del {
-			container_id,
-			config,
-			..
+
+		debug!("reading pool name");
+		let name = group
+			.meta
+			.network_pool
+			.ok_or(CniError::MissingField("alloc.group.meta.network-pool"))?;
+		info!("pool-name={}", name);
+
+		debug!("reading requested ip");
+		let requested_ip = group.meta.network_ip;
+		info!("requested-ip={:?}", requested_ip);
+
+		let pools = vec![Pool { name, requested_ip }];
+		pools_reply(command, config.cni_version, config.prev_result, pools)
+
+		// TODO: support multiple cni networks / multiple groups?
+	});
+
+	match res {
+		Ok(res) => reply(res),
+		Err(res) => {
+			error!("error: {}", res);
+			reply(res.into_reply(cni_version))
 		}
-		| Cni::Check {
-			container_id,
-			config,
-			..
-		} => {
-			let cni_version = config.cni_version.clone(); // for error
-			info!(
-				"{} serving spec v{} for command=any",
-				env!("CARGO_PKG_NAME"),
-				cni_version
-			);
-
-			let res: AppResult<IpamSuccessReply> = block_on(async move {
-				let alloc_id = container_id;
-
-				let ipam = config.ipam.clone().ok_or(CniError::MissingField("ipam"))?;
-				debug!("ipam={:?}", ipam);
-
-				let mut nomad_servers = ipam
-					.specific
-					.get("nomad_servers")
-					.ok_or(CniError::MissingField("ipam.nomad_servers"))
-					.and_then(|v| -> Result<Vec<Url>, _> {
-						serde_json::from_value(v.to_owned()).map_err(CniError::Json)
-					})?;
-				debug!(
-					"nomad-servers={}",
-					nomad_servers
-						.iter()
-						.map(ToString::to_string)
-						.collect::<Vec<String>>()
-						.join(",")
-				);
+	}
+}
 
-				nomad_servers.reverse();
-				let mut nomad_url = nomad_servers
-					.pop()
-					.ok_or(CniError::MissingField("ipam.nomad_servers"))?;
-				let alloc: Alloc = loop {
-					match surf::get(nomad_url.join("v1/allocation/")?.join(&alloc_id)?)
-						.recv_json()
-						.await
-						.map_err(|err| AppError::Fetch {
-							remote: "nomad",
-							resource: "allocation",
-							err: err.into(),
-						}) {
-						Ok(res) => {
-							debug!("found good nomad server: {}", nomad_url);
-							break res;
-						}
-						Err(err) => {
-							if let Some(url) = nomad_servers.pop() {
-								warn!("bad nomad server, trying next. err={}", err);
-								nomad_url = url;
-							} else {
-								return Err(err);
-							}
-						}
-					}
-				};
-				debug!("alloc={:?}", alloc);
-
-				debug!("checking we have the group definition");
-				let group = alloc.job.task_groups.iter().find(|g| g.name == alloc.task_group).ok_or(AppError::InvalidResource {
-					remote: "nomad",
-					resource: "allocation",
-					path: alloc_id.clone(),
-					err: Box::new(CniError::Generic(format!("alloc {} is for task group {} but its own job definition is missing it", alloc_id, alloc.task_group)))
-				})?.clone();
-
-				debug!("checking group network is a cni network");
-				if let Some(network_mode) = group.networks.first().map(|n| &n.mode) {
-					if !network_mode.starts_with("cni/") {
-						return Err(CniError::InvalidField {
-							field: "alloc.group.networks[0].mode",
-							expected: "cni/<name>",
-							value: network_mode.as_str().into(),
-						}
-						.into());
-					}
-				} else {
-					return Err(CniError::MissingField("alloc.group.networks[0]").into());
-				}
+/// Builds the reply with the selected pools, carrying the previous IPs through.
+///
+/// On CHECK, the `prevResult` is required and must be consistent with the pools.
+fn pools_reply(
+	command: Command,
+	cni_version: CniVersion,
+	prev_result: Option<Value>,
+	pools: Vec<Pool>,
+) -> AppResult<IpamSuccessReply> {
+	let mut specific = HashMap::new();
+	specific.insert(
+		"pools".into(),
+		serde_json::to_value(&pools).map_err(CniError::Json)?,
+	);
 
-				debug!("reading pool name");
-				let name = group
-					.meta
-					.network_pool
-					.ok_or(CniError::MissingField("alloc.group.meta.network-pool"))?;
-				info!("pool-name={}", name);
-
-				debug!("reading requested ip");
-				let requested_ip = group.meta.network_ip;
-				info!("requested-ip={:?}", requested_ip);
-
-				let mut specific = HashMap::new();
-				specific.insert(
-					"pools".into(),
-					serde_json::to_value(&vec![Pool { name, requested_ip }])
-						.map_err(CniError::Json)?,
-				);
+	let (ips, pool) = if matches!(command, Command::Check) {
+		let prev_result: IpamSuccessReply = prev_result
+			.map(serde_json::from_value)
+			.transpose()
+			.map_err(CniError::Json)?
+			.ok_or(CniError::MissingField("prevResult"))?;
 
-				let ips = if let Some(prev_ipam) =
-					config
-						.prev_result
-						.and_then(|val| -> Option<IpamSuccessReply> {
-							serde_json::from_value(val).ok()
-						}) {
-					prev_ipam.ips
-				} else {
-					Vec::new()
-				};
-
-				Ok(IpamSuccessReply {
-					cni_version: config.cni_version,
-					ips,
-					routes: Vec::new(),
-					dns: Dns::default(),
-					specific,
-				})
+		check_prev(&pools, &prev_result)?;
+		info!("prevResult is consistent with the selected pool");
+		(prev_result.ips, prev_result.pool)
+	} else if let Some(prev_ipam) =
+		prev_result.and_then(|val| -> Option<IpamSuccessReply> { serde_json::from_value(val).ok() })
+	{
+		(prev_ipam.ips, prev_ipam.pool)
+	} else {
+		(Vec::new(), None)
+	};
+
+	Ok(IpamSuccessReply {
+		cni_version,
+		ips,
+		routes: Vec::new(),
+		dns: Dns::default(),
+		pool,
+		specific,
+	})
+}
 
-				// TODO: support multiple cni networks / multiple groups?
+/// Checks that a previous result could have come from the selected pools.
+///
+/// Requested IPs must have been allocated, and if the previous result still
+/// has its `pools`, they must be the same as the selected ones.
+fn check_prev(pools: &[Pool], prev_result: &IpamSuccessReply) -> Result<(), CniError> {
+	if let Some(prev_pools) = prev_result.specific.get("pools") {
+		let prev_pools: Vec<Pool> = serde_json::from_value(prev_pools.clone())?;
+		if prev_pools != pools {
+			return Err(CniError::InvalidField {
+				field: "prevResult.pools",
+				expected: "the selected pools",
+				value: serde_json::to_value(prev_pools)?,
 			});
+		}
+	}
 
-			match res {
-				Ok(res) => reply(res),
-				Err(res) => {
-					error!("error: {}", res);
-					reply(res.into_reply(cni_version))
-				}
+	for pool in pools {
+		if let Some(ip) = pool.requested_ip {
+			if !prev_result.ips.iter().any(|prev| prev.address.ip() == ip) {
+				return Err(CniError::InvalidField {
+					field: "prevResult.ips",
+					expected: "the requested ip of the pool",
+					value: ip.to_string().into(),
+				});
 			}
 		}
-		Cni::Version(_) => unreachable!(),
 	}
+
+	Ok(())
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// Builds the client shared by all requests of the run.
+fn http_client(tls: &TlsOptions) -> AppResult<surf::Client> {
+	let mut builder = HttpClient::builder();
+	if let Some(ca_cert) = &tls.ca_cert {
+		builder = builder.ssl_ca_certificate(CaCertificate::file(ca_cert));
+	}
+	if tls.insecure {
+		warn!("not verifying TLS certificates");
+		builder = builder.ssl_options(
+			SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+		);
+	}
+
+	let client = builder
+		.build()
+		.map_err(|err| CniError::Generic(format!("cannot build http client: {}", err)))?;
+	Ok(surf::Client::with_http_client(IsahcClient::from_client(
+		client,
+	)))
+}
+
+async fn fetch_alloc(client: &surf::Client, list: &[Url], alloc_id: &str) -> AppResult<Alloc> {
+	if list.is_empty() {
+		return Err(CniError::MissingField("ipam.nomad_servers").into());
+	}
+
+	let mut round = 1;
+	loop {
+		let res = first_reachable(list, |url| async move {
+			client
+				.get(url.join("v1/allocation/")?.join(alloc_id)?)
+				.recv_json()
+				.await
+				.map_err(|err| AppError::Fetch {
+					remote: "nomad",
+					resource: "allocation",
+					err: err.into(),
+				})
+		})
+		.await;
+
+		match res {
+			Ok((_, alloc)) => return Ok(alloc),
+			Err(err) if round >= SERVER_ROUNDS => return Err(err.into()),
+			Err(_) => {
+				let nap = Duration::from_millis(SERVER_BACKOFF_MS << (round - 1));
+				warn!(
+					"no good nomad server in round {}, waiting {:?} before retrying",
+					round, nap
+				);
+				sleep(nap).await;
+				round += 1;
+			}
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 struct Pool {
 	name: String,
 	requested_ip: Option<IpAddr>,
 }
+
+#[test]
+fn test_pools_reply() {
+	let version: CniVersion = "1.0.0".parse().unwrap();
+	let pools = || {
+		vec![Pool {
+			name: "pool".into(),
+			requested_ip: Some("10.0.0.2".parse().unwrap()),
+		}]
+	};
+	let prev = |address: &str| {
+		serde_json::json!({
+			"cniVersion": "1.0.0",
+			"ips": [{"address": address}],
+			"pool": "pool",
+		})
+	};
+
+	let reply = pools_reply(Command::Add, version.clone(), None, pools()).unwrap();
+	assert!(reply.ips.is_empty());
+	assert_eq!(
+		reply.specific["pools"],
+		serde_json::json!([{"name": "pool", "requested_ip": "10.0.0.2"}])
+	);
+
+	let reply = pools_reply(
+		Command::Add,
+		version.clone(),
+		Some(prev("10.0.0.9/24")),
+		pools(),
+	)
+	.unwrap();
+	assert_eq!(reply.ips.len(), 1);
+	assert_eq!(reply.pool.as_deref(), Some("pool"));
+
+	let reply = pools_reply(
+		Command::Check,
+		version.clone(),
+		Some(prev("10.0.0.2/24")),
+		pools(),
+	)
+	.unwrap();
+	assert_eq!(reply.ips.len(), 1);
+
+	assert!(matches!(
+		pools_reply(Command::Check, version.clone(), None, pools()),
+		Err(AppError::Cni(CniError::MissingField("prevResult")))
+	));
+	assert!(matches!(
+		pools_reply(Command::Check, version, Some(prev("10.0.0.3/24")), pools()),
+		Err(AppError::Cni(CniError::InvalidField {
+			field: "prevResult.ips",
+			..
+		}))
+	));
+}
+
+#[test]
+fn test_check_prev() {
+	let pools = vec![Pool {
+		name: "pool".into(),
+		requested_ip: None,
+	}];
+	let mut prev_result: IpamSuccessReply = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"ips": [{"address": "10.0.0.2/24"}],
+	}))
+	.unwrap();
+	assert!(check_prev(&pools, &prev_result).is_ok());
+
+	prev_result.specific.insert(
+		"pools".into(),
+		serde_json::json!([{ "name": "other", "requested_ip": null }]),
+	);
+	assert!(matches!(
+		check_prev(&pools, &prev_result),
+		Err(CniError::InvalidField {
+			field: "prevResult.pools",
+			..
+		})
+	));
+}
@@ -1,12 +1,15 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::IpAddr};
 
 use async_std::task::block_on;
 use cni_plugin::{
 	error::CniError,
-	reply::{reply, IpamSuccessReply},
-	Cni,
+	reply::{reply, Ip, IpamSuccessReply},
+	Cni, Command, Inputs,
 };
+use ipnetwork::IpNetwork;
 use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 fn main() {
 	cni_plugin::logger::install(env!("CARGO_PKG_NAME"));
@@ -16,56 +19,252 @@ fn main() {
 		env!("CARGO_PKG_VERSION")
 	);
 
-	match Cni::load() {
-		Cni::Add { config, .. } | Cni::Del { config, .. } | Cni::Check { config, .. } => {
-			let cni_version = config.cni_version.clone(); // for error
-			info!(
-				"{} serving spec v{} for command=any",
-				env!("CARGO_PKG_NAME"),
-				cni_version
-			);
-
-			let res: Result<IpamSuccessReply, CniError> = block_on(async move {
-				let ipam = config.ipam.clone().ok_or(CniError::MissingField("ipam"))?;
-				debug!("ipam={:?}", ipam);
-
-				let pools = ipam
-					.specific
-					.get("pools")
-					.ok_or(CniError::MissingField("ipam.pools"))?
-					.clone();
-
-				let mut specific = HashMap::new();
-				specific.insert("pools".into(), pools);
-
-				let ips = if let Some(prev_ipam) =
-					config
-						.prev_result
-						.and_then(|val| -> Option<IpamSuccessReply> {
-							serde_json::from_value(val).ok()
-						}) {
-					prev_ipam.ips
-				} else {
-					Vec::new()
-				};
-
-				Ok(IpamSuccessReply {
-					cni_version: config.cni_version,
-					ips,
-					routes: Vec::new(),
-					dns: Default::default(),
-					specific,
+	// UNWRAP: None on Version, but Version is handled by load()
+	let Inputs {
+		command, config, ..
+	} = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
+
+	let cni_version = config.cni_version.clone(); // for error
+	info!(
+		"{} serving spec v{} for command={}",
+		env!("CARGO_PKG_NAME"),
+		cni_version,
+		command
+	);
+
+	let res: Result<IpamSuccessReply, CniError> = block_on(async move {
+		let ipam = config.ipam.clone().ok_or(CniError::MissingField("ipam"))?;
+		debug!("ipam={:?}", ipam);
+
+		let runtime_ips = config
+			.runtime
+			.as_ref()
+			.map(|rt| rt.ips.clone())
+			.unwrap_or_default();
+		debug!("runtime-ips={:?}", runtime_ips);
+
+		let subnets: Vec<IpNetwork> = ipam
+			.specific
+			.get("subnets")
+			.map(|v| serde_json::from_value(v.clone()))
+			.transpose()?
+			.unwrap_or_default();
+		debug!("subnets={:?}", subnets);
+		check_runtime_ips(&runtime_ips, &subnets)?;
+
+		let pools = match ipam.specific.get("pools") {
+			Some(pools) => parse_pools(pools)?,
+			// static IPs from the runtime don't need a pool
+			None if !runtime_ips.is_empty() => Vec::new(),
+			None => return Err(CniError::MissingField("ipam.pools")),
+		};
+		debug!("pools={:?}", pools);
+
+		let mut specific = HashMap::new();
+		specific.insert("pools".into(), serde_json::to_value(&pools)?);
+
+		let (ips, pool) = if matches!(command, Command::Check) {
+			let prev_result: IpamSuccessReply = config
+				.prev_result
+				.map(serde_json::from_value)
+				.transpose()?
+				.ok_or(CniError::MissingField("prevResult"))?;
+
+			check_prev(&pools, &prev_result)?;
+			info!("prevResult is consistent with the selected pools");
+			(prev_result.ips, prev_result.pool)
+		} else if matches!(command, Command::Add) && !runtime_ips.is_empty() {
+			info!("using static ips from the runtime");
+			let ips = runtime_ips
+				.into_iter()
+				.map(|address| Ip {
+					address,
+					gateway: None,
+					interface: None,
 				})
-			});
+				.collect();
+			(ips, None)
+		} else if let Some(prev_ipam) = config
+			.prev_result
+			.and_then(|val| -> Option<IpamSuccessReply> { serde_json::from_value(val).ok() })
+		{
+			(prev_ipam.ips, prev_ipam.pool)
+		} else {
+			(Vec::new(), None)
+		};
+
+		Ok(IpamSuccessReply {
+			cni_version: config.cni_version,
+			ips,
+			routes: Vec::new(),
+			dns: Default::default(),
+			pool,
+			specific,
+		})
+	});
 
-			match res {
-				Ok(res) => reply(res),
-				Err(res) => {
-					error!("error: {}", res);
-					reply(res.into_reply(cni_version))
+	match res {
+		Ok(res) => reply(res),
+		Err(res) => {
+			error!("error: {}", res);
+			reply(res.into_reply(cni_version))
+		}
+	}
+}
+
+fn parse_pools(pools: &Value) -> Result<Vec<Pool>, CniError> {
+	let pools = pools.as_array().ok_or_else(|| CniError::InvalidField {
+		field: "ipam.pools",
+		expected: "list of pools",
+		value: pools.clone(),
+	})?;
+
+	pools
+		.iter()
+		.enumerate()
+		.map(|(i, pool)| {
+			serde_json::from_value(pool.clone()).map_err(|err| {
+				debug!("pool {} is invalid: {}", i, err);
+				CniError::InvalidFieldPath {
+					path: format!("ipam.pools[{}]", i),
+					expected: "pool object",
+					value: pool.clone(),
 				}
+			})
+		})
+		.collect()
+}
+
+/// Checks that static IPs from the runtime are within the subnets, if any.
+fn check_runtime_ips(ips: &[IpNetwork], subnets: &[IpNetwork]) -> Result<(), CniError> {
+	if subnets.is_empty() {
+		return Ok(());
+	}
+
+	for (i, ip) in ips.iter().enumerate() {
+		if !subnets.iter().any(|subnet| subnet.contains(ip.ip())) {
+			return Err(CniError::InvalidFieldPath {
+				path: format!("runtimeConfig.ips[{}]", i),
+				expected: "an IP within ipam.subnets",
+				value: ip.to_string().into(),
+			});
+		}
+	}
+
+	Ok(())
+}
+
+/// Checks that a previous result could have come from the selected pools.
+///
+/// Requested IPs must have been allocated, and if the previous result still
+/// has its `pools`, they must be the same as the selected ones.
+fn check_prev(pools: &[Pool], prev_result: &IpamSuccessReply) -> Result<(), CniError> {
+	if let Some(prev_pools) = prev_result.specific.get("pools") {
+		let prev_pools: Vec<Pool> = serde_json::from_value(prev_pools.clone())?;
+		if prev_pools != pools {
+			return Err(CniError::InvalidField {
+				field: "prevResult.pools",
+				expected: "the selected pools",
+				value: serde_json::to_value(prev_pools)?,
+			});
+		}
+	}
+
+	for pool in pools {
+		if let Some(ip) = pool.requested_ip {
+			if !prev_result.ips.iter().any(|prev| prev.address.ip() == ip) {
+				return Err(CniError::InvalidField {
+					field: "prevResult.ips",
+					expected: "the requested ip of the pool",
+					value: ip.to_string().into(),
+				});
 			}
 		}
-		Cni::Version(_) => unreachable!(),
 	}
+
+	Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Pool {
+	name: String,
+	#[serde(alias = "requested-ip")]
+	requested_ip: Option<IpAddr>,
+}
+
+#[test]
+fn test_parse_pools() {
+	let pools = parse_pools(&serde_json::json!([
+		{"name": "a", "requested_ip": "10.0.0.2"},
+		{"name": "b", "requested-ip": "10.0.0.3"},
+		{"name": "c"},
+	]))
+	.unwrap();
+	assert_eq!(pools[0].requested_ip, Some("10.0.0.2".parse().unwrap()));
+	assert_eq!(pools[1].requested_ip, Some("10.0.0.3".parse().unwrap()));
+	assert_eq!(pools[2].requested_ip, None);
+
+	assert!(matches!(
+		parse_pools(&serde_json::json!([
+			{"name": "a"},
+			{"name": "b", "requestedIp": "10.0.0.3"},
+		])),
+		Err(CniError::InvalidFieldPath { path, .. }) if path == "ipam.pools[1]"
+	));
+}
+
+#[test]
+fn test_check_prev() {
+	let pools = vec![Pool {
+		name: "pool".into(),
+		requested_ip: Some("10.0.0.2".parse().unwrap()),
+	}];
+	let mut prev_result: IpamSuccessReply = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"ips": [{"address": "10.0.0.2/24"}],
+	}))
+	.unwrap();
+	assert!(check_prev(&pools, &prev_result).is_ok());
+
+	prev_result.ips[0].address = "10.0.0.3/24".parse().unwrap();
+	assert!(matches!(
+		check_prev(&pools, &prev_result),
+		Err(CniError::InvalidField {
+			field: "prevResult.ips",
+			..
+		})
+	));
+
+	prev_result.specific.insert(
+		"pools".into(),
+		serde_json::json!([{ "name": "other", "requested_ip": null }]),
+	);
+	assert!(matches!(
+		check_prev(&pools, &prev_result),
+		Err(CniError::InvalidField {
+			field: "prevResult.pools",
+			..
+		})
+	));
+}
+
+#[test]
+fn test_check_runtime_ips() {
+	let ips: Vec<IpNetwork> = vec![
+		"10.0.0.2/24".parse().unwrap(),
+		"fd00::2/64".parse().unwrap(),
+	];
+	assert!(check_runtime_ips(&ips, &[]).is_ok());
+
+	let subnets: Vec<IpNetwork> =
+		vec!["10.0.0.0/24".parse().unwrap(), "fd00::/64".parse().unwrap()];
+	assert!(check_runtime_ips(&ips, &subnets).is_ok());
+
+	assert!(matches!(
+		check_runtime_ips(&ips, &subnets[..1]),
+		Err(CniError::InvalidFieldPath { path, .. }) if path == "runtimeConfig.ips[1]"
+	));
 }
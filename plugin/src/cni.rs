@@ -1,21 +1,26 @@
 use std::{
-	env,
+	env, fs,
 	io::{stdin, Read},
-	path::PathBuf,
+	path::{Component, Path, PathBuf},
+	process::exit,
 	str::FromStr,
+	sync::atomic::{AtomicU64, Ordering},
 };
 
 use log::{debug, error};
 use regex::Regex;
 use semver::Version;
+use serde_json::Value;
 
 use crate::{
 	command::Command,
-	config::NetworkConfig,
+	config::{NetworkConfig, NetworkConfigList},
 	error::{CniError, EmptyValueError, RegexValueError},
 	path::CniPath,
 	reply::reply,
-	version::VersionPayload,
+	reply::VersionReply,
+	version::{deserialize_version_list, supported_versions, VersionPayload},
+	CniVersion,
 };
 
 /// The main entrypoint to this plugin and the enum which contains plugin input.
@@ -63,7 +68,10 @@ pub enum Cni {
 		container_id: String,
 
 		/// The name of the interface inside the container.
-		ifname: String,
+		///
+		/// Some runtimes do not provide this during teardown, so it may not be
+		/// provided for DEL commands.
+		ifname: Option<String>,
 
 		/// The container’s “isolation domain” or namespace path.
 		///
@@ -105,7 +113,7 @@ pub enum Cni {
 	///
 	/// Note that when using [`Cni::load()`], this command is already handled,
 	/// and you should mark this [`unreachable!()`].
-	Version(Version),
+	Version(CniVersion),
 }
 
 impl Cni {
@@ -126,11 +134,62 @@ impl Cni {
 	/// In general you should prefer [`Cni::load()`].
 	///
 	/// [args-deprecation]: https://github.com/containernetworking/cni/blob/master/CONVENTIONS.md#cni_args
+	///
+	/// STDIN is read up to [`max_input_size()`][Cni::max_input_size()], and
+	/// larger inputs are rejected with a [`CniError::Generic`].
+	///
+	/// If the plugin is run with a `--config <path>` argument, the input is
+	/// read from that file instead of STDIN. This is mostly useful for testing,
+	/// and the environment variables are still required. A `--config` without
+	/// a path is a [`CniError::MissingField`].
 	pub fn from_env() -> Result<Self, CniError> {
+		let mut payload = Vec::with_capacity(1024);
+		read_input(&mut payload)?;
+		Self::from_env_and_payload(payload, &default_id_regex())
+	}
+
+	/// Reads the plugin inputs from the environment and STDIN, with a custom
+	/// container ID validation.
+	///
+	/// This does the same thing as [`Cni::from_env()`] but checks the
+	/// `CNI_CONTAINERID` against the given regex instead of the one
+	/// recommended by the spec, which is `^[a-z0-9][a-z0-9_.\-]*$`.
+	///
+	/// This is useful when the runtime uses container IDs which don't conform
+	/// to the spec's recommendation, e.g. with uppercase letters or colons.
+	pub fn from_env_with_id_regex(id_regex: &Regex) -> Result<Self, CniError> {
+		let mut payload = Vec::with_capacity(1024);
+		read_input(&mut payload)?;
+		Self::from_env_and_payload(payload, id_regex)
+	}
+
+	/// Reads the plugin inputs from the environment and STDIN, asynchronously.
+	///
+	/// This does the same thing as [`Cni::from_env()`] but reads STDIN via the
+	/// async runtime instead of blocking the current thread. Environment
+	/// variables are still read synchronously, as that doesn't block.
+	///
+	/// This method is only available when either of the **with-smol** (for
+	/// smol and async-std async runtimes) or **with-tokio** features are
+	/// enabled.
+	#[cfg(any(feature = "with-smol", feature = "with-tokio"))]
+	pub async fn from_env_async() -> Result<Self, CniError> {
+		let payload = if config_flag(env::args())?.is_some() {
+			let mut payload = Vec::with_capacity(1024);
+			read_input(&mut payload)?;
+			payload
+		} else {
+			debug!("reading stdin til EOF, asynchronously...");
+			read_stdin_async().await?
+		};
+		Self::from_env_and_payload(payload, &default_id_regex())
+	}
+
+	fn from_env_and_payload(payload: Vec<u8>, id_regex: &Regex) -> Result<Self, CniError> {
 		fn require_env<T>(var: &'static str) -> Result<T, CniError>
 		where
 			T: FromStr,
-			T::Err: std::error::Error + 'static,
+			T::Err: std::error::Error + Send + Sync + 'static,
 		{
 			env::var(var)
 				.map_err(|err| CniError::MissingEnv { var, err })
@@ -145,7 +204,7 @@ impl Cni {
 		fn load_env<T>(var: &'static str) -> Result<Option<T>, CniError>
 		where
 			T: FromStr,
-			T::Err: std::error::Error + 'static,
+			T::Err: std::error::Error + Send + Sync + 'static,
 		{
 			require_env(var).map(Some).or_else(|err| {
 				if let CniError::MissingEnv { .. } = err {
@@ -159,15 +218,12 @@ impl Cni {
 		let path: CniPath = load_env("CNI_PATH")?.unwrap_or_default();
 		let path = path.0;
 
-		let mut payload = Vec::with_capacity(1024);
-		debug!("reading stdin til EOF...");
-		stdin().read_to_end(&mut payload)?;
 		debug!("read payload bytes={}", payload.len());
 		if payload.is_empty() {
 			return Err(CniError::MissingInput);
 		}
 
-		fn check_container_id(id: &str) -> Result<(), CniError> {
+		fn check_container_id(id: &str, re: &Regex) -> Result<(), CniError> {
 			if id.is_empty() {
 				return Err(CniError::InvalidEnv {
 					var: "CNI_CONTAINERID",
@@ -175,11 +231,10 @@ impl Cni {
 				});
 			}
 
-			let re = Regex::new(r"^[a-z0-9][a-z0-9_.\-]*$").unwrap();
 			if !re.is_match(id) {
 				return Err(CniError::InvalidEnv {
 					var: "CNI_CONTAINERID",
-					err: Box::new(RegexValueError(re)),
+					err: Box::new(RegexValueError(re.clone())),
 				});
 			}
 
@@ -189,7 +244,7 @@ impl Cni {
 		match require_env("CNI_COMMAND")? {
 			Command::Add => {
 				let container_id: String = require_env("CNI_CONTAINERID")?;
-				check_container_id(&container_id)?;
+				check_container_id(&container_id, id_regex)?;
 
 				let config: NetworkConfig = serde_json::from_slice(&payload)?;
 				Self::check_version(&config.cni_version)?;
@@ -204,14 +259,14 @@ impl Cni {
 			}
 			Command::Del => {
 				let container_id: String = require_env("CNI_CONTAINERID")?;
-				check_container_id(&container_id)?;
+				check_container_id(&container_id, id_regex)?;
 
 				let config: NetworkConfig = serde_json::from_slice(&payload)?;
 				Self::check_version(&config.cni_version)?;
 
 				Ok(Self::Del {
 					container_id,
-					ifname: require_env("CNI_IFNAME")?,
+					ifname: load_env("CNI_IFNAME")?,
 					netns: load_env("CNI_NETNS")?,
 					path,
 					config,
@@ -219,7 +274,7 @@ impl Cni {
 			}
 			Command::Check => {
 				let container_id: String = require_env("CNI_CONTAINERID")?;
-				check_container_id(&container_id)?;
+				check_container_id(&container_id, id_regex)?;
 
 				let config: NetworkConfig = serde_json::from_slice(&payload)?;
 				Self::check_version(&config.cni_version)?;
@@ -247,16 +302,59 @@ impl Cni {
 	///
 	/// This version also logs a debug message with the name and version of this
 	/// library crate.
+	///
+	/// With the **schema** feature, if the plugin is run with a `--schema`
+	/// argument, this prints the JSON schema of [`NetworkConfig`] and exits
+	/// before reading anything. See [`emit_schema()`][crate::describe::emit_schema].
+	///
+	/// If the plugin is run with a `--version` or `-V` argument, this prints the
+	/// name of the binary and the version of this library crate, and exits
+	/// before reading anything. This is for humans, and is distinct from the
+	/// `VERSION` command. Use [`Cni::load_with_version()`] to print the
+	/// plugin's own name and version instead.
 	pub fn load() -> Self {
+		Self::load_inner(None)
+	}
+
+	/// Reads the plugin inputs like [`Cni::load()`], with the plugin's name and
+	/// version for the `--version` flag.
+	///
+	/// This does the same thing as [`Cni::load()`], except that when run with
+	/// a `--version` or `-V` argument, it prints `name version` and exits. As
+	/// `env!()` expands where it's written, call this from the plugin binary:
+	///
+	/// ```no_run
+	/// use cni_plugin::Cni;
+	///
+	/// let cni = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+	/// ```
+	pub fn load_with_version(name: &str, version: &str) -> Self {
+		Self::load_inner(Some((name, version)))
+	}
+
+	fn load_inner(plugin: Option<(&str, &str)>) -> Self {
 		debug!(
 			"CNI plugin built with {} crate version {}",
 			env!("CARGO_PKG_NAME"),
 			env!("CARGO_PKG_VERSION")
 		);
 
-		let cni_version = Version::parse("1.0.0").unwrap();
+		if let Some(line) = version_flag(env::args(), plugin) {
+			println!("{}", line);
+			exit(0);
+		}
+
+		#[cfg(feature = "schema")]
+		if env::args().skip(1).any(|arg| arg == "--schema") {
+			crate::describe::emit_schema::<NetworkConfig>();
+		}
 
-		match Self::from_env() {
+		let mut payload = Vec::with_capacity(1024);
+		let read = read_input(&mut payload);
+		let cni_version = version_hint(&payload);
+		let res = read.and_then(|_| Self::from_env_and_payload(payload, &default_id_regex()));
+
+		match res {
 			Err(e) => {
 				error!("{}", e);
 				reply(e.into_reply(cni_version))
@@ -266,6 +364,470 @@ impl Cni {
 		}
 	}
 
-	// TODO: parse network config (administrator) files
-	// maybe also with something that searches in common locations
+	/// The default maximum size of the input read from STDIN: 4 MiB.
+	pub const DEFAULT_MAX_INPUT_SIZE: u64 = 4 * 1024 * 1024;
+
+	/// Sets the maximum size of the input read from STDIN, in bytes.
+	///
+	/// This applies to [`Cni::load()`] and the `Cni::from_env*()` methods, and
+	/// should be called before them. The default is
+	/// [`DEFAULT_MAX_INPUT_SIZE`][Cni::DEFAULT_MAX_INPUT_SIZE], which is plenty for CNI configs; this guards
+	/// against a broken runtime sending an endless input.
+	pub fn set_max_input_size(bytes: u64) {
+		MAX_INPUT_SIZE.store(bytes, Ordering::Relaxed);
+	}
+
+	/// The maximum size of the input read from STDIN, in bytes.
+	pub fn max_input_size() -> u64 {
+		MAX_INPUT_SIZE.load(Ordering::Relaxed)
+	}
+
+	/// Reads a network configuration (administrator) file, in `.conf` format.
+	///
+	/// If `path` is a bare name, it is looked up in `/etc/cni/net.d`, and if it
+	/// has no extension, `.conf` is appended.
+	///
+	/// Note that the administrator format is not quite the same as the input
+	/// given to plugins, so this will accept files which would be invalid as
+	/// plugin input (with `capabilities`, for example), and include their
+	/// extra fields in [`specific`][NetworkConfig::specific].
+	pub fn load_conf_file(path: impl AsRef<Path>) -> Result<NetworkConfig, CniError> {
+		let path = conf_path(path.as_ref(), "conf");
+		debug!("reading network config from {:?}", path);
+		Ok(serde_json::from_slice(&fs::read(path)?)?)
+	}
+
+	/// Reads a network configuration list (administrator) file, in `.conflist` format.
+	///
+	/// If `path` is a bare name, it is looked up in `/etc/cni/net.d`, and if it
+	/// has no extension, `.conflist` is appended.
+	///
+	/// The file is an object with a `plugins` array, which is expanded into a
+	/// [`NetworkConfig`] per plugin, in order. All other top-level fields (like
+	/// `cniVersion` and `name`) are merged into each plugin config, unless the
+	/// plugin config defines them itself.
+	///
+	/// If the list has a `cniVersions` array, as introduced in spec 1.1, the
+	/// highest version in it which this library supports is negotiated with
+	/// [`VersionReply::negotiate()`], and used as the `cniVersion` instead. The
+	/// offered versions are kept in [`NetworkConfigList::cni_versions`].
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::Incompatible`] if none of the `cniVersions` are
+	/// supported, with the highest of them.
+	pub fn load_conflist_file(path: impl AsRef<Path>) -> Result<NetworkConfigList, CniError> {
+		let path = conf_path(path.as_ref(), "conflist");
+		debug!("reading network config list from {:?}", path);
+		parse_conflist(&fs::read(path)?)
+	}
+}
+
+static MAX_INPUT_SIZE: AtomicU64 = AtomicU64::new(Cni::DEFAULT_MAX_INPUT_SIZE);
+
+/// Reads the input from the `--config` file if given, or from STDIN.
+fn read_input(payload: &mut Vec<u8>) -> Result<(), CniError> {
+	if let Some(path) = config_flag(env::args())? {
+		debug!("reading input from {:?}...", path);
+		read_limited(fs::File::open(path)?, payload)
+	} else {
+		debug!("reading stdin til EOF...");
+		read_limited(stdin(), payload)
+	}
+}
+
+/// Reads the input to the end, failing if it's larger than the maximum size.
+fn read_limited(reader: impl Read, payload: &mut Vec<u8>) -> Result<(), CniError> {
+	let max = Cni::max_input_size();
+	// one more byte than the max so we can tell if there was more
+	reader.take(max.saturating_add(1)).read_to_end(payload)?;
+	check_input_size(payload, max)
+}
+
+fn check_input_size(payload: &[u8], max: u64) -> Result<(), CniError> {
+	if payload.len() as u64 > max {
+		Err(CniError::Generic(format!(
+			"input too large: over {} bytes",
+			max
+		)))
+	} else {
+		Ok(())
+	}
+}
+
+/// The default directory for network configuration (administrator) files.
+const NETCONF_DIR: &str = "/etc/cni/net.d";
+
+fn conf_path(path: &Path, extension: &str) -> PathBuf {
+	let mut components = path.components();
+	let bare = matches!(
+		(components.next(), components.next()),
+		(Some(Component::Normal(_)), None)
+	);
+
+	if bare {
+		let mut path = Path::new(NETCONF_DIR).join(path);
+		if path.extension().is_none() {
+			path.set_extension(extension);
+		}
+		path
+	} else {
+		path.to_owned()
+	}
+}
+
+fn parse_conflist(payload: &[u8]) -> Result<NetworkConfigList, CniError> {
+	let mut list: serde_json::Map<String, Value> = serde_json::from_slice(payload)?;
+	let plugins = match list.remove("plugins") {
+		Some(Value::Array(plugins)) => plugins,
+		Some(value) => {
+			return Err(CniError::InvalidField {
+				field: "plugins",
+				expected: "array",
+				value,
+			})
+		}
+		None => return Err(CniError::MissingField("plugins")),
+	};
+
+	let cni_versions = match list.remove("cniVersions") {
+		Some(value) => {
+			deserialize_version_list(value.clone()).map_err(|_| CniError::InvalidField {
+				field: "cniVersions",
+				expected: "array of versions",
+				value,
+			})?
+		}
+		None => Vec::new(),
+	};
+
+	let cni_version = if let Some(highest) = cni_versions.iter().max() {
+		let ours = VersionReply {
+			cni_version: highest.clone().into(),
+			supported_versions: supported_versions(),
+			supported_commands: Vec::new(),
+		};
+		let version = ours
+			.negotiate(&cni_versions)
+			.ok_or_else(|| CniError::Incompatible(highest.clone()))?;
+		list.insert("cniVersion".into(), Value::String(version.to_string()));
+		version.into()
+	} else {
+		let value = list
+			.get("cniVersion")
+			.cloned()
+			.ok_or(CniError::MissingField("cniVersion"))?;
+		serde_json::from_value(value.clone()).map_err(|_| CniError::InvalidField {
+			field: "cniVersion",
+			expected: "version",
+			value,
+		})?
+	};
+
+	let plugins = plugins
+		.into_iter()
+		.map(|plugin| {
+			let mut plugin = match plugin {
+				Value::Object(plugin) => plugin,
+				value => {
+					return Err(CniError::InvalidField {
+						field: "plugins[]",
+						expected: "object",
+						value,
+					})
+				}
+			};
+
+			for (key, value) in &list {
+				plugin.entry(key.as_str()).or_insert_with(|| value.clone());
+			}
+
+			Ok(serde_json::from_value(Value::Object(plugin))?)
+		})
+		.collect::<Result<_, CniError>>()?;
+
+	Ok(NetworkConfigList {
+		cni_version,
+		cni_versions,
+		plugins,
+	})
+}
+
+/// Extracts the `cniVersion` from a payload, even if it's otherwise malformed.
+///
+/// This is used for error replies, which should use the runtime's version when
+/// possible, and falls back to 1.0.0 if there's nothing usable.
+fn version_hint(payload: &[u8]) -> CniVersion {
+	// UNWRAP: static regex which is known to be valid
+	let re = regex::bytes::Regex::new(r#""cniVersion"\s*:\s*"([^"]*)""#).unwrap();
+	re.captures(payload)
+		.and_then(|caps| CniVersion::parse(std::str::from_utf8(&caps[1]).ok()?).ok())
+		.unwrap_or_else(|| Version::new(1, 0, 0).into())
+}
+
+fn default_id_regex() -> Regex {
+	// UNWRAP: static regex which is known to be valid
+	Regex::new(r"^[a-z0-9][a-z0-9_.\-]*$").unwrap()
+}
+
+#[cfg(feature = "with-smol")]
+async fn read_stdin_async() -> Result<Vec<u8>, CniError> {
+	use blocking::Unblock;
+	use futures::io::AsyncReadExt;
+
+	let max = Cni::max_input_size();
+	let mut payload = Vec::with_capacity(1024);
+	Unblock::new(stdin())
+		.take(max.saturating_add(1))
+		.read_to_end(&mut payload)
+		.await?;
+	check_input_size(&payload, max)?;
+	Ok(payload)
+}
+
+#[cfg(feature = "with-tokio")]
+async fn read_stdin_async() -> Result<Vec<u8>, CniError> {
+	use tokio::io::AsyncReadExt;
+
+	let max = Cni::max_input_size();
+	let mut payload = Vec::with_capacity(1024);
+	tokio::io::stdin()
+		.take(max.saturating_add(1))
+		.read_to_end(&mut payload)
+		.await?;
+	check_input_size(&payload, max)?;
+	Ok(payload)
+}
+
+#[test]
+fn test_parse_conflist() {
+	let list = parse_conflist(
+		br#"{
+			"cniVersion": "1.0.0",
+			"name": "test",
+			"plugins": [
+				{"type": "bridge", "bridge": "cni0"},
+				{"type": "tuning", "name": "override"}
+			]
+		}"#,
+	)
+	.unwrap();
+	assert_eq!(list.cni_version, Version::new(1, 0, 0));
+	assert!(list.cni_versions.is_empty());
+
+	let configs = list.plugins;
+	assert_eq!(configs.len(), 2);
+	assert_eq!(configs[0].plugin, "bridge");
+	assert_eq!(configs[0].name, "test");
+	assert_eq!(configs[0].cni_version, Version::new(1, 0, 0));
+	assert_eq!(configs[0].specific["bridge"], "cni0");
+	assert_eq!(configs[1].plugin, "tuning");
+	assert_eq!(configs[1].name, "override");
+
+	assert!(parse_conflist(br#"{"cniVersion": "1.0.0", "name": "test"}"#).is_err());
+	assert!(matches!(
+		parse_conflist(br#"{"name": "test", "plugins": []}"#),
+		Err(CniError::MissingField("cniVersion"))
+	));
+}
+
+#[test]
+fn test_parse_conflist_versions() {
+	let list = parse_conflist(
+		br#"{
+			"cniVersion": "0.4.0",
+			"cniVersions": ["0.4.0", "1.1.0", "1.0.0"],
+			"name": "test",
+			"plugins": [
+				{"type": "bridge"},
+				{"type": "tuning", "cniVersion": "1.0.0"}
+			]
+		}"#,
+	)
+	.unwrap();
+
+	// 1.1.0 is offered but not supported
+	assert_eq!(list.cni_version, Version::new(1, 0, 0));
+	assert_eq!(
+		list.cni_versions,
+		vec![
+			Version::new(0, 4, 0),
+			Version::new(1, 1, 0),
+			Version::new(1, 0, 0)
+		]
+	);
+	assert_eq!(list.plugins[0].cni_version, Version::new(1, 0, 0));
+	assert!(!list.plugins[0].specific.contains_key("cniVersions"));
+	assert_eq!(list.plugins[1].cni_version, Version::new(1, 0, 0));
+
+	assert!(matches!(
+		parse_conflist(
+			br#"{"cniVersions": ["0.3.1", "1.1.0"], "name": "test", "plugins": []}"#
+		),
+		Err(CniError::Incompatible(version)) if version == Version::new(1, 1, 0)
+	));
+
+	assert!(matches!(
+		parse_conflist(br#"{"cniVersions": ["latest"], "name": "test", "plugins": []}"#),
+		Err(CniError::InvalidField {
+			field: "cniVersions",
+			..
+		})
+	));
+}
+
+#[test]
+fn test_conf_path() {
+	assert_eq!(
+		conf_path(Path::new("mynet"), "conflist"),
+		Path::new("/etc/cni/net.d/mynet.conflist")
+	);
+	assert_eq!(
+		conf_path(Path::new("mynet.conf"), "conflist"),
+		Path::new("/etc/cni/net.d/mynet.conf")
+	);
+	assert_eq!(
+		conf_path(Path::new("./mynet"), "conf"),
+		Path::new("./mynet")
+	);
+}
+
+/// If the arguments have a version flag, the line to print for it.
+///
+/// That's the plugin's name and version if given, and otherwise the name of
+/// the binary and the version of this library.
+fn version_flag(
+	mut args: impl Iterator<Item = String>,
+	plugin: Option<(&str, &str)>,
+) -> Option<String> {
+	let program = args.next().unwrap_or_default();
+	if !args.any(|arg| arg == "--version" || arg == "-V") {
+		return None;
+	}
+
+	if let Some((name, version)) = plugin {
+		return Some(format!("{} {}", name, version));
+	}
+
+	let program = Path::new(&program)
+		.file_name()
+		.map(|name| name.to_string_lossy().into_owned())
+		.unwrap_or(program);
+	Some(format!(
+		"{} ({} {})",
+		program,
+		env!("CARGO_PKG_NAME"),
+		env!("CARGO_PKG_VERSION")
+	))
+}
+
+/// Finds the path given with `--config`, erroring if it's missing or empty.
+fn config_flag(args: impl Iterator<Item = String>) -> Result<Option<PathBuf>, CniError> {
+	let mut args = args.skip(1);
+	while let Some(arg) = args.next() {
+		let path = if arg == "--config" {
+			args.next()
+		} else if let Some(path) = arg.strip_prefix("--config=") {
+			Some(path.to_owned())
+		} else {
+			continue;
+		};
+
+		return match path {
+			Some(path) if !path.is_empty() => Ok(Some(path.into())),
+			_ => Err(CniError::MissingField("--config")),
+		};
+	}
+
+	Ok(None)
+}
+
+#[test]
+fn test_version_hint() {
+	assert_eq!(
+		version_hint(br#"{"cniVersion": "0.4.0", "name": "test"}"#),
+		Version::new(0, 4, 0)
+	);
+	assert_eq!(
+		version_hint(br#"{"name": "test", "cniVersion":"0.4.0", "type": "#),
+		Version::new(0, 4, 0)
+	);
+	assert_eq!(version_hint(br#"{"name": "test"}"#), Version::new(1, 0, 0));
+	assert_eq!(
+		version_hint(br#"{"cniVersion": "latest"}"#),
+		Version::new(1, 0, 0)
+	);
+	assert_eq!(version_hint(b""), Version::new(1, 0, 0));
+	assert_eq!(version_hint(br#"{"cniVersion": "1.0"}"#).to_string(), "1.0");
+}
+
+#[test]
+fn test_version_flag() {
+	let args = |list: &[&str]| {
+		list.iter()
+			.map(|s| s.to_string())
+			.collect::<Vec<_>>()
+			.into_iter()
+	};
+	let plugin = Some(("portmap", "0.3.0"));
+	assert_eq!(version_flag(args(&["/opt/cni/bin/portmap"]), plugin), None);
+	assert_eq!(
+		version_flag(args(&["/opt/cni/bin/portmap", "-V"]), plugin),
+		Some("portmap 0.3.0".into())
+	);
+	assert_eq!(
+		version_flag(args(&["/opt/cni/bin/portmap", "-V"]), None),
+		Some(format!(
+			"portmap (cni-plugin {})",
+			env!("CARGO_PKG_VERSION")
+		))
+	);
+	assert!(version_flag(args(&["portmap", "--version"]), None).is_some());
+}
+
+#[test]
+fn test_read_limited() {
+	let mut payload = Vec::new();
+	assert!(read_limited(&br#"{"cniVersion": "1.0.0"}"#[..], &mut payload).is_ok());
+	assert_eq!(payload.len(), 23);
+
+	let big = vec![b' '; Cni::DEFAULT_MAX_INPUT_SIZE as usize + 1];
+	let mut payload = Vec::new();
+	assert!(matches!(
+		read_limited(&big[..], &mut payload),
+		Err(CniError::Generic(_))
+	));
+	assert_eq!(payload.len() as u64, Cni::DEFAULT_MAX_INPUT_SIZE + 1);
+	assert!(check_input_size(&big[1..], Cni::DEFAULT_MAX_INPUT_SIZE).is_ok());
+}
+
+#[test]
+fn test_config_flag() {
+	let args = |list: &[&str]| {
+		list.iter()
+			.map(|s| s.to_string())
+			.collect::<Vec<_>>()
+			.into_iter()
+	};
+	assert_eq!(config_flag(args(&["portmap"])).unwrap(), None);
+	assert_eq!(
+		config_flag(args(&["portmap", "--config", "net.json"])).unwrap(),
+		Some(PathBuf::from("net.json"))
+	);
+	assert_eq!(
+		config_flag(args(&["portmap", "--config=/tmp/net.json"])).unwrap(),
+		Some(PathBuf::from("/tmp/net.json"))
+	);
+	assert_eq!(config_flag(args(&["--config", "portmap"])).unwrap(), None);
+
+	for missing in &[
+		&["portmap", "--config"][..],
+		&["portmap", "--config="],
+		&["portmap", "--config", ""],
+	] {
+		assert!(matches!(
+			config_flag(args(missing)),
+			Err(CniError::MissingField("--config"))
+		));
+	}
 }
@@ -1,4 +1,6 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
 
 use crate::error::InvalidCommandError;
 
@@ -6,7 +8,11 @@ use crate::error::InvalidCommandError;
 ///
 /// For more information about the command semantics, see the spec or the
 /// [`Cni`][crate::Cni] enum documentation.
-#[derive(Clone, Copy, Debug)]
+///
+/// This is displayed and (de)serialised as the `CNI_COMMAND` strings, e.g.
+/// `"ADD"`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum Command {
 	/// The ADD command.
 	Add,
@@ -49,3 +55,21 @@ impl AsRef<str> for Command {
 		}
 	}
 }
+
+impl fmt::Display for Command {
+	/// Writes one of ADD, DEL, CHECK, or VERSION.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_ref())
+	}
+}
+
+#[test]
+fn test_command_strings() {
+	assert_eq!(Command::Check.to_string(), "CHECK");
+	assert_eq!(serde_json::to_string(&Command::Add).unwrap(), r#""ADD""#);
+	assert!(matches!(
+		serde_json::from_str::<Command>(r#""DEL""#).unwrap(),
+		Command::Del
+	));
+	assert!(serde_json::from_str::<Command>(r#""del""#).is_err());
+}
@@ -9,9 +9,9 @@ use semver::Version;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{ip_range::IpRange, macaddr::MacAddr};
+use crate::{ip_range::IpRange, macaddr::MacAddr, CniVersion};
 
-pub use crate::dns::Dns;
+pub use crate::dns::{Dns, DnsMergeMode};
 
 /// Top-level network configuration.
 ///
@@ -27,21 +27,22 @@ pub use crate::dns::Dns;
 /// In general, this structure will only ever be read or modified by a plugin,
 /// but all fields are public to allow construction if necessary.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct NetworkConfig {
 	/// Version of the CNI spec to which this configuration conforms.
 	///
 	/// This is a [Semantic Version 2.0](https://semver.org/) version number,
-	/// and that is enforced here by being a [`Version`], not a string.
+	/// and that is enforced here by being a [`CniVersion`], not a string. Its
+	/// spelling is kept, so replies built from it echo it back as given.
 	///
 	/// This version must be used when creating [replies][crate::reply], which
 	/// include a similar field. The spec does not currently cover the case
 	/// where an [`ErrorReply`][crate::reply::ErrorReply] must be created
 	/// _before_ the config is parsed, or in cases of unparseable config; this
 	/// is [under discussion](https://github.com/containernetworking/cni/issues/827).
-	#[serde(deserialize_with = "crate::version::deserialize_version")]
-	#[serde(serialize_with = "crate::version::serialize_version")]
-	pub cni_version: Version,
+	#[cfg_attr(feature = "schema", schemars(with = "String"))]
+	pub cni_version: CniVersion,
 
 	/// Name of the network configuration.
 	///
@@ -101,7 +102,8 @@ pub struct NetworkConfig {
 	///
 	/// Note that this section is sourced from the administrative configuration.
 	/// There is another field for runtime-provided DNS settings when supported,
-	/// see [`RuntimeConfig`].
+	/// see [`RuntimeConfig`], and [`effective_dns()`](Self::effective_dns) to
+	/// pick between them.
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub dns: Option<Dns>,
 
@@ -154,11 +156,50 @@ pub struct NetworkConfig {
 	pub specific: HashMap<String, Value>,
 }
 
+impl NetworkConfig {
+	/// The DNS settings to configure, from the runtime or the administrator.
+	///
+	/// Per convention, runtime configuration overrides administrative
+	/// configuration, so this is the [runtime `dns`](RuntimeConfig::dns) if
+	/// it's set, and otherwise the [administrative `dns`](Self::dns). The two
+	/// are not merged; use [`Dns::merge`] for that.
+	pub fn effective_dns(&self) -> Option<Dns> {
+		self.runtime
+			.as_ref()
+			.and_then(|rt| rt.dns.clone())
+			.or_else(|| self.dns.clone())
+	}
+}
+
+/// A network configuration list, as read from a `.conflist` file.
+///
+/// See [`Cni::load_conflist_file()`][crate::Cni::load_conflist_file()].
+#[derive(Clone, Debug)]
+pub struct NetworkConfigList {
+	/// Version of the CNI spec used for the list.
+	///
+	/// If the list offers [several versions](Self::cni_versions), this is the
+	/// one negotiated with this plugin, otherwise it is the list's `cniVersion`.
+	pub cni_version: CniVersion,
+
+	/// Versions of the CNI spec the list offers, from spec 1.1.
+	///
+	/// This is empty if the list doesn't have a `cniVersions` array.
+	pub cni_versions: Vec<Version>,
+
+	/// Configuration for each plugin in the list, in order.
+	///
+	/// The list's top-level fields are merged into each, unless the plugin's
+	/// config defines them itself.
+	pub plugins: Vec<NetworkConfig>,
+}
+
 /// IP Address Management configuration.
 ///
 /// IPAM plugins will be invoked with the full [`NetworkConfig`] as input, but
 /// should take their configuration from this section only.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct IpamConfig {
 	/// Name of the IPAM plugin binary on disk.
@@ -191,6 +232,7 @@ pub struct IpamConfig {
 ///
 /// [CONVENTIONS.md]: https://github.com/containernetworking/cni/blob/master/CONVENTIONS.md
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 #[non_exhaustive]
 pub struct RuntimeConfig {
@@ -221,10 +263,12 @@ pub struct RuntimeConfig {
 
 	/// List of static IPs to use for IPAM.
 	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	#[cfg_attr(feature = "schema", schemars(with = "Vec<String>"))]
 	pub ips: Vec<IpNetwork>,
 
 	/// MAC address to use for the interface.
 	#[serde(default, skip_serializing_if = "Option::is_none")]
+	#[cfg_attr(feature = "schema", schemars(with = "Option<String>"))]
 	pub mac: Option<MacAddr>,
 
 	/// List of names mapped to the IPs assigned to this interface.
@@ -244,6 +288,29 @@ pub struct RuntimeConfig {
 	pub specific: HashMap<String, Value>,
 }
 
+impl RuntimeConfig {
+	/// The [`port_mappings`](Self::port_mappings), grouped by protocol.
+	///
+	/// Mappings without a `protocol` are listed under both TCP and UDP. Use
+	/// the `port_mappings` directly to handle them differently. The order of
+	/// the mappings is kept within each protocol.
+	pub fn port_mappings_by_protocol(&self) -> HashMap<PortProtocol, Vec<&PortMapping>> {
+		let mut by_protocol: HashMap<PortProtocol, Vec<&PortMapping>> = HashMap::new();
+		for mapping in &self.port_mappings {
+			let protocols: &[PortProtocol] = match &mapping.protocol {
+				Some(protocol) => std::slice::from_ref(protocol),
+				None => &[PortProtocol::Tcp, PortProtocol::Udp],
+			};
+
+			for protocol in protocols {
+				by_protocol.entry(*protocol).or_default().push(mapping);
+			}
+		}
+
+		by_protocol
+	}
+}
+
 /// Port mapping entry.
 ///
 /// This defines a single mapping (forwarding) of a port from the host to the
@@ -251,6 +318,7 @@ pub struct RuntimeConfig {
 ///
 /// It is up to the implementation what to do if the `protocol` is left `None`.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct PortMapping {
 	/// Port on the host.
@@ -267,7 +335,8 @@ pub struct PortMapping {
 /// Protocol for a port.
 ///
 /// This is non-exhaustive as more protocols may be added.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "snake_case")]
 #[non_exhaustive]
 pub enum PortProtocol {
@@ -280,6 +349,7 @@ pub enum PortProtocol {
 
 /// Bandwidth limits to set on the interface.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct BandwidthLimits {
 	/// Rate limit for incoming traffic in bits per second.
@@ -298,3 +368,60 @@ pub struct BandwidthLimits {
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub egress_burst: Option<usize>,
 }
+
+#[test]
+fn test_effective_dns() {
+	let mut config: NetworkConfig = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"name": "test",
+		"type": "test",
+		"dns": { "nameservers": ["10.0.0.1"] },
+		"runtimeConfig": { "dns": { "nameservers": ["10.0.0.2"], "search": ["svc"] } },
+	}))
+	.unwrap();
+
+	let dns = config.effective_dns().unwrap();
+	assert_eq!(
+		dns.nameservers,
+		vec!["10.0.0.2".parse::<std::net::IpAddr>().unwrap()]
+	);
+	assert_eq!(dns.search, vec!["svc".to_string()]);
+
+	config.runtime.as_mut().unwrap().dns = None;
+	let dns = config.effective_dns().unwrap();
+	assert_eq!(
+		dns.nameservers,
+		vec!["10.0.0.1".parse::<std::net::IpAddr>().unwrap()]
+	);
+
+	config.dns = None;
+	assert!(config.effective_dns().is_none());
+}
+
+#[test]
+fn test_port_mappings_by_protocol() {
+	let runtime: RuntimeConfig = serde_json::from_value(serde_json::json!({
+		"portMappings": [
+			{ "hostPort": 8080, "containerPort": 80, "protocol": "tcp" },
+			{ "hostPort": 5353, "containerPort": 53 },
+			{ "hostPort": 5000, "containerPort": 5000, "protocol": "udp" },
+		],
+	}))
+	.unwrap();
+
+	let by_protocol = runtime.port_mappings_by_protocol();
+	assert_eq!(by_protocol.len(), 2);
+
+	let ports = |protocol| -> Vec<u16> {
+		by_protocol[&protocol]
+			.iter()
+			.map(|mapping| mapping.host_port)
+			.collect()
+	};
+	assert_eq!(ports(PortProtocol::Tcp), vec![8080, 5353]);
+	assert_eq!(ports(PortProtocol::Udp), vec![5353, 5000]);
+
+	assert!(RuntimeConfig::default()
+		.port_mappings_by_protocol()
+		.is_empty());
+}
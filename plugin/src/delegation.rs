@@ -5,16 +5,25 @@
 //! are provided, the crate won't compile.
 
 use std::{
-	env,
-	io::Cursor,
-	path::Path,
+	collections::{BTreeMap, HashMap},
+	env, fs,
+	future::Future,
+	path::{Path, PathBuf},
 	process::{ExitStatus, Stdio},
+	time::{Duration, Instant},
 };
 
 use log::{debug, error, info};
+use semver::Version;
 use which::which_in;
 
-use crate::{config::NetworkConfig, error::CniError, reply::ReplyPayload, Command};
+use crate::{
+	config::NetworkConfig,
+	error::{CniError, EmptyValueError},
+	reply::{AnyReply, ErrorReply, ReplyPayload},
+	version::VersionPayload,
+	Command,
+};
 
 /// Run a plugin as delegate.
 ///
@@ -32,7 +41,7 @@ use crate::{config::NetworkConfig, error::CniError, reply::ReplyPayload, Command
 ///
 /// This method errors if:
 /// - the current directory can’t be obtained
-/// - the `CNI_PATH` variable is missing
+/// - the `CNI_PATH` variable is missing or empty
 /// - the `CNI_PATH` doesn't contain the `sub_plugin` (as per `PATH` logic)
 /// - the delegate plugin errors
 ///
@@ -42,38 +51,133 @@ pub async fn delegate<S>(
 	command: Command,
 	config: &NetworkConfig,
 ) -> Result<S, CniError>
+where
+	S: for<'de> ReplyPayload<'de>,
+{
+	delegate_timed(sub_plugin, command, config)
+		.await
+		.map(|(reply, _)| reply)
+}
+
+/// Run a plugin as delegate, and return how long it took.
+///
+/// This does the same thing as [`delegate()`], and also returns the wall-clock
+/// duration of the delegate plugin's run for the given command. That excludes
+/// the cleanup DEL which is run if an ADD fails.
+pub async fn delegate_timed<S>(
+	sub_plugin: &str,
+	command: Command,
+	config: &NetworkConfig,
+) -> Result<(S, Duration), CniError>
+where
+	S: for<'de> ReplyPayload<'de>,
+{
+	delegate_inner(sub_plugin, command, config, &HashMap::new()).await
+}
+
+/// Run a plugin as delegate, with extra environment variables.
+///
+/// This does the same thing as [`delegate()`], but the `envs` are set in the
+/// delegate's environment on top of what it inherits from this process. This
+/// can be used to present a different `CNI_IFNAME` to the delegate, or to pass
+/// it plugin-specific variables. `CNI_COMMAND` is always set from `command`.
+pub async fn delegate_with_env<S>(
+	sub_plugin: &str,
+	command: Command,
+	config: &NetworkConfig,
+	envs: &HashMap<String, String>,
+) -> Result<S, CniError>
+where
+	S: for<'de> ReplyPayload<'de>,
+{
+	delegate_inner(sub_plugin, command, config, envs)
+		.await
+		.map(|(reply, _)| reply)
+}
+
+async fn delegate_inner<S>(
+	sub_plugin: &str,
+	command: Command,
+	config: &NetworkConfig,
+	envs: &HashMap<String, String>,
+) -> Result<(S, Duration), CniError>
 where
 	S: for<'de> ReplyPayload<'de>,
 {
 	let cwd = env::current_dir().map_err(|_| CniError::NoCwd)?;
-	let plugin = which_in(
-		sub_plugin,
-		Some(env::var("CNI_PATH").map_err(|err| CniError::MissingEnv {
-			var: "CNI_PATH",
+	let plugin =
+		which_in(sub_plugin, Some(cni_path()?), cwd).map_err(|err| CniError::MissingPlugin {
+			name: sub_plugin.into(),
 			err,
-		})?),
-		cwd,
+		})?;
+
+	let plugin = &plugin;
+	run_delegate(
+		sub_plugin,
+		command,
+		config,
+		move |command, stdin| async move { delegate_command(plugin, command, &stdin, envs).await },
 	)
-	.map_err(|err| CniError::MissingPlugin {
-		name: sub_plugin.into(),
-		err,
-	})?;
+	.await
+}
 
+/// Run a delegate through a custom runner, for testing.
+///
+/// This does the same thing as [`delegate()`], except that instead of finding
+/// and spawning the `sub_plugin`, the `runner` is called with the command and
+/// the bytes which would be written to the delegate's STDIN, and returns its
+/// exit status and STDOUT. The `sub_plugin` is only used in errors.
+///
+/// This lets the handling of a delegate's output be tested without an
+/// executable on disk, by returning canned outputs: success replies, error
+/// replies, empty or invalid output, and so on. As with [`delegate()`], the
+/// runner is called again with [`Command::Del`] if an ADD fails.
+pub async fn delegate_with_runner<S, R, F>(
+	sub_plugin: &str,
+	command: Command,
+	config: &NetworkConfig,
+	runner: R,
+) -> Result<S, CniError>
+where
+	S: for<'de> ReplyPayload<'de>,
+	R: Fn(Command, Vec<u8>) -> F,
+	F: Future<Output = Result<(ExitStatus, Vec<u8>), CniError>>,
+{
+	run_delegate(sub_plugin, command, config, runner)
+		.await
+		.map(|(reply, _)| reply)
+}
+
+async fn run_delegate<S, R, F>(
+	sub_plugin: &str,
+	command: Command,
+	config: &NetworkConfig,
+	runner: R,
+) -> Result<(S, Duration), CniError>
+where
+	S: for<'de> ReplyPayload<'de>,
+	R: Fn(Command, Vec<u8>) -> F,
+	F: Future<Output = Result<(ExitStatus, Vec<u8>), CniError>>,
+{
 	let config_bytes = serde_json::to_vec(config).map_err(|err| CniError::Delegated {
 		plugin: sub_plugin.into(),
 		err: Box::new(err.into()),
 	})?;
 
-	match delegate_command(&plugin, command, &config_bytes).await {
+	let pre = Instant::now();
+	let output = runner(command, config_bytes.clone()).await;
+	let elapsed = pre.elapsed();
+
+	match output {
 		Ok((status, stdout)) => {
 			if stdout.is_empty() {
 				if matches!(command, Command::Add) {
-					delegate_command(&plugin, Command::Del, &config_bytes)
-						.await
-						.map_err(|err| CniError::Delegated {
+					runner(Command::Del, config_bytes).await.map_err(|err| {
+						CniError::Delegated {
 							plugin: sub_plugin.into(),
 							err: Box::new(err),
-						})?;
+						}
+					})?;
 				}
 
 				return Err(CniError::Delegated {
@@ -83,26 +187,47 @@ where
 			}
 
 			if status.success() {
-				let reader = Cursor::new(stdout);
-				Ok(
-					serde_json::from_reader(reader).map_err(|err| CniError::Delegated {
-						plugin: sub_plugin.into(),
-						err: Box::new(err.into()),
-					})?,
-				)
+				match parse_success(config, &stdout) {
+					Ok(reply) => Ok((reply, elapsed)),
+					Err(err) => {
+						// the delegate succeeded, so it may have allocated something
+						if matches!(command, Command::Add) {
+							runner(Command::Del, config_bytes).await.map_err(|err| {
+								CniError::Delegated {
+									plugin: sub_plugin.into(),
+									err: Box::new(err),
+								}
+							})?;
+						}
+
+						Err(CniError::Delegated {
+							plugin: sub_plugin.into(),
+							err: Box::new(err),
+						})
+					}
+				}
 			} else {
 				if matches!(command, Command::Add) {
-					delegate_command(&plugin, Command::Del, &config_bytes)
-						.await
-						.map_err(|err| CniError::Delegated {
+					runner(Command::Del, config_bytes).await.map_err(|err| {
+						CniError::Delegated {
 							plugin: sub_plugin.into(),
 							err: Box::new(err),
-						})?;
+						}
+					})?;
 				}
 
+				let err = match serde_json::from_slice::<ErrorReply>(&stdout) {
+					Ok(reply) => CniError::Plugin {
+						code: reply.code,
+						msg: reply.msg.into(),
+						details: reply.details,
+					},
+					Err(_) => CniError::Generic(String::from_utf8_lossy(&stdout).into()),
+				};
+
 				Err(CniError::Delegated {
 					plugin: sub_plugin.into(),
-					err: Box::new(CniError::Generic(String::from_utf8_lossy(&stdout).into())),
+					err: Box::new(err),
 				})
 			}
 		}
@@ -111,9 +236,7 @@ where
 			if matches!(command, Command::Add) {
 				// We're already failing pretty badly so this is a Just In Case, but
 				// in all likelihood won't work either. So we ignore any failure.
-				delegate_command(&plugin, Command::Del, &config_bytes)
-					.await
-					.ok();
+				runner(Command::Del, config_bytes).await.ok();
 			}
 
 			Err(CniError::Delegated {
@@ -124,11 +247,137 @@ where
 	}
 }
 
+/// Run a plugin as delegate, without knowing its reply type ahead.
+///
+/// This does the same thing as [`delegate()`], but the reply can be either a
+/// [`SuccessReply`][crate::reply::SuccessReply] or an
+/// [`IpamSuccessReply`][crate::reply::IpamSuccessReply]; see [`AnyReply`] for
+/// how they're told apart.
+pub async fn delegate_any(
+	sub_plugin: &str,
+	command: Command,
+	config: &NetworkConfig,
+) -> Result<AnyReply, CniError> {
+	delegate(sub_plugin, command, config).await
+}
+
+/// List the plugins which can be delegated to.
+///
+/// This enumerates the executable files in the `CNI_PATH` directories, for
+/// diagnostics or to check what's available before delegating. Each name is
+/// listed once, with the path of its first occurrence, as for `PATH` lookups.
+/// The list is sorted by name.
+///
+/// Directories which don't exist or can't be read are skipped.
+///
+/// # Errors
+///
+/// This errors if the `CNI_PATH` variable is missing or empty.
+pub fn list_plugins() -> Result<Vec<(String, PathBuf)>, CniError> {
+	Ok(plugins_in(&cni_path()?))
+}
+
+fn plugins_in(path: &str) -> Vec<(String, PathBuf)> {
+	let mut plugins = BTreeMap::new();
+	for dir in env::split_paths(path) {
+		if dir.as_os_str().is_empty() {
+			continue;
+		}
+
+		let entries = match fs::read_dir(&dir) {
+			Ok(entries) => entries,
+			Err(err) => {
+				debug!("skipping CNI_PATH entry {}: {}", dir.display(), err);
+				continue;
+			}
+		};
+
+		for entry in entries.filter_map(Result::ok) {
+			let path = entry.path();
+			if !is_executable(&path) {
+				continue;
+			}
+
+			let name = path
+				.file_name()
+				.and_then(|name| name.to_str())
+				.map(String::from);
+			if let Some(name) = name {
+				plugins.entry(name).or_insert(path);
+			}
+		}
+	}
+
+	plugins.into_iter().collect()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+	use std::os::unix::fs::PermissionsExt;
+	fs::metadata(path)
+		.map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+		.unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+	path.is_file()
+}
+
+/// Reads `CNI_PATH`, which must be set and have at least one non-empty entry.
+///
+/// Without this, an empty `CNI_PATH` would make every delegate plugin missing,
+/// which is confusing when the runtime is at fault.
+fn cni_path() -> Result<String, CniError> {
+	let path = env::var("CNI_PATH").map_err(|err| CniError::MissingEnv {
+		var: "CNI_PATH",
+		err,
+	})?;
+
+	check_cni_path(path)
+}
+
+fn check_cni_path(path: String) -> Result<String, CniError> {
+	if env::split_paths(&path).all(|dir| dir.to_string_lossy().trim().is_empty()) {
+		Err(CniError::InvalidEnv {
+			var: "CNI_PATH",
+			err: Box::new(EmptyValueError),
+		})
+	} else {
+		Ok(path)
+	}
+}
+
+/// Parses a delegate's success reply, checking its version against the config.
+fn parse_success<S>(config: &NetworkConfig, stdout: &[u8]) -> Result<S, CniError>
+where
+	S: for<'de> ReplyPayload<'de>,
+{
+	let VersionPayload { cni_version } = serde_json::from_slice(stdout)?;
+	if !versions_compatible(&config.cni_version, &cni_version) {
+		error!(
+			"delegate replied with cni version {} to a {} config",
+			cni_version, config.cni_version
+		);
+		return Err(CniError::Incompatible(cni_version.into()));
+	}
+
+	Ok(serde_json::from_slice(stdout)?)
+}
+
+/// Whether a reply in version `reply` can be used for a config in `config`.
+///
+/// That's semver compatibility: same major, and same minor for major zero.
+fn versions_compatible(config: &Version, reply: &Version) -> bool {
+	config.major == reply.major && (config.major != 0 || config.minor == reply.minor)
+}
+
 #[cfg(feature = "with-smol")]
 async fn delegate_command(
 	plugin: impl AsRef<Path>,
 	command: impl AsRef<str>,
 	stdin_bytes: &[u8],
+	envs: &HashMap<String, String>,
 ) -> Result<(ExitStatus, Vec<u8>), CniError> {
 	use async_process::Command;
 	use futures::io::{copy, AsyncWriteExt, Cursor};
@@ -142,12 +391,16 @@ async fn delegate_command(
 		command
 	);
 
+	let pre = Instant::now();
 	debug!("spawing child process, async=smol");
 	let mut child = Command::new(plugin)
+		.envs(envs)
 		.env("CNI_COMMAND", command)
 		.stdin(Stdio::piped())
 		.stdout(Stdio::piped())
 		.stderr(Stdio::inherit())
+		// if the caller gives up on the delegate (e.g. on a timeout), so do we
+		.kill_on_drop(true)
 		.spawn()?;
 
 	{
@@ -169,11 +422,13 @@ async fn delegate_command(
 	debug!("awaiting child");
 	let output = child.output().await?;
 
+	let elapsed = pre.elapsed();
 	info!(
-		"delegate plugin at {} for command={} has returned with {}; stdout bytes={}",
+		"delegate plugin at {} for command={} has returned with {} in {:?}; stdout bytes={}",
 		plugin.display(),
 		command,
 		output.status,
+		elapsed,
 		output.stdout.len()
 	);
 	Ok((output.status, output.stdout))
@@ -184,6 +439,7 @@ async fn delegate_command(
 	plugin: impl AsRef<Path>,
 	command: impl AsRef<str>,
 	mut stdin_bytes: &[u8],
+	envs: &HashMap<String, String>,
 ) -> Result<(ExitStatus, Vec<u8>), CniError> {
 	use tokio::io::copy_buf;
 	use tokio::process::Command;
@@ -197,12 +453,16 @@ async fn delegate_command(
 		command
 	);
 
+	let pre = Instant::now();
 	debug!("spawing child process, async=tokio");
 	let mut child = Command::new(plugin)
+		.envs(envs)
 		.env("CNI_COMMAND", command)
 		.stdin(Stdio::piped())
 		.stdout(Stdio::piped())
 		.stderr(Stdio::inherit())
+		// if the caller gives up on the delegate (e.g. on a timeout), so do we
+		.kill_on_drop(true)
 		.spawn()?;
 
 	{
@@ -221,12 +481,154 @@ async fn delegate_command(
 	debug!("awaiting child");
 	let output = child.wait_with_output().await?;
 
+	let elapsed = pre.elapsed();
 	info!(
-		"delegate plugin at {} for command={} has returned with {}; stdout bytes={}",
+		"delegate plugin at {} for command={} has returned with {} in {:?}; stdout bytes={}",
 		plugin.display(),
 		command,
 		output.status,
+		elapsed,
 		output.stdout.len()
 	);
 	Ok((output.status, output.stdout))
 }
+
+#[test]
+fn test_versions_compatible() {
+	let v = |s| Version::parse(s).unwrap();
+	assert!(versions_compatible(&v("1.0.0"), &v("1.0.0")));
+	assert!(versions_compatible(&v("1.0.0"), &v("1.1.0")));
+	assert!(versions_compatible(&v("0.4.0"), &v("0.4.1")));
+	assert!(!versions_compatible(&v("1.0.0"), &v("0.4.0")));
+	assert!(!versions_compatible(&v("0.4.0"), &v("0.3.1")));
+}
+
+#[test]
+fn test_check_cni_path() {
+	assert!(check_cni_path("/opt/cni/bin".into()).is_ok());
+	assert!(check_cni_path(":/opt/cni/bin".into()).is_ok());
+	for empty in &["", " ", ":", " : "] {
+		assert!(matches!(
+			check_cni_path((*empty).into()),
+			Err(CniError::InvalidEnv {
+				var: "CNI_PATH",
+				..
+			})
+		));
+	}
+}
+
+#[cfg(unix)]
+#[test]
+fn test_plugins_in() {
+	use std::os::unix::fs::PermissionsExt;
+
+	let dir = env::temp_dir().join(format!("cni-plugin-delegation-{}", std::process::id()));
+	let (first, second) = (dir.join("first"), dir.join("second"));
+	fs::create_dir_all(&first).unwrap();
+	fs::create_dir_all(&second).unwrap();
+
+	let file = |path: PathBuf, mode| {
+		fs::write(&path, "").unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+	};
+	file(first.join("bridge"), 0o755);
+	file(first.join("README"), 0o644);
+	file(second.join("bridge"), 0o755);
+	file(second.join("portmap"), 0o755);
+
+	let path = env::join_paths([&first, &dir.join("missing"), &second])
+		.unwrap()
+		.into_string()
+		.unwrap();
+	assert_eq!(
+		plugins_in(&path),
+		vec![
+			("bridge".to_string(), first.join("bridge")),
+			("portmap".to_string(), second.join("portmap")),
+		]
+	);
+
+	fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(unix, feature = "with-smol"))]
+#[test]
+fn test_delegate_with_runner() {
+	use futures::executor::block_on;
+	use std::{os::unix::process::ExitStatusExt, sync::Mutex};
+
+	use crate::reply::IpamSuccessReply;
+
+	let config: NetworkConfig = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"name": "test",
+		"type": "test",
+	}))
+	.unwrap();
+
+	let delegate = |code: i32, stdout: &'static str| {
+		let calls = Mutex::new(Vec::new());
+		let res = block_on(delegate_with_runner::<IpamSuccessReply, _, _>(
+			"test-ipam",
+			Command::Add,
+			&config,
+			|command, _| {
+				calls.lock().unwrap().push(command.to_string());
+				let stdout = if matches!(command, Command::Add) {
+					stdout
+				} else {
+					""
+				};
+				async move { Ok((ExitStatus::from_raw(code << 8), stdout.into())) }
+			},
+		));
+		(res, calls.into_inner().unwrap())
+	};
+
+	let (res, calls) = delegate(
+		0,
+		r#"{"cniVersion":"1.0.0","ips":[{"address":"10.0.0.5/24"}]}"#,
+	);
+	assert_eq!(res.unwrap().ips.len(), 1);
+	assert_eq!(calls, vec!["ADD"]);
+
+	// failed ADDs are cleaned up with a DEL
+	let (res, calls) = delegate(0, "");
+	assert!(matches!(
+		res,
+		Err(CniError::Delegated { err, .. }) if matches!(*err, CniError::MissingOutput)
+	));
+	assert_eq!(calls, vec!["ADD", "DEL"]);
+
+	let (res, calls) = delegate(0, "not json");
+	assert!(matches!(
+		res,
+		Err(CniError::Delegated { err, .. }) if matches!(*err, CniError::Json(_))
+	));
+	assert_eq!(calls, vec!["ADD", "DEL"]);
+
+	let (res, calls) = delegate(0, r#"{"cniVersion":"1.0.0","ips":"nope"}"#);
+	assert!(matches!(
+		res,
+		Err(CniError::Delegated { err, .. }) if matches!(*err, CniError::Json(_))
+	));
+	assert_eq!(calls, vec!["ADD", "DEL"]);
+
+	let (res, calls) = delegate(
+		7,
+		r#"{"cniVersion":"1.0.0","code":7,"msg":"no pool","details":"nope"}"#,
+	);
+	assert!(matches!(
+		res,
+		Err(CniError::Delegated { err, .. }) if matches!(*err, CniError::Plugin { code: 7, .. })
+	));
+	assert_eq!(calls, vec!["ADD", "DEL"]);
+
+	let (res, calls) = delegate(0, r#"{"cniVersion":"0.4.0","ips":[]}"#);
+	assert!(matches!(
+		res,
+		Err(CniError::Delegated { err, .. }) if matches!(*err, CniError::Incompatible(_))
+	));
+	assert_eq!(calls, vec!["ADD", "DEL"]);
+}
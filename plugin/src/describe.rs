@@ -0,0 +1,30 @@
+//! Helpers for plugins to describe themselves.
+//!
+//! This is only available with the **schema** feature.
+
+use std::process::exit;
+
+use schemars::{schema_for, JsonSchema};
+
+/// Prints the JSON schema of a configuration type to STDOUT and exits.
+///
+/// [`Cni::load()`][crate::Cni::load] calls this with
+/// [`NetworkConfig`][crate::config::NetworkConfig] when the plugin is run with
+/// a `--schema` argument. Plugins with their own configuration type can check
+/// for that argument and call this themselves before loading.
+///
+/// # Example
+///
+/// ```no_run
+/// use cni_plugin::{config::NetworkConfig, describe::emit_schema};
+/// if std::env::args().any(|arg| arg == "--schema") {
+///     emit_schema::<NetworkConfig>();
+/// }
+/// ```
+pub fn emit_schema<T: JsonSchema>() -> ! {
+	let schema = schema_for!(T);
+
+	// UNWRAP: schemas are always serialisable
+	println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+	exit(0)
+}
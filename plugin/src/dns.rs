@@ -10,6 +10,7 @@ use serde::{Deserialize, Serialize};
 ///
 /// All fields are optional ([`Vec`]s will default to empty).
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Dns {
 	/// List of DNS nameservers this network is aware of.
@@ -35,3 +36,144 @@ pub struct Dns {
 	#[serde(default, skip_serializing_if = "Vec::is_empty")]
 	pub options: Vec<String>,
 }
+
+/// How to merge two [`Dns`] sections, see [`Dns::merge`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DnsMergeMode {
+	/// Fields set in the other section replace those in this one.
+	Override,
+
+	/// Lists are appended to, skipping duplicates, and the domain is kept if
+	/// set, or taken from the other section otherwise.
+	Append,
+
+	/// Fields set in this section are kept, and only empty fields are taken
+	/// from the other section.
+	PreferExisting,
+}
+
+impl Dns {
+	/// Merges another DNS section into this one.
+	///
+	/// This is useful when a plugin computes its own DNS settings but also has
+	/// some from the `prevResult`, as the spec doesn't say how to combine them.
+	///
+	/// Fields are considered set if they're `Some` or non-empty, so an empty
+	/// list never overrides, and a field empty on both sides stays empty.
+	pub fn merge(&mut self, other: Dns, mode: DnsMergeMode) {
+		match mode {
+			DnsMergeMode::Override => {
+				if !other.nameservers.is_empty() {
+					self.nameservers = other.nameservers;
+				}
+				if other.domain.is_some() {
+					self.domain = other.domain;
+				}
+				if !other.search.is_empty() {
+					self.search = other.search;
+				}
+				if !other.options.is_empty() {
+					self.options = other.options;
+				}
+			}
+			DnsMergeMode::Append => {
+				append_dedup(&mut self.nameservers, other.nameservers);
+				if self.domain.is_none() {
+					self.domain = other.domain;
+				}
+				append_dedup(&mut self.search, other.search);
+				append_dedup(&mut self.options, other.options);
+			}
+			DnsMergeMode::PreferExisting => {
+				if self.nameservers.is_empty() {
+					self.nameservers = other.nameservers;
+				}
+				if self.domain.is_none() {
+					self.domain = other.domain;
+				}
+				if self.search.is_empty() {
+					self.search = other.search;
+				}
+				if self.options.is_empty() {
+					self.options = other.options;
+				}
+			}
+		}
+	}
+}
+
+fn append_dedup<T: PartialEq>(list: &mut Vec<T>, other: Vec<T>) {
+	for item in other {
+		if !list.contains(&item) {
+			list.push(item);
+		}
+	}
+}
+
+#[cfg(test)]
+fn dns(nameservers: &[&str], domain: Option<&str>, search: &[&str]) -> Dns {
+	Dns {
+		nameservers: nameservers.iter().map(|ns| ns.parse().unwrap()).collect(),
+		domain: domain.map(ToOwned::to_owned),
+		search: search.iter().map(|s| (*s).to_owned()).collect(),
+		options: Vec::new(),
+	}
+}
+
+#[test]
+fn test_merge_override() {
+	let mut base = dns(&["10.0.0.1"], Some("a.local"), &["a.local"]);
+	base.merge(dns(&["10.0.0.2"], None, &[]), DnsMergeMode::Override);
+	assert_eq!(base.nameservers, dns(&["10.0.0.2"], None, &[]).nameservers);
+	assert_eq!(base.domain.as_deref(), Some("a.local"));
+	assert_eq!(base.search, vec!["a.local"]);
+
+	let mut empty = Dns::default();
+	empty.merge(
+		dns(&["10.0.0.2"], Some("b.local"), &["b.local"]),
+		DnsMergeMode::Override,
+	);
+	assert_eq!(empty.domain.as_deref(), Some("b.local"));
+	assert_eq!(empty.search, vec!["b.local"]);
+}
+
+#[test]
+fn test_merge_append() {
+	let mut base = dns(&["10.0.0.1", "10.0.0.2"], None, &["a.local"]);
+	base.merge(
+		dns(
+			&["10.0.0.2", "10.0.0.3"],
+			Some("b.local"),
+			&["b.local", "a.local"],
+		),
+		DnsMergeMode::Append,
+	);
+	assert_eq!(
+		base.nameservers,
+		dns(&["10.0.0.1", "10.0.0.2", "10.0.0.3"], None, &[]).nameservers
+	);
+	assert_eq!(base.domain.as_deref(), Some("b.local"));
+	assert_eq!(base.search, vec!["a.local", "b.local"]);
+
+	let mut base = dns(&["10.0.0.1"], Some("a.local"), &[]);
+	base.merge(Dns::default(), DnsMergeMode::Append);
+	assert_eq!(base.nameservers.len(), 1);
+	assert_eq!(base.domain.as_deref(), Some("a.local"));
+	assert!(base.search.is_empty());
+}
+
+#[test]
+fn test_merge_prefer_existing() {
+	let mut base = dns(&["10.0.0.1"], None, &[]);
+	base.merge(
+		dns(&["10.0.0.2"], Some("b.local"), &["b.local"]),
+		DnsMergeMode::PreferExisting,
+	);
+	assert_eq!(base.nameservers, dns(&["10.0.0.1"], None, &[]).nameservers);
+	assert_eq!(base.domain.as_deref(), Some("b.local"));
+	assert_eq!(base.search, vec!["b.local"]);
+
+	let mut empty = Dns::default();
+	empty.merge(Dns::default(), DnsMergeMode::PreferExisting);
+	assert_eq!(serde_json::to_string(&empty).unwrap(), "{}");
+}
@@ -1,13 +1,13 @@
 //! When CNI goes bad.
 
-use std::env::VarError;
+use std::{env::VarError, time::Duration};
 
 use regex::Regex;
 use semver::Version;
 use serde_json::Value;
 use thiserror::Error;
 
-use crate::reply::ErrorReply;
+use crate::{reply::ErrorReply, CniVersion};
 
 /// All errors emitted by this library, plus a few others.
 #[derive(Debug, Error)]
@@ -55,7 +55,7 @@ pub enum CniError {
 
 		/// the underlying error
 		#[source]
-		err: Box<dyn std::error::Error>,
+		err: Box<dyn std::error::Error + Send + Sync>,
 	},
 
 	/// When the current working directory cannot be obtained (for delegation).
@@ -74,6 +74,8 @@ pub enum CniError {
 	},
 
 	/// Wrapper for errors in relation to a delegated plugin.
+	///
+	/// This replies with the code of the underlying error.
 	#[error("with plugin {plugin}: {err}")]
 	Delegated {
 		/// the name of the plugin binary
@@ -83,6 +85,83 @@ pub enum CniError {
 		err: Box<Self>,
 	},
 
+	/// Errors from several delegated plugins, like when undoing a failure.
+	///
+	/// This replies with the first code which isn't a catch-all (like the 5 of
+	/// I/O errors or the 100 of [`Generic`](Self::Generic)), and with the code
+	/// of each error in the details.
+	#[error("{}", join_delegated(.0))]
+	DelegatedMany(Vec<(String, CniError)>),
+
+	/// When a (delegated) plugin replies with an error.
+	///
+	/// This keeps the code of its [`ErrorReply`], and replies with it.
+	#[error("{msg}: {details} (code {code})")]
+	Plugin {
+		/// the code of the error reply
+		code: i32,
+
+		/// the short message of the error reply
+		msg: String,
+
+		/// the longer message of the error reply
+		details: String,
+	},
+
+	/// When an operation, like a delegated plugin or a chain of them, doesn't
+	/// complete within its deadline.
+	///
+	/// The [`Duration`] is the deadline which was exceeded.
+	#[error("timed out after {0:?}")]
+	Timeout(Duration),
+
+	/// When a remote resource (e.g. over HTTP) cannot be obtained or used.
+	///
+	/// This error variant is not used in the library, but is provided for
+	/// plugin implementations which talk to a backend (a KV store, an
+	/// orchestrator API...) to make use of without needing to make their own
+	/// error type.
+	///
+	/// With the **surf** feature, errors from [surf](https://docs.rs/surf) convert to this variant,
+	/// with `http` as the remote and `request` as the resource.
+	///
+	/// # Example
+	///
+	/// ```
+	/// # use cni_plugin::error::CniError;
+	/// CniError::Remote {
+	///     remote: "consul".into(),
+	///     resource: "pool".into(),
+	///     source: "connection refused".into(),
+	/// };
+	/// ```
+	#[error("{remote}::{resource}: {source}")]
+	Remote {
+		/// the name of the remote, e.g. the service name
+		remote: String,
+
+		/// the resource being accessed on the remote
+		resource: String,
+
+		/// the underlying error
+		source: Box<dyn std::error::Error + Send + Sync>,
+	},
+
+	/// When a network interface cannot be found by name.
+	///
+	/// This error variant is not used in the library, but is provided for
+	/// plugin implementations which act on interfaces created by an earlier
+	/// plugin in the chain, as the likely cause is that it hasn't run.
+	///
+	/// # Example
+	///
+	/// ```
+	/// # use cni_plugin::error::CniError;
+	/// CniError::MissingInterface("eth0".into());
+	/// ```
+	#[error("interface {0} not found, check that the plugin which creates it has run")]
+	MissingInterface(String),
+
 	/// A generic error as a string.
 	///
 	/// This error variant is not used in the library, but is provided for
@@ -111,7 +190,7 @@ pub enum CniError {
 	/// CniError::Debug(Box::new(("hello", "world", vec![1, 2, 3])));
 	/// ```
 	#[error("{0:?}")]
-	Debug(Box<dyn std::fmt::Debug>),
+	Debug(Box<dyn std::fmt::Debug + Send + Sync>),
 
 	/// When a field in configuration is missing.
 	///
@@ -156,6 +235,35 @@ pub enum CniError {
 		/// the actual value or a facsimile thereof
 		value: Value,
 	},
+
+	/// When a field in configuration is invalid, at a path built at runtime.
+	///
+	/// This is the same as [`InvalidField`](Self::InvalidField), but the path
+	/// can include array indices or map keys to point at the exact element.
+	///
+	/// # Example
+	///
+	/// ```
+	/// # use cni_plugin::error::CniError;
+	/// # use serde_json::Value;
+	/// let i = 2;
+	/// CniError::InvalidFieldPath {
+	///     path: format!("ipam.pools[{}].subnet", i),
+	///     expected: "CIDR string",
+	///     value: Value::Null,
+	/// };
+	/// ```
+	#[error("{path}: expected {expected}, got: {value:?}")]
+	InvalidFieldPath {
+		/// the path of the invalid field
+		path: String,
+
+		/// the value or type the field was expected to be
+		expected: &'static str,
+
+		/// the actual value or a facsimile thereof
+		value: Value,
+	},
 }
 
 impl CniError {
@@ -169,7 +277,8 @@ impl CniError {
 	///
 	/// It’s recommended to add an implementation of this if you make your own
 	/// error type.
-	pub fn into_reply(self, cni_version: Version) -> ErrorReply<'static> {
+	pub fn into_reply(self, cni_version: impl Into<CniVersion>) -> ErrorReply<'static> {
+		let cni_version = cni_version.into();
 		match self {
 			Self::Io(e) => ErrorReply {
 				cni_version,
@@ -225,10 +334,63 @@ impl CniError {
 				msg: "Missing plugin",
 				details: e.to_string(),
 			},
-			e @ Self::Delegated { .. } => ErrorReply {
+			Self::Delegated { plugin, err } => {
+				let inner = err.into_reply(cni_version);
+				ErrorReply {
+					details: format!("with plugin {}: {}", plugin, inner.details),
+					..inner
+				}
+			}
+			Self::DelegatedMany(errors) => {
+				let replies: Vec<(String, ErrorReply)> = errors
+					.into_iter()
+					.map(|(plugin, err)| (plugin, err.into_reply(cni_version.clone())))
+					.collect();
+				let code = replies
+					.iter()
+					.map(|(_, reply)| reply.code)
+					.find(|code| !CATCHALL_CODES.contains(code))
+					.or_else(|| replies.first().map(|(_, reply)| reply.code))
+					.unwrap_or(5);
+
+				ErrorReply {
+					cni_version,
+					code,
+					msg: "Delegated",
+					details: replies
+						.iter()
+						.map(|(plugin, reply)| {
+							format!(
+								"{}: {} (code {}): {}",
+								plugin, reply.msg, reply.code, reply.details
+							)
+						})
+						.collect::<Vec<_>>()
+						.join("\n"),
+				}
+			}
+			Self::Plugin { code, msg, details } => ErrorReply {
 				cni_version,
-				code: 5,
-				msg: "Delegated",
+				code,
+				msg: "Plugin error",
+				details: format!("{}: {}", msg, details),
+			},
+			e @ Self::Timeout(_) => ErrorReply {
+				cni_version,
+				code: 11,
+				msg: "Timed out, try again later",
+				details: e.to_string(),
+			},
+			e @ Self::Remote { .. } => ErrorReply {
+				cni_version,
+				code: 111,
+				msg: "Remote error",
+				details: e.to_string(),
+			},
+			e @ Self::MissingInterface(_) => ErrorReply {
+				cni_version,
+				code: 109,
+				msg: "Missing interface",
 				details: e.to_string(),
 			},
 			Self::Generic(s) => ErrorReply {
@@ -249,7 +411,7 @@ impl CniError {
 				msg: "Missing field",
 				details: e.to_string(),
 			},
-			e @ Self::InvalidField { .. } => ErrorReply {
+			e @ Self::InvalidField { .. } | e @ Self::InvalidFieldPath { .. } => ErrorReply {
 				cni_version,
 				code: 107,
 				msg: "Invalid field",
@@ -259,6 +421,28 @@ impl CniError {
 	}
 }
 
+#[cfg(feature = "surf")]
+impl From<surf::Error> for CniError {
+	fn from(err: surf::Error) -> Self {
+		Self::Remote {
+			remote: "http".into(),
+			resource: "request".into(),
+			source: err.into(),
+		}
+	}
+}
+
+/// Codes which say little about the error, see [`CniError::DelegatedMany`].
+const CATCHALL_CODES: &[i32] = &[5, 100, 101];
+
+fn join_delegated(errors: &[(String, CniError)]) -> String {
+	errors
+		.iter()
+		.map(|(plugin, err)| format!("{}: {}", plugin, err))
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
 /// Underlying error used for an empty value that shouldn’t be.
 ///
 /// Used with [`CniError::InvalidEnv`].
@@ -279,3 +463,59 @@ pub struct InvalidCommandError;
 #[derive(Clone, Debug, Error)]
 #[error("must match regex: {0}")]
 pub struct RegexValueError(pub Regex);
+
+#[test]
+fn test_error_is_send_sync() {
+	fn assert_send_sync<T: Send + Sync>() {}
+	assert_send_sync::<CniError>();
+}
+
+#[test]
+fn test_delegated_codes() {
+	let version = Version::new(1, 0, 0);
+	let plugin_error = || CniError::Plugin {
+		code: 122,
+		msg: "Pool is full".into(),
+		details: "default does not have any free IP space".into(),
+	};
+
+	let reply = CniError::Delegated {
+		plugin: "ipam-da-consul".into(),
+		err: Box::new(plugin_error()),
+	}
+	.into_reply(version.clone());
+	assert_eq!(reply.code, 122);
+
+	let reply = CniError::DelegatedMany(vec![
+		("ipam-ds-static".into(), CniError::Generic("oops".into())),
+		(
+			"ipam-da-consul".into(),
+			CniError::Delegated {
+				plugin: "ipam-da-consul".into(),
+				err: Box::new(plugin_error()),
+			},
+		),
+	])
+	.into_reply(version.clone());
+	assert_eq!(reply.code, 122);
+	assert_eq!(reply.details.lines().count(), 2);
+	assert!(reply
+		.details
+		.starts_with("ipam-ds-static: ERROR (code 100): oops"));
+
+	let reply = CniError::DelegatedMany(vec![(
+		"ipam-ds-static".into(),
+		CniError::Generic("oops".into()),
+	)])
+	.into_reply(version);
+	assert_eq!(reply.code, 100);
+}
+
+#[cfg(feature = "surf")]
+#[test]
+fn test_from_surf() {
+	let err = surf::Error::from_str(surf::StatusCode::BadGateway, "no backend");
+	let err = CniError::from(err);
+	assert_eq!(err.to_string(), "http::request: no backend");
+	assert_eq!(err.into_reply(semver::Version::new(1, 0, 0)).code, 111);
+}
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::{env, path::PathBuf};
+
+use log::warn;
 
 use crate::{config::NetworkConfig, Cni, Command};
 
@@ -22,7 +24,9 @@ pub struct Inputs {
 	pub container_id: String,
 
 	/// The name of the interface to create, delete, check, or manage inside the container.
-	pub ifname: String,
+	///
+	/// Optional for DEL.
+	pub ifname: Option<String>,
 
 	/// The container’s “isolation domain.”
 	///
@@ -41,6 +45,30 @@ pub struct Inputs {
 	pub config: NetworkConfig,
 }
 
+impl Inputs {
+	/// The container ID to act on, which can be overridden for testing.
+	///
+	/// This is the [`container_id`](Self::container_id), unless the
+	/// `CNI_TEST_ID` environment variable is set and not empty, in which case
+	/// that is returned instead. This lets plugins which look the container
+	/// up elsewhere (like in an orchestrator) be tested with tools such as
+	/// `cnitool`, which make up their own IDs.
+	///
+	/// A warning is logged when the override is used.
+	pub fn effective_container_id(&self) -> String {
+		match env::var("CNI_TEST_ID") {
+			Ok(id) if !id.is_empty() => {
+				warn!(
+					"using CNI_TEST_ID={} instead of container id {}",
+					id, self.container_id
+				);
+				id
+			}
+			_ => self.container_id.clone(),
+		}
+	}
+}
+
 impl Cni {
 	/// Converts this enum into an alternate representation which holds the Command separately from the inputs.
 	///
@@ -70,7 +98,7 @@ impl Cni {
 			} => Some(Inputs {
 				command,
 				container_id,
-				ifname,
+				ifname: Some(ifname),
 				netns: Some(netns),
 				path,
 				config,
@@ -93,3 +121,30 @@ impl Cni {
 		}
 	}
 }
+
+#[test]
+fn test_effective_container_id() {
+	let inputs = Inputs {
+		command: Command::Add,
+		container_id: "real".into(),
+		ifname: None,
+		netns: None,
+		path: Vec::new(),
+		config: serde_json::from_value(serde_json::json!({
+			"cniVersion": "1.0.0",
+			"name": "test",
+			"type": "test",
+		}))
+		.unwrap(),
+	};
+
+	env::remove_var("CNI_TEST_ID");
+	assert_eq!(inputs.effective_container_id(), "real");
+
+	env::set_var("CNI_TEST_ID", "");
+	assert_eq!(inputs.effective_container_id(), "real");
+
+	env::set_var("CNI_TEST_ID", "fake");
+	assert_eq!(inputs.effective_container_id(), "fake");
+	env::remove_var("CNI_TEST_ID");
+}
@@ -1,6 +1,9 @@
 //! The IpRange type and helpers for IP pools.
 
-use std::net::IpAddr;
+use std::{
+	collections::HashSet,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
 
 use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
@@ -11,17 +14,24 @@ use serde::{Deserialize, Serialize};
 /// The subnet is the only required field. The range can be further limited
 /// with the `range_start` and `range_end` fields, which are inclusive.
 ///
+/// The `gateway` and any `reserved` IPs are never available. For IPv4 subnets
+/// larger than `/31`, the network and broadcast addresses aren't available
+/// either, unless `include_network_broadcast` is set.
+///
 /// # Examples
 ///
 /// ```json
 /// {"subnet": "10.0.0.0/8"}
 /// {"subnet": "10.0.10.0/23", "rangeStart": "10.0.11.0", "rangeEnd": "10.0.11.254"}
 /// {"subnet": "192.168.1.1/24", "gateway": "192.168.1.254"}
+/// {"subnet": "192.168.1.1/24", "reserved": ["192.168.1.2", "192.168.1.3"]}
 /// ```
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct IpRange {
 	/// The subnet for the range.
+	#[cfg_attr(feature = "schema", schemars(with = "String"))]
 	pub subnet: IpNetwork,
 
 	/// The start of the available range within the subnet, inclusive.
@@ -37,14 +47,212 @@ pub struct IpRange {
 	/// Interpretation of an absent gateway is left to the implementation.
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub gateway: Option<IpAddr>,
+
+	/// IPs within the range which are never available, e.g. for infrastructure.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub reserved: Vec<IpAddr>,
+
+	/// Make the network and broadcast addresses of IPv4 subnets available.
+	///
+	/// Defaults to false. This has no effect on `/31`, `/32`, and IPv6 subnets.
+	#[serde(default, skip_serializing_if = "is_false")]
+	pub include_network_broadcast: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+	!b
 }
 
 impl IpRange {
+	/// Whether an IP is available in the range.
+	///
+	/// This is true for the same IPs as are yielded by [`iter_free`](Self::iter_free):
+	/// every IP in the subnet, except those lower than `range_start`, higher
+	/// than `range_end`, or which are [excluded](Self::excluded).
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		self.subnet.contains(ip)
+			&& !matches!(self.range_start, Some(start) if ip < start)
+			&& !matches!(self.range_end, Some(end) if ip > end)
+			&& !self.excluded().contains(&ip)
+	}
+
+	/// The IPs of the subnet which are never available, sorted.
+	///
+	/// That's the `gateway`, the `reserved` IPs, and for IPv4 subnets larger
+	/// than `/31` the network and broadcast addresses, unless
+	/// `include_network_broadcast` is set. IPs outside of the subnet are left
+	/// out, but those outside of `range_start` and `range_end` are not.
+	pub fn excluded(&self) -> Vec<IpAddr> {
+		let mut excluded: Vec<IpAddr> = self
+			.gateway
+			.iter()
+			.chain(self.reserved.iter())
+			.copied()
+			.filter(|ip| self.subnet.contains(*ip))
+			.collect();
+
+		if let IpNetwork::V4(net) = self.subnet {
+			if !self.include_network_broadcast && net.prefix() < 31 {
+				let (network, broadcast) = subnet_bounds(&self.subnet);
+				excluded.push(network);
+				excluded.push(broadcast);
+			}
+		}
+
+		excluded.sort_unstable();
+		excluded.dedup();
+		excluded
+	}
+
+	/// The number of IPs available in the range.
+	///
+	/// This is the number of IPs that [`iter_free`](Self::iter_free) yields,
+	/// computed without iterating. An IPv6 `/0` subnet is too large to count,
+	/// and saturates at [`u128::MAX`].
+	pub fn usable_count(&self) -> u128 {
+		let (low, high) = match self.usable_bounds() {
+			Some(bounds) => bounds,
+			None => return 0,
+		};
+
+		let count = (ip_to_u128(high) - ip_to_u128(low)).saturating_add(1);
+		let excluded = self
+			.excluded()
+			.into_iter()
+			.filter(|ip| low <= *ip && *ip <= high)
+			.count();
+		count - excluded as u128
+	}
+
+	/// The lowest IP available in the range.
+	///
+	/// That's the first IP [`iter_free`](Self::iter_free) yields: from
+	/// `range_start`, skipping [excluded](Self::excluded) IPs like the gateway.
+	/// This is `None` if no IP is available.
+	pub fn first_usable(&self) -> Option<IpAddr> {
+		self.iter_usable().next()
+	}
+
+	/// The highest IP available in the range.
+	///
+	/// Like [`first_usable`](Self::first_usable), but from `range_end` down.
+	pub fn last_usable(&self) -> Option<IpAddr> {
+		let (low, high) = self.usable_bounds()?;
+		let excluded = self.excluded();
+		(ip_to_u128(low)..=ip_to_u128(high))
+			.rev()
+			.map(|n| u128_to_ip(n, low))
+			.find(|ip| !excluded.contains(ip))
+	}
+
+	/// The gateway of the range, or a default if it isn't set.
+	///
+	/// The default is the first host in the subnet: the address after the
+	/// network address, except for IPv4 `/31` and `/32` and IPv6 `/127` and
+	/// `/128` subnets, which don't have a network address, so it's the first.
+	///
+	/// Note that the default is _not_ excluded from allocation, unlike an
+	/// explicit `gateway`. Use `range_start` to keep it free if needed.
+	pub fn gateway_or_first_host(&self) -> Option<IpAddr> {
+		if self.gateway.is_some() {
+			return self.gateway;
+		}
+
+		let (first, last) = subnet_bounds(&self.subnet);
+		let first = ip_to_u128(first);
+		let host = if ip_to_u128(last) - first > 1 {
+			first + 1
+		} else {
+			first
+		};
+		Some(u128_to_ip(host, self.subnet.ip()))
+	}
+
+	/// The ranges covering this range except for the IPs in `hole`.
+	///
+	/// The returned ranges keep the same subnet, gateway, and other fields, and
+	/// only differ in `range_start` and `range_end`. There can be none (if the
+	/// hole covers the whole range), one, or two (if the hole is in the middle).
+	///
+	/// For example, `10.0.0.0/24` minus `10.0.0.128/25` is `10.0.0.0/24` up to
+	/// `10.0.0.127`.
+	pub fn subtract(&self, hole: &IpNetwork) -> Vec<IpRange> {
+		let (low, high) = match self.usable_bounds() {
+			Some(bounds) => bounds,
+			None => return Vec::new(),
+		};
+
+		if low.is_ipv4() != hole.is_ipv4() {
+			return vec![self.clone()];
+		}
+
+		let (hole_low, hole_high) = subnet_bounds(hole);
+		if hole_high < low || high < hole_low {
+			return vec![self.clone()];
+		}
+
+		let mut ranges = Vec::with_capacity(2);
+		if low < hole_low {
+			ranges.push(IpRange {
+				range_end: Some(u128_to_ip(ip_to_u128(hole_low) - 1, low)),
+				..self.clone()
+			});
+		}
+		if hole_high < high {
+			ranges.push(IpRange {
+				range_start: Some(u128_to_ip(ip_to_u128(hole_high) + 1, low)),
+				..self.clone()
+			});
+		}
+
+		ranges
+	}
+
+	/// The lowest and highest IPs of the subnet within `range_start` and `range_end`.
+	fn usable_bounds(&self) -> Option<(IpAddr, IpAddr)> {
+		let (first, last) = subnet_bounds(&self.subnet);
+		let low = self.range_start.map_or(first, |start| start.max(first));
+		let high = self.range_end.map_or(last, |end| end.min(last));
+
+		if low > high {
+			None
+		} else {
+			Some((low, high))
+		}
+	}
+
+	/// Lazily iterate the IPs available in the range, starting from `range_start`.
+	fn iter_usable(&self) -> impl Iterator<Item = IpAddr> {
+		self.iter_usable_from(0)
+	}
+
+	/// Lazily iterate the IPs available in the range, skipping `offset` of them.
+	fn iter_usable_from(&self, offset: u128) -> impl Iterator<Item = IpAddr> {
+		let excluded = self.excluded();
+		self.usable_bounds()
+			.into_iter()
+			.flat_map(move |(low, high)| {
+				// skip over the excluded IPs which come before the offset, in order,
+				// so that the offset counts available IPs only
+				let mut start = ip_to_u128(low).saturating_add(offset);
+				for ex in excluded.iter().map(|ip| ip_to_u128(*ip)) {
+					if ip_to_u128(low) <= ex && ex <= start {
+						start = start.saturating_add(1);
+					}
+				}
+
+				let excluded = excluded.clone();
+				(start..=ip_to_u128(high))
+					.map(move |n| u128_to_ip(n, low))
+					.filter(move |ip| !excluded.contains(ip))
+			})
+	}
+
 	/// Naive implementation of iterating the IP range.
 	///
 	/// This iterator will yield every IP available in the range, that is, every
 	/// IP in the subnet, except those lower than `range_start`, higher than
-	/// `range_end`, or the one which is the `gateway`.
+	/// `range_end`, or which are [excluded](Self::excluded).
 	///
 	/// The current implementation iterates through the entire range and filters
 	/// off the excluded IPs as per above. For IPv4 this will likely never be an
@@ -54,7 +262,7 @@ impl IpRange {
 		let prefix = self.subnet.prefix();
 		let range_start = self.range_start;
 		let range_end = self.range_end;
-		let gateway = self.gateway;
+		let excluded = self.excluded();
 
 		self.subnet
 			.iter()
@@ -73,15 +281,601 @@ impl IpRange {
 					}
 				}
 
-				if let Some(ref gw) = gateway {
-					if ip == gw {
-						return false;
-					}
-				}
-
-				true
+				!excluded.contains(ip)
 			})
 			.map(move |ip| (IpNetwork::new(ip, prefix).unwrap(), self))
 		// UNWRAP: panics on invalid prefix, but we got it from another IpNetwork
 	}
 }
+
+/// A resumable position in an [`IpRange`], to allocate round-robin.
+///
+/// The cursor remembers the last IP it yielded, and the next allocation
+/// continues after it instead of from the start of the range, wrapping around
+/// after `range_end`. This avoids immediately reusing a just-released IP.
+///
+/// It's (de)serialised as `{"last": "10.0.0.5"}`, so that it can be stored
+/// between allocations, e.g. in Consul KV. A cursor with no `last`, or with a
+/// `last` outside the range, starts from the beginning of the range.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IpRangeCursor {
+	/// The last IP yielded.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub last: Option<IpAddr>,
+}
+
+impl IpRangeCursor {
+	/// A cursor at the start of the range.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The next available IP in the range which isn't `used`, after the last.
+	///
+	/// The IPs are tried in order from after the last one yielded, up to the
+	/// end of the range and then from its start, so each IP is tried at most
+	/// once. On success, the cursor moves to the returned IP.
+	///
+	/// Like [`IpPool::next_free`], this is lazy: only the IPs up to the one
+	/// returned are visited.
+	pub fn next_free(&mut self, range: &IpRange, used: &HashSet<IpAddr>) -> Option<IpNetwork> {
+		let (low, high) = range.usable_bounds()?;
+		let (low_n, high_n) = (ip_to_u128(low), ip_to_u128(high));
+		let start = match self.last {
+			Some(last) if last.is_ipv4() == low.is_ipv4() => {
+				let last = ip_to_u128(last);
+				if low_n <= last && last < high_n {
+					last + 1
+				} else {
+					// at the end or outside of the range: wrap around
+					low_n
+				}
+			}
+			_ => low_n,
+		};
+
+		let excluded = range.excluded();
+		let ip = (start..=high_n)
+			.chain(low_n..start)
+			.map(|n| u128_to_ip(n, low))
+			.find(|ip| !excluded.contains(ip) && !used.contains(ip))?;
+
+		self.last = Some(ip);
+		// UNWRAP: panics on invalid prefix, but we got it from another IpNetwork
+		Some(IpNetwork::new(ip, range.subnet.prefix()).unwrap())
+	}
+}
+
+/// How to pick the next IP to allocate from an [`IpPool`].
+///
+/// This is deserialised from and serialised to camelCase strings, e.g.
+/// `"leastRecentlyUsed"`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AllocationStrategy {
+	/// Allocate the lowest available IP, trying ranges in order.
+	///
+	/// This is the default. See [`IpPool::next_free`].
+	#[default]
+	Sequential,
+
+	/// Allocate a random available IP.
+	///
+	/// See [`IpPool::next_free_random`].
+	Random,
+
+	/// Allocate the available IP which has been released the longest ago.
+	///
+	/// Implementations need to keep track of releases to support this.
+	LeastRecentlyUsed,
+}
+
+/// A pool of IPs, made of one or more [`IpRange`]s.
+///
+/// This is deserialised from and serialised to a plain array of ranges.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct IpPool(pub Vec<IpRange>);
+
+impl IpPool {
+	/// Whether an IP is available in any of the ranges of the pool.
+	///
+	/// See [`IpRange::contains`].
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		self.0.iter().any(|range| range.contains(ip))
+	}
+
+	/// The number of IPs available across all ranges of the pool.
+	///
+	/// See [`IpRange::usable_count`]. Saturates at [`u128::MAX`].
+	pub fn usable_count(&self) -> u128 {
+		self.0
+			.iter()
+			.fold(0, |sum, range| sum.saturating_add(range.usable_count()))
+	}
+
+	/// The first available IP in the pool which isn't `used`, with its range.
+	///
+	/// Ranges are tried in order, and IPs within a range are tried from its
+	/// start upwards. This is lazy: only the IPs up to the one returned are
+	/// visited, so large IPv6 ranges are fine as long as they're not full.
+	pub fn next_free(&self, used: &HashSet<IpAddr>) -> Option<(IpNetwork, &IpRange)> {
+		self.0.iter().find_map(|range| {
+			range
+				.iter_usable()
+				.find(|ip| !used.contains(ip))
+				// UNWRAP: panics on invalid prefix, but we got it from another IpNetwork
+				.map(|ip| (IpNetwork::new(ip, range.subnet.prefix()).unwrap(), range))
+		})
+	}
+
+	/// A random available IP in the pool which isn't `used`, with its range.
+	///
+	/// An IP is picked uniformly at random across all ranges of the pool. If
+	/// it's used, the IPs after it are tried in order, wrapping around to the
+	/// start of the pool. Like [`next_free`](Self::next_free), this is lazy.
+	///
+	/// This is only available with the **random-alloc** feature.
+	#[cfg(feature = "random-alloc")]
+	pub fn next_free_random(&self, used: &HashSet<IpAddr>) -> Option<(IpNetwork, &IpRange)> {
+		use rand::{rngs::OsRng, Rng};
+
+		let total = self.usable_count();
+		if total == 0 {
+			return None;
+		}
+
+		let mut offset = OsRng.gen_range(0..total);
+		let mut first = 0;
+		for (i, range) in self.0.iter().enumerate() {
+			let count = range.usable_count();
+			if offset < count {
+				first = i;
+				break;
+			}
+			offset -= count;
+		}
+
+		let (before, after) = self.0.split_at(first);
+		let start = &after[0];
+		start
+			.iter_usable_from(offset)
+			.map(move |ip| (ip, start))
+			.chain(
+				after[1..]
+					.iter()
+					.chain(before)
+					.chain(Some(start))
+					.flat_map(|range| range.iter_usable().map(move |ip| (ip, range))),
+			)
+			.find(|(ip, _)| !used.contains(ip))
+			// UNWRAP: panics on invalid prefix, but we got it from another IpNetwork
+			.map(|(ip, range)| (IpNetwork::new(ip, range.subnet.prefix()).unwrap(), range))
+	}
+}
+
+fn subnet_bounds(subnet: &IpNetwork) -> (IpAddr, IpAddr) {
+	match subnet {
+		IpNetwork::V4(net) => {
+			let mask = u32::MAX.checked_shr(net.prefix().into()).unwrap_or(0);
+			let first = u32::from(net.ip()) & !mask;
+			(
+				Ipv4Addr::from(first).into(),
+				Ipv4Addr::from(first | mask).into(),
+			)
+		}
+		IpNetwork::V6(net) => {
+			let mask = u128::MAX.checked_shr(net.prefix().into()).unwrap_or(0);
+			let first = u128::from(net.ip()) & !mask;
+			(
+				Ipv6Addr::from(first).into(),
+				Ipv6Addr::from(first | mask).into(),
+			)
+		}
+	}
+}
+
+fn ip_to_u128(ip: IpAddr) -> u128 {
+	match ip {
+		IpAddr::V4(ip) => u32::from(ip).into(),
+		IpAddr::V6(ip) => u128::from(ip),
+	}
+}
+
+fn u128_to_ip(n: u128, family: IpAddr) -> IpAddr {
+	match family {
+		IpAddr::V4(_) => Ipv4Addr::from(n as u32).into(),
+		IpAddr::V6(_) => Ipv6Addr::from(n).into(),
+	}
+}
+
+#[test]
+fn test_contains_v4() {
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.0/23", "rangeStart": "10.0.11.0", "rangeEnd": "10.0.11.200", "gateway": "10.0.11.1"}"#,
+	)
+	.unwrap();
+
+	assert!(!range.contains("10.0.10.255".parse().unwrap()));
+	assert!(range.contains("10.0.11.0".parse().unwrap()));
+	assert!(!range.contains("10.0.11.1".parse().unwrap()));
+	assert!(range.contains("10.0.11.2".parse().unwrap()));
+	assert!(range.contains("10.0.11.200".parse().unwrap()));
+	assert!(!range.contains("10.0.11.201".parse().unwrap()));
+	assert!(!range.contains("10.0.12.0".parse().unwrap()));
+	assert!(!range.contains("fd00::1".parse().unwrap()));
+}
+
+#[test]
+fn test_contains_v6() {
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "fd00::/64", "rangeStart": "fd00::10", "rangeEnd": "fd00::20"}"#,
+	)
+	.unwrap();
+
+	assert!(!range.contains("fd00::f".parse().unwrap()));
+	assert!(range.contains("fd00::10".parse().unwrap()));
+	assert!(range.contains("fd00::20".parse().unwrap()));
+	assert!(!range.contains("fd00::21".parse().unwrap()));
+	assert!(!range.contains("fd01::15".parse().unwrap()));
+	assert!(!range.contains("10.0.0.1".parse().unwrap()));
+}
+
+#[test]
+fn test_usable_count_v4() {
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "192.168.1.0/24"}"#).unwrap();
+	assert_eq!(range.usable_count(), 254);
+	assert_eq!(range.usable_count(), range.iter_free().count() as u128);
+
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.0/23", "rangeStart": "10.0.11.0", "rangeEnd": "10.0.11.200", "gateway": "10.0.11.1"}"#,
+	)
+	.unwrap();
+	assert_eq!(range.usable_count(), 200);
+	assert_eq!(range.usable_count(), range.iter_free().count() as u128);
+
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.0/24", "rangeStart": "10.0.10.5", "rangeEnd": "10.0.10.5"}"#,
+	)
+	.unwrap();
+	assert_eq!(range.usable_count(), 1);
+
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.0/24", "rangeStart": "10.0.10.6", "rangeEnd": "10.0.10.5"}"#,
+	)
+	.unwrap();
+	assert_eq!(range.usable_count(), 0);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "0.0.0.0/0"}"#).unwrap();
+	assert_eq!(range.usable_count(), (1 << 32) - 2);
+
+	let range: IpRange =
+		serde_json::from_str(r#"{"subnet": "0.0.0.0/0", "includeNetworkBroadcast": true}"#)
+			.unwrap();
+	assert_eq!(range.usable_count(), 1 << 32);
+}
+
+#[test]
+fn test_usable_count_v6() {
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "fd00::/64", "rangeStart": "fd00::10", "rangeEnd": "fd00::20", "gateway": "fd00::1"}"#,
+	)
+	.unwrap();
+	assert_eq!(range.usable_count(), 17);
+
+	let range: IpRange =
+		serde_json::from_str(r#"{"subnet": "fd00::/120", "gateway": "fd00::1"}"#).unwrap();
+	assert_eq!(range.usable_count(), 255);
+	assert_eq!(range.usable_count(), range.iter_free().count() as u128);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "fd00::/64"}"#).unwrap();
+	assert_eq!(range.usable_count(), 1 << 64);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "::/0"}"#).unwrap();
+	assert_eq!(range.usable_count(), u128::MAX);
+}
+
+#[test]
+fn test_excluded_slash_30() {
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "10.0.10.4/30"}"#).unwrap();
+	assert_eq!(
+		range.iter_free().map(|(ip, _)| ip.ip()).collect::<Vec<_>>(),
+		vec![
+			"10.0.10.5".parse::<IpAddr>().unwrap(),
+			"10.0.10.6".parse().unwrap()
+		]
+	);
+	assert_eq!(range.usable_count(), 2);
+	assert!(!range.contains("10.0.10.4".parse().unwrap()));
+	assert!(!range.contains("10.0.10.7".parse().unwrap()));
+
+	let pool = IpPool(vec![range.clone()]);
+	let (ip, _) = pool.next_free(&HashSet::new()).unwrap();
+	assert_eq!(ip, "10.0.10.5/30".parse().unwrap());
+
+	let range = IpRange {
+		include_network_broadcast: true,
+		..range
+	};
+	assert_eq!(range.usable_count(), 4);
+	assert_eq!(range.iter_free().count(), 4);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "10.0.10.4/31"}"#).unwrap();
+	assert_eq!(range.usable_count(), 2);
+}
+
+#[test]
+fn test_excluded_reserved() {
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.0/24", "gateway": "10.0.10.1", "reserved": ["10.0.10.2", "10.0.10.4", "10.0.10.1", "10.0.11.1"]}"#,
+	)
+	.unwrap();
+	assert_eq!(
+		range.excluded(),
+		vec![
+			"10.0.10.0".parse::<IpAddr>().unwrap(),
+			"10.0.10.1".parse().unwrap(),
+			"10.0.10.2".parse().unwrap(),
+			"10.0.10.4".parse().unwrap(),
+			"10.0.10.255".parse().unwrap(),
+		]
+	);
+	assert_eq!(range.usable_count(), 251);
+	assert_eq!(range.usable_count(), range.iter_free().count() as u128);
+	assert!(!range.contains("10.0.10.4".parse().unwrap()));
+	assert!(range.contains("10.0.10.3".parse().unwrap()));
+
+	let pool = IpPool(vec![range]);
+	let (ip, _) = pool.next_free(&HashSet::new()).unwrap();
+	assert_eq!(ip, "10.0.10.3/24".parse().unwrap());
+
+	let range: IpRange =
+		serde_json::from_str(r#"{"subnet": "fd00::/120", "reserved": ["fd00::"]}"#).unwrap();
+	assert_eq!(range.excluded(), vec!["fd00::".parse::<IpAddr>().unwrap()]);
+	assert_eq!(range.usable_count(), 255);
+}
+
+#[test]
+fn test_subtract() {
+	let range: IpRange =
+		serde_json::from_str(r#"{"subnet": "10.0.0.0/24", "gateway": "10.0.0.1"}"#).unwrap();
+	let bounds = |ranges: Vec<IpRange>| {
+		ranges
+			.into_iter()
+			.map(|r| (r.range_start, r.range_end))
+			.collect::<Vec<_>>()
+	};
+	let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+	// non-overlapping
+	let ranges = range.subtract(&"10.0.1.0/24".parse().unwrap());
+	assert_eq!(bounds(ranges), vec![(None, None)]);
+	let ranges = range.subtract(&"fd00::/64".parse().unwrap());
+	assert_eq!(bounds(ranges), vec![(None, None)]);
+
+	// hole at the end
+	let ranges = range.subtract(&"10.0.0.128/25".parse().unwrap());
+	assert_eq!(ranges[0].gateway, ip("10.0.0.1"));
+	assert_eq!(bounds(ranges), vec![(None, ip("10.0.0.127"))]);
+
+	// fully-contained hole
+	let ranges = range.subtract(&"10.0.0.64/26".parse().unwrap());
+	assert_eq!(
+		bounds(ranges.clone()),
+		vec![(None, ip("10.0.0.63")), (ip("10.0.0.128"), None)]
+	);
+	assert_eq!(
+		ranges.iter().map(IpRange::usable_count).sum::<u128>(),
+		range.usable_count() - 64
+	);
+
+	// partial overlap of a bounded range
+	let range = IpRange {
+		range_start: ip("10.0.0.100"),
+		range_end: ip("10.0.0.200"),
+		..range
+	};
+	let ranges = range.subtract(&"10.0.0.128/25".parse().unwrap());
+	assert_eq!(bounds(ranges), vec![(ip("10.0.0.100"), ip("10.0.0.127"))]);
+	let ranges = range.subtract(&"10.0.0.0/25".parse().unwrap());
+	assert_eq!(bounds(ranges), vec![(ip("10.0.0.128"), ip("10.0.0.200"))]);
+
+	// hole covering everything
+	assert!(range.subtract(&"10.0.0.0/16".parse().unwrap()).is_empty());
+}
+
+#[test]
+fn test_gateway_or_first_host() {
+	let range: IpRange =
+		serde_json::from_str(r#"{"subnet": "10.0.10.0/24", "gateway": "10.0.10.254"}"#).unwrap();
+	assert_eq!(
+		range.gateway_or_first_host(),
+		Some("10.0.10.254".parse().unwrap())
+	);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "10.0.10.12/23"}"#).unwrap();
+	assert_eq!(
+		range.gateway_or_first_host(),
+		Some("10.0.10.1".parse().unwrap())
+	);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "10.0.10.12/31"}"#).unwrap();
+	assert_eq!(
+		range.gateway_or_first_host(),
+		Some("10.0.10.12".parse().unwrap())
+	);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "fd00::/64"}"#).unwrap();
+	assert_eq!(
+		range.gateway_or_first_host(),
+		Some("fd00::1".parse().unwrap())
+	);
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "fd00::5/128"}"#).unwrap();
+	assert_eq!(
+		range.gateway_or_first_host(),
+		Some("fd00::5".parse().unwrap())
+	);
+}
+
+#[test]
+fn test_first_last_usable() {
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "10.0.10.0/24"}"#).unwrap();
+	assert_eq!(range.first_usable(), Some("10.0.10.1".parse().unwrap()));
+	assert_eq!(range.last_usable(), Some("10.0.10.254".parse().unwrap()));
+
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.0/24", "rangeStart": "10.0.10.10", "rangeEnd": "10.0.10.20", "gateway": "10.0.10.10", "reserved": ["10.0.10.20"]}"#,
+	)
+	.unwrap();
+	assert_eq!(range.first_usable(), Some("10.0.10.11".parse().unwrap()));
+	assert_eq!(range.last_usable(), Some("10.0.10.19".parse().unwrap()));
+
+	// point-to-point: both ends are hosts, there's no room for a gateway
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "10.0.10.12/31"}"#).unwrap();
+	assert_eq!(range.first_usable(), Some("10.0.10.12".parse().unwrap()));
+	assert_eq!(range.last_usable(), Some("10.0.10.13".parse().unwrap()));
+
+	let range: IpRange = serde_json::from_str(r#"{"subnet": "fd00::4/127"}"#).unwrap();
+	assert_eq!(range.first_usable(), Some("fd00::4".parse().unwrap()));
+	assert_eq!(range.last_usable(), Some("fd00::5".parse().unwrap()));
+
+	let range: IpRange =
+		serde_json::from_str(r#"{"subnet": "fd00::4/127", "gateway": "fd00::4"}"#).unwrap();
+	assert_eq!(range.first_usable(), Some("fd00::5".parse().unwrap()));
+	assert_eq!(range.last_usable(), Some("fd00::5".parse().unwrap()));
+
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.12/31", "gateway": "10.0.10.12", "reserved": ["10.0.10.13"]}"#,
+	)
+	.unwrap();
+	assert_eq!(range.first_usable(), None);
+	assert_eq!(range.last_usable(), None);
+}
+
+#[test]
+fn test_pool_next_free() {
+	let pool: IpPool = serde_json::from_str(
+		r#"[
+			{"subnet": "10.0.10.0/24", "rangeStart": "10.0.10.1", "rangeEnd": "10.0.10.2", "gateway": "10.0.10.1"},
+			{"subnet": "10.0.20.0/23", "rangeStart": "10.0.21.0"}
+		]"#,
+	)
+	.unwrap();
+
+	let mut used = HashSet::new();
+	let (ip, range) = pool.next_free(&used).unwrap();
+	assert_eq!(ip, "10.0.10.2/24".parse().unwrap());
+	assert_eq!(range.subnet, "10.0.10.0/24".parse().unwrap());
+
+	used.insert("10.0.10.2".parse().unwrap());
+	used.insert("10.0.21.0".parse().unwrap());
+	let (ip, range) = pool.next_free(&used).unwrap();
+	assert_eq!(ip, "10.0.21.1/23".parse().unwrap());
+	assert_eq!(range.subnet, "10.0.20.0/23".parse().unwrap());
+
+	assert!(pool.contains("10.0.21.1".parse().unwrap()));
+	assert!(!pool.contains("10.0.10.1".parse().unwrap()));
+	assert!(!pool.contains("10.0.20.1".parse().unwrap()));
+	assert!(!pool.contains("10.0.21.255".parse().unwrap()));
+	assert_eq!(pool.usable_count(), 256);
+}
+
+#[test]
+fn test_pool_next_free_full() {
+	let pool: IpPool = serde_json::from_str(
+		r#"[{"subnet": "10.0.10.0/24", "rangeStart": "10.0.10.1", "rangeEnd": "10.0.10.2"}]"#,
+	)
+	.unwrap();
+
+	let used = vec!["10.0.10.1".parse().unwrap(), "10.0.10.2".parse().unwrap()]
+		.into_iter()
+		.collect();
+	assert!(pool.next_free(&used).is_none());
+}
+
+#[test]
+fn test_pool_next_free_large_v6() {
+	let pool: IpPool =
+		serde_json::from_str(r#"[{"subnet": "fd00::/16", "rangeStart": "fd00:ffff::1"}]"#).unwrap();
+
+	let (ip, _) = pool.next_free(&HashSet::new()).unwrap();
+	assert_eq!(ip, "fd00:ffff::1/16".parse().unwrap());
+}
+
+#[cfg(feature = "random-alloc")]
+#[test]
+fn test_pool_next_free_random() {
+	let pool: IpPool = serde_json::from_str(
+		r#"[
+			{"subnet": "10.0.10.0/24", "rangeStart": "10.0.10.1", "rangeEnd": "10.0.10.3", "gateway": "10.0.10.2"},
+			{"subnet": "fd00::/64", "rangeStart": "fd00::1", "rangeEnd": "fd00::2"}
+		]"#,
+	)
+	.unwrap();
+
+	let mut used = HashSet::new();
+	for _ in 0..4 {
+		let (ip, range) = pool.next_free_random(&used).unwrap();
+		assert!(range.contains(ip.ip()));
+		assert!(used.insert(ip.ip()));
+	}
+
+	assert!(pool.next_free_random(&used).is_none());
+}
+
+#[test]
+fn test_allocation_strategy_serde() {
+	assert_eq!(
+		AllocationStrategy::default(),
+		AllocationStrategy::Sequential
+	);
+	assert_eq!(
+		serde_json::from_str::<AllocationStrategy>(r#""leastRecentlyUsed""#).unwrap(),
+		AllocationStrategy::LeastRecentlyUsed
+	);
+	assert_eq!(
+		serde_json::to_string(&AllocationStrategy::Random).unwrap(),
+		r#""random""#
+	);
+}
+
+#[test]
+fn test_cursor_round_robin() {
+	let range: IpRange = serde_json::from_str(
+		r#"{"subnet": "10.0.10.0/24", "rangeStart": "10.0.10.1", "rangeEnd": "10.0.10.4", "gateway": "10.0.10.1"}"#,
+	)
+	.unwrap();
+	let mut used = HashSet::new();
+	let mut cursor = IpRangeCursor::new();
+
+	let ip = cursor.next_free(&range, &used).unwrap();
+	assert_eq!(ip, "10.0.10.2/24".parse().unwrap());
+	assert_eq!(
+		serde_json::to_string(&cursor).unwrap(),
+		r#"{"last":"10.0.10.2"}"#
+	);
+
+	// a release doesn't make the next allocation go back
+	let ip = cursor.next_free(&range, &used).unwrap();
+	assert_eq!(ip, "10.0.10.3/24".parse().unwrap());
+
+	// wraps around after the end, skipping the gateway and used IPs
+	used.insert("10.0.10.2".parse().unwrap());
+	let mut cursor: IpRangeCursor = serde_json::from_str(r#"{"last":"10.0.10.4"}"#).unwrap();
+	let ip = cursor.next_free(&range, &used).unwrap();
+	assert_eq!(ip, "10.0.10.3/24".parse().unwrap());
+
+	// a cursor outside of the range starts from the beginning
+	let mut cursor = IpRangeCursor {
+		last: Some("fd00::1".parse().unwrap()),
+	};
+	let ip = cursor.next_free(&range, &HashSet::new()).unwrap();
+	assert_eq!(ip, "10.0.10.2/24".parse().unwrap());
+
+	used.insert("10.0.10.3".parse().unwrap());
+	used.insert("10.0.10.4".parse().unwrap());
+	assert!(cursor.next_free(&range, &used).is_none());
+	assert_eq!(cursor.last, Some("10.0.10.2".parse().unwrap()));
+}
@@ -36,19 +36,32 @@
 pub use cni::Cni;
 pub use command::Command;
 pub use inputs::Inputs;
+pub use panic::install_panic_hook;
+pub use version::CniVersion;
 
 pub mod config;
 #[cfg(any(feature = "with-smol", feature = "with-tokio"))]
 pub mod delegation;
+#[cfg(feature = "schema")]
+pub mod describe;
 pub mod error;
 pub mod ip_range;
+#[cfg(all(feature = "lockfile", unix))]
+pub mod lockfile;
 pub mod logger;
 pub mod macaddr;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+pub mod netlink;
+#[cfg(all(feature = "netns", target_os = "linux"))]
+pub mod netns;
 pub mod reply;
 
 mod cni;
 mod command;
 mod dns;
 mod inputs;
+mod panic;
 mod path;
 mod version;
@@ -0,0 +1,164 @@
+//! Advisory file locks, to serialise access to file-backed state.
+//!
+//! This module is only available with the **lockfile** feature, and only on
+//! Unix.
+//!
+//! When the runtime starts many containers at once, several instances of an
+//! IPAM plugin may try to allocate from the same pool concurrently. Taking a
+//! [`Lock`] on a per-pool file for the duration of the allocation prevents two
+//! of them from handing out the same IP.
+
+use std::{
+	fs::{self, File, OpenOptions},
+	io,
+	os::unix::io::AsRawFd,
+	path::{Path, PathBuf},
+};
+
+use log::{debug, error};
+
+use crate::error::CniError;
+
+/// An exclusive advisory lock on a file, released on drop.
+///
+/// This uses `flock(2)`, so it only excludes other processes which also take
+/// the lock: it doesn't prevent reading or writing the file. The lock is held
+/// by the open file, so it's also released if the process exits or crashes.
+#[derive(Debug)]
+pub struct Lock {
+	file: File,
+	path: PathBuf,
+}
+
+impl Lock {
+	/// Takes the lock on the file at `path`, waiting for it if needed.
+	///
+	/// The file is created if it doesn't exist, but not its parent directory.
+	///
+	/// # Errors
+	///
+	/// This errors with [`CniError::Io`] if the file can't be opened or locked.
+	pub fn acquire(path: impl Into<PathBuf>) -> Result<Self, CniError> {
+		let path = path.into();
+		let file = open(&path)?;
+
+		debug!("waiting for lock on {}", path.display());
+		flock(&file, libc::LOCK_EX)?;
+		debug!("locked {}", path.display());
+
+		Ok(Self { file, path })
+	}
+
+	/// Takes the lock on the file at `path` if it's free.
+	///
+	/// Returns `Ok(None)` if another process holds the lock.
+	///
+	/// # Errors
+	///
+	/// This errors with [`CniError::Io`] if the file can't be opened or locked.
+	pub fn try_acquire(path: impl Into<PathBuf>) -> Result<Option<Self>, CniError> {
+		let path = path.into();
+		let file = open(&path)?;
+
+		match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+			Ok(()) => {
+				debug!("locked {}", path.display());
+				Ok(Some(Self { file, path }))
+			}
+			Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+				debug!("{} is locked elsewhere", path.display());
+				Ok(None)
+			}
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	/// Takes the lock for a pool, in the file `pool.lock` under `dir`.
+	///
+	/// The directory is created if it doesn't exist. This waits for the lock
+	/// like [`Lock::acquire()`].
+	///
+	/// # Errors
+	///
+	/// This errors with [`CniError::InvalidField`] if the pool name is empty,
+	/// `.` or `..`, or contains a `/`, as the lock file would then not be
+	/// directly under `dir`. It errors with [`CniError::Io`] if the directory
+	/// can't be created, or the file can't be opened or locked.
+	pub fn pool(dir: &Path, pool: &str) -> Result<Self, CniError> {
+		if pool.is_empty() || pool == "." || pool == ".." || pool.contains('/') {
+			return Err(CniError::InvalidField {
+				field: "pool",
+				expected: "pool name without slashes",
+				value: pool.into(),
+			});
+		}
+
+		fs::create_dir_all(dir)?;
+		Self::acquire(dir.join(format!("{}.lock", pool)))
+	}
+
+	/// The path of the lock file.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl Drop for Lock {
+	fn drop(&mut self) {
+		// closing the file releases the lock anyway, this is for the log
+		match flock(&self.file, libc::LOCK_UN) {
+			Ok(()) => debug!("unlocked {}", self.path.display()),
+			Err(err) => error!("could not unlock {}: {}", self.path.display(), err),
+		}
+	}
+}
+
+fn open(path: &Path) -> io::Result<File> {
+	OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(false)
+		.open(path)
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+	loop {
+		// SAFETY: the fd is valid for the duration of the call
+		if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+			return Ok(());
+		}
+
+		let err = io::Error::last_os_error();
+		if err.kind() != io::ErrorKind::Interrupted {
+			return Err(err);
+		}
+	}
+}
+
+#[test]
+fn test_lock_exclusive() {
+	let dir = std::env::temp_dir().join(format!("cni-plugin-lockfile-{}", std::process::id()));
+
+	let lock = Lock::pool(&dir, "default").unwrap();
+	assert_eq!(lock.path(), dir.join("default.lock"));
+
+	// flock is per open file, so this conflicts even within the process
+	assert!(Lock::try_acquire(lock.path()).unwrap().is_none());
+	assert!(Lock::try_acquire(dir.join("other.lock")).unwrap().is_some());
+
+	drop(lock);
+	assert!(Lock::try_acquire(dir.join("default.lock"))
+		.unwrap()
+		.is_some());
+
+	// the lock file must stay in the directory
+	for pool in &["", ".", "..", "../x", "a/b", "/tmp/x"] {
+		assert!(matches!(
+			Lock::pool(&dir, pool),
+			Err(CniError::InvalidField { field: "pool", .. })
+		));
+	}
+
+	fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,12 +1,16 @@
 //! The standard logger and utilities.
 
 use std::{
-	fs::OpenOptions,
+	fs::{File, OpenOptions},
+	io::Write,
 	path::{Path, PathBuf},
+	sync::Mutex,
+	time::{SystemTime, UNIX_EPOCH},
 };
 
-use log::LevelFilter;
-use simplelog::{Config, ConfigBuilder};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+use simplelog::{Config, ConfigBuilder, SharedLogger};
 
 /// Install the standard logger for plugins.
 ///
@@ -18,6 +22,9 @@ use simplelog::{Config, ConfigBuilder};
 /// working directory, and otherwise logs to `/var/log/cni/logname.log`,
 /// creating the directory if it does not exist.
 ///
+/// Messages are still subject to the compile-time max level of the `log`
+/// crate, which plugins can set with its `release_max_level_*` features.
+///
 /// Also see [`with_config`], notably to filter off modules.
 ///
 /// # Panics
@@ -25,12 +32,23 @@ use simplelog::{Config, ConfigBuilder};
 /// - if the logging directory cannot be created (in development or with the
 ///   release-logs feature only);
 /// - if the logfile cannot be opened (in development or with the release-logs
-///   feature only);
-/// - if the logger cannot be installed.
+///   feature only).
+///
+/// If a logger is already installed, this logs a warning to it and returns
+/// without installing anything. Use [`try_install`] to handle that case.
 pub fn install(logname: impl AsRef<Path>) {
 	with_config(logname, default_config().build())
 }
 
+/// Install the standard logger for plugins, returning an error if a logger is
+/// already installed.
+///
+/// This has identical behaviour to [`install`], except that the
+/// [`SetLoggerError`] is returned instead of being logged.
+pub fn try_install(logname: impl AsRef<Path>) -> Result<(), SetLoggerError> {
+	try_with_config(logname, default_config().build())
+}
+
 /// The default configuration for the standard logger, as a builder.
 ///
 /// Note that the default logger configuration is not stable.
@@ -48,6 +66,17 @@ pub fn default_config() -> ConfigBuilder {
 ///
 /// Also see [`default_config`] to obtain the default config _builder_.
 pub fn with_config(logname: impl AsRef<Path>, config: Config) {
+	if let Err(err) = try_with_config(logname, config) {
+		log::warn!("not installing the standard logger: {}", err);
+	}
+}
+
+/// Install the standard logger for plugins, with configuration, returning an
+/// error if a logger is already installed.
+///
+/// This has identical behaviour to [`with_config`], except that the
+/// [`SetLoggerError`] is returned instead of being logged.
+pub fn try_with_config(logname: impl AsRef<Path>, config: Config) -> Result<(), SetLoggerError> {
 	use simplelog::*;
 
 	let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
@@ -57,6 +86,97 @@ pub fn with_config(logname: impl AsRef<Path>, config: Config) {
 		ColorChoice::Never,
 	)];
 
+	if let Some(logfile) = open_logfile(logname) {
+		loggers.push(WriteLogger::new(LevelFilter::Debug, config, logfile));
+	}
+
+	CombinedLogger::init(loggers)
+}
+
+/// Install the standard logger for plugins, logging to a particular file.
+///
+/// This logger has identical behaviour to [`with_config`], except that it
+/// always logs from `debug` level up to the given file, regardless of build
+/// profile or features, and creates the parent directories of the file if
+/// they do not exist.
+///
+/// # Panics
+/// - if the logging directory cannot be created;
+/// - if the logfile cannot be opened.
+///
+/// If a logger is already installed, this logs a warning to it and returns
+/// without installing anything. Use [`try_with_file`] to handle that case.
+pub fn with_file(path: PathBuf, config: Config) {
+	if let Err(err) = try_with_file(path, config) {
+		log::warn!("not installing the standard logger: {}", err);
+	}
+}
+
+/// Install the standard logger for plugins, logging to a particular file,
+/// returning an error if a logger is already installed.
+///
+/// This has identical behaviour to [`with_file`], except that the
+/// [`SetLoggerError`] is returned instead of being logged.
+pub fn try_with_file(path: PathBuf, config: Config) -> Result<(), SetLoggerError> {
+	use simplelog::*;
+
+	CombinedLogger::init(vec![
+		TermLogger::new(
+			LevelFilter::Warn,
+			config.clone(),
+			TerminalMode::Stderr,
+			ColorChoice::Never,
+		),
+		WriteLogger::new(LevelFilter::Debug, config, open_file(&path)),
+	])
+}
+
+/// Install the standard logger for plugins, with JSON output to file.
+///
+/// This logger has identical behaviour to [`install`], except that messages
+/// sent to the log file are formatted as one JSON object per line, with fields
+/// `timestamp` (seconds since the UNIX epoch, with fractional milliseconds),
+/// `level`, `target`, and `message`, and that the file log level can be set.
+///
+/// Messages sent to STDERR are still human-formatted.
+///
+/// # Panics
+/// In the same conditions as [`install`].
+///
+/// If a logger is already installed, this logs a warning to it and returns
+/// without installing anything. Use [`try_with_json`] to handle that case.
+pub fn with_json(logname: impl AsRef<Path>, level: LevelFilter) {
+	if let Err(err) = try_with_json(logname, level) {
+		log::warn!("not installing the standard logger: {}", err);
+	}
+}
+
+/// Install the standard logger for plugins, with JSON output to file,
+/// returning an error if a logger is already installed.
+///
+/// This has identical behaviour to [`with_json`], except that the
+/// [`SetLoggerError`] is returned instead of being logged.
+pub fn try_with_json(logname: impl AsRef<Path>, level: LevelFilter) -> Result<(), SetLoggerError> {
+	use simplelog::*;
+
+	let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+		LevelFilter::Warn,
+		default_config().build(),
+		TerminalMode::Stderr,
+		ColorChoice::Never,
+	)];
+
+	if let Some(logfile) = open_logfile(logname) {
+		loggers.push(Box::new(JsonLogger {
+			level,
+			file: Mutex::new(logfile),
+		}));
+	}
+
+	CombinedLogger::init(loggers)
+}
+
+fn open_logfile(logname: impl AsRef<Path>) -> Option<File> {
 	if cfg!(any(debug_assertions, feature = "release-logs")) {
 		let logdir = if cfg!(debug_assertions) {
 			std::env::current_dir().unwrap()
@@ -67,20 +187,74 @@ pub fn with_config(logname: impl AsRef<Path>, config: Config) {
 		let mut logfile = logdir.join(logname);
 		logfile.set_extension("log");
 
-		if let Some(dir) = logfile.parent() {
-			std::fs::create_dir_all(dir).unwrap();
+		Some(open_file(&logfile))
+	} else {
+		None
+	}
+}
+
+fn open_file(logfile: &Path) -> File {
+	if let Some(dir) = logfile.parent() {
+		std::fs::create_dir_all(dir).unwrap();
+	}
+
+	OpenOptions::new()
+		.append(true)
+		.create(true)
+		.open(logfile)
+		.unwrap()
+}
+
+struct JsonLogger {
+	level: LevelFilter,
+	file: Mutex<File>,
+}
+
+impl Log for JsonLogger {
+	fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+		metadata.level() <= self.level
+	}
+
+	fn log(&self, record: &Record<'_>) {
+		if !self.enabled(record.metadata()) {
+			return;
 		}
 
-		loggers.push(WriteLogger::new(
-			LevelFilter::Debug,
-			config,
-			OpenOptions::new()
-				.append(true)
-				.create(true)
-				.open(logfile)
-				.unwrap(),
-		));
+		let timestamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_millis() as f64 / 1000.0)
+			.unwrap_or_default();
+
+		let line = json!({
+			"timestamp": timestamp,
+			"level": record.level().as_str(),
+			"target": record.target(),
+			"message": record.args().to_string(),
+		});
+
+		if let Ok(mut file) = self.file.lock() {
+			// there's nowhere to report logging errors to, so ignore them
+			writeln!(file, "{}", line).ok();
+		}
 	}
 
-	CombinedLogger::init(loggers).unwrap();
+	fn flush(&self) {
+		if let Ok(mut file) = self.file.lock() {
+			file.flush().ok();
+		}
+	}
+}
+
+impl SharedLogger for JsonLogger {
+	fn level(&self) -> LevelFilter {
+		self.level
+	}
+
+	fn config(&self) -> Option<&Config> {
+		None
+	}
+
+	fn as_log(self: Box<Self>) -> Box<dyn Log> {
+		Box::new(*self)
+	}
 }
@@ -1,6 +1,6 @@
 //! MAC address (de)serialisation.
 
-use std::{fmt, str::FromStr};
+use std::{fmt, net::Ipv6Addr, str::FromStr};
 
 use macaddr::{MacAddr6, ParseError};
 use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
@@ -13,6 +13,50 @@ use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 #[derive(Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
 pub struct MacAddr(pub MacAddr6);
 
+impl MacAddr {
+	/// Generates a random locally-administered unicast MAC address.
+	///
+	/// The bytes are obtained from the OS random number generator, and then the
+	/// first octet is adjusted to set the locally-administered bit and clear the
+	/// multicast bit, such that the address is suitable for an interface.
+	///
+	/// This is only available with the **random-mac** feature.
+	#[cfg(feature = "random-mac")]
+	pub fn random_local() -> Self {
+		use rand::{rngs::OsRng, RngCore};
+
+		let mut bytes = [0_u8; 6];
+		OsRng.fill_bytes(&mut bytes);
+		bytes[0] = (bytes[0] | 0b10) & !0b01;
+		Self(MacAddr6::from(bytes))
+	}
+
+	/// Derives the modified EUI-64 interface identifier from this address.
+	///
+	/// This is the interface identifier used for IPv6 stateless address
+	/// autoconfiguration ([RFC 4291 Appendix A][rfc4291-a]): `ff:fe` is
+	/// inserted in the middle of the address, and the universal/local bit is
+	/// flipped.
+	///
+	/// [rfc4291-a]: https://tools.ietf.org/html/rfc4291#appendix-A
+	pub fn to_eui64(&self) -> [u8; 8] {
+		let m = self.0.as_bytes();
+		[m[0] ^ 0b10, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5]]
+	}
+
+	/// Derives the IPv6 link-local address for this address.
+	///
+	/// This is the `fe80::/64` prefix followed by the [EUI-64][MacAddr::to_eui64()]
+	/// interface identifier.
+	pub fn to_link_local_ipv6(&self) -> Ipv6Addr {
+		let mut octets = [0_u8; 16];
+		octets[0] = 0xfe;
+		octets[1] = 0x80;
+		octets[8..].copy_from_slice(&self.to_eui64());
+		Ipv6Addr::from(octets)
+	}
+}
+
 impl From<MacAddr6> for MacAddr {
 	fn from(m: MacAddr6) -> Self {
 		Self(m)
@@ -57,3 +101,49 @@ impl<'de> Deserialize<'de> for MacAddr {
 		Self::from_str(&j).map_err(Error::custom)
 	}
 }
+
+#[cfg(feature = "random-mac")]
+#[test]
+fn test_random_local() {
+	for _ in 0..100 {
+		let mac = MacAddr::random_local();
+		let first = mac.0.as_bytes()[0];
+		assert_eq!(
+			first & 0b10,
+			0b10,
+			"locally-administered bit not set: {}",
+			mac
+		);
+		assert_eq!(first & 0b01, 0, "multicast bit not cleared: {}", mac);
+	}
+}
+
+#[test]
+fn test_eui64() {
+	let mac: MacAddr = "00:11:22:33:44:55".parse().unwrap();
+	assert_eq!(
+		mac.to_eui64(),
+		[0x02, 0x11, 0x22, 0xff, 0xfe, 0x33, 0x44, 0x55]
+	);
+
+	let mac: MacAddr = "52:54:00:ab:cd:ef".parse().unwrap();
+	assert_eq!(
+		mac.to_eui64(),
+		[0x50, 0x54, 0x00, 0xff, 0xfe, 0xab, 0xcd, 0xef]
+	);
+}
+
+#[test]
+fn test_link_local_ipv6() {
+	let mac: MacAddr = "00:11:22:33:44:55".parse().unwrap();
+	assert_eq!(
+		mac.to_link_local_ipv6(),
+		"fe80::211:22ff:fe33:4455".parse::<Ipv6Addr>().unwrap()
+	);
+
+	let mac: MacAddr = "52:54:00:ab:cd:ef".parse().unwrap();
+	assert_eq!(
+		mac.to_link_local_ipv6(),
+		"fe80::5054:ff:feab:cdef".parse::<Ipv6Addr>().unwrap()
+	);
+}
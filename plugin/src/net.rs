@@ -0,0 +1,129 @@
+//! Helpers for plugins which talk to remote servers.
+//!
+//! This module is only available with the **net** feature.
+
+use std::{collections::HashMap, fmt::Display, future::Future, path::PathBuf};
+
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::error::CniError;
+
+/// Finds the first server in a list which passes a check.
+///
+/// The URLs are tried in order, and the first one for which the `check`
+/// succeeds is returned along with the output of the check, so that the check
+/// can be a useful request rather than just a probe.
+///
+/// This goes through the list once: retrying is left to the caller. This does
+/// not depend on an async runtime.
+///
+/// # Errors
+///
+/// This errors with a [`CniError::Generic`] if the list is empty, or with all
+/// the check errors together if every check fails.
+///
+/// # Example
+///
+/// ```no_run
+/// # async fn example(servers: &[url::Url]) -> Result<(), cni_plugin::error::CniError> {
+/// use cni_plugin::net::first_reachable;
+/// let (server, _) = first_reachable(servers, |url| async move {
+///     if url.scheme() == "http" { Ok(()) } else { Err("not http") }
+/// })
+/// .await?;
+/// # Ok(()) }
+/// ```
+pub async fn first_reachable<'u, T, E, F, Fut>(
+	urls: &'u [Url],
+	check: F,
+) -> Result<(&'u Url, T), CniError>
+where
+	F: Fn(&'u Url) -> Fut,
+	Fut: Future<Output = Result<T, E>>,
+	E: Display,
+{
+	let mut errors = Vec::with_capacity(urls.len());
+	for url in urls {
+		match check(url).await {
+			Ok(output) => {
+				debug!("found good server: {}", url);
+				return Ok((url, output));
+			}
+			Err(err) => {
+				warn!("bad server, trying next. url={} err={}", url, err);
+				errors.push(format!("{}: {}", url, err));
+			}
+		}
+	}
+
+	Err(CniError::Generic(if errors.is_empty() {
+		"no servers to try".into()
+	} else {
+		format!("no good server: {}", errors.join("; "))
+	}))
+}
+
+/// TLS options for talking to servers over HTTPS.
+///
+/// This doesn't do anything by itself, as this library has no HTTP client:
+/// plugins should configure theirs according to these options.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct TlsOptions {
+	/// Path to a PEM file of CA certificates to trust instead of the system's.
+	#[serde(default, rename = "tlsCaCert")]
+	pub ca_cert: Option<PathBuf>,
+
+	/// Whether to skip verifying server certificates. Dangerous!
+	#[serde(default, rename = "tlsInsecure")]
+	pub insecure: bool,
+}
+
+impl TlsOptions {
+	/// Reads the `tlsCaCert` and `tlsInsecure` fields of a config section.
+	///
+	/// Missing fields give the defaults: trust the system's CAs, and verify.
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::Json`] if the fields are not of the right types.
+	pub fn from_specific(specific: &HashMap<String, Value>) -> Result<Self, CniError> {
+		let mut fields = serde_json::Map::new();
+		for key in &["tlsCaCert", "tlsInsecure"] {
+			if let Some(value) = specific.get(*key) {
+				fields.insert((*key).into(), value.clone());
+			}
+		}
+
+		Ok(serde_json::from_value(Value::Object(fields))?)
+	}
+}
+
+#[test]
+fn test_tls_options() {
+	let specific: HashMap<String, Value> = serde_json::from_value(serde_json::json!({
+		"tlsCaCert": "/etc/ssl/internal.pem",
+		"other": 1,
+	}))
+	.unwrap();
+	assert_eq!(
+		TlsOptions::from_specific(&specific).unwrap(),
+		TlsOptions {
+			ca_cert: Some("/etc/ssl/internal.pem".into()),
+			insecure: false,
+		}
+	);
+	assert_eq!(
+		TlsOptions::from_specific(&HashMap::new()).unwrap(),
+		TlsOptions::default()
+	);
+
+	let specific: HashMap<String, Value> =
+		serde_json::from_value(serde_json::json!({ "tlsInsecure": "yes" })).unwrap();
+	assert!(matches!(
+		TlsOptions::from_specific(&specific),
+		Err(CniError::Json(_))
+	));
+}
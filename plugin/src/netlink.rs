@@ -0,0 +1,104 @@
+//! Helpers for plugins which manage interfaces, routes, or neighbours over
+//! netlink with [rtnetlink].
+//!
+//! This module is only available with the **netlink** feature, and only on
+//! Linux.
+
+use std::convert::TryInto;
+
+use futures::stream::TryStreamExt;
+use log::debug;
+use macaddr::MacAddr6;
+use rtnetlink::{packet::rtnl::link::nlas::Nla, Handle, LinkHandle};
+
+use crate::{error::CniError, macaddr::MacAddr};
+
+/// An interface, as found by [`resolve_link()`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LinkInfo {
+	/// The index of the interface.
+	pub index: u32,
+
+	/// The hardware address of the interface.
+	///
+	/// This is `None` if the interface doesn't have a 6-byte hardware address,
+	/// like loopback, tunnels, or infiniband interfaces.
+	pub mac: Option<MacAddr>,
+}
+
+/// Looks up an interface by name.
+///
+/// # Errors
+///
+/// This errors with [`CniError::MissingInterface`] if there's no interface with
+/// that name, and with a [`CniError::Generic`] if netlink fails.
+pub async fn resolve_link(handle: &Handle, name: &str) -> Result<LinkInfo, CniError> {
+	let mut linklist = LinkHandle::new(handle.clone())
+		.get()
+		.set_name_filter(name.into())
+		.execute();
+
+	let link = linklist
+		.try_next()
+		.await
+		.map_err(|err| link_error(err, name))?
+		.ok_or_else(|| CniError::MissingInterface(name.into()))?;
+	debug!("link: {:?}", link.header);
+
+	let mac = link.nlas.iter().find_map(|nla| match nla {
+		Nla::Address(bytes) => {
+			let bytes: [u8; 6] = bytes.as_slice().try_into().ok()?;
+			Some(MacAddr6::from(bytes).into())
+		}
+		_ => None,
+	});
+
+	Ok(LinkInfo {
+		index: link.header.index,
+		mac,
+	})
+}
+
+/// Converts a netlink error from looking up the interface `name`.
+///
+/// A lookup by name isn't a dump, so the kernel answers a missing interface
+/// with an ENODEV error rather than an empty reply. That becomes a
+/// [`CniError::MissingInterface`], and other errors go through [`nlerror()`].
+fn link_error(err: rtnetlink::Error, name: &str) -> CniError {
+	match err {
+		rtnetlink::Error::NetlinkError(ref msg) if msg.code == -libc::ENODEV => {
+			CniError::MissingInterface(name.into())
+		}
+		err => nlerror(err),
+	}
+}
+
+/// Converts a netlink error to a [`CniError::Generic`].
+pub fn nlerror(err: rtnetlink::Error) -> CniError {
+	CniError::Generic(format!("netlink: {}", err))
+}
+
+#[test]
+fn test_link_error() {
+	use rtnetlink::packet::ErrorMessage;
+
+	let err = |code| {
+		rtnetlink::Error::NetlinkError(ErrorMessage {
+			code,
+			header: Vec::new(),
+		})
+	};
+
+	assert!(matches!(
+		link_error(err(-libc::ENODEV), "eth0"),
+		CniError::MissingInterface(name) if name == "eth0"
+	));
+	assert!(matches!(
+		link_error(err(-libc::EPERM), "eth0"),
+		CniError::Generic(_)
+	));
+	assert!(matches!(
+		link_error(rtnetlink::Error::RequestFailed, "eth0"),
+		CniError::Generic(_)
+	));
+}
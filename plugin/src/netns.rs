@@ -0,0 +1,94 @@
+//! Helpers to run code inside a network namespace.
+//!
+//! This module is only available with the **netns** feature, and only on Linux.
+
+use std::{fs::File, io, os::unix::io::AsRawFd, path::Path};
+
+use log::{debug, error};
+
+use crate::error::CniError;
+
+/// The network namespace of the current thread.
+const THREAD_NETNS: &str = "/proc/thread-self/ns/net";
+
+/// Runs a closure inside the network namespace at `path`.
+///
+/// This is typically given the `CNI_NETNS` path, to act on the container's
+/// interfaces, addresses, routes, sysctls, etc. Namespaces are per-thread, so
+/// this only affects the current thread: don't spawn threads or await futures
+/// which may be polled elsewhere from within the closure and expect them to be
+/// in the namespace.
+///
+/// The original namespace is restored after the closure returns, and also if
+/// it panics.
+///
+/// # Errors
+///
+/// This errors with [`CniError::Io`] if the namespace can't be opened or
+/// entered (in which case the closure is not run), or if the original namespace
+/// can't be restored afterwards.
+///
+/// # Example
+///
+/// ```no_run
+/// # fn example(netns: &std::path::Path) -> Result<(), cni_plugin::error::CniError> {
+/// use cni_plugin::netns::with_netns;
+/// let forwarding = with_netns(netns, || {
+///     std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")
+/// })??;
+/// # Ok(()) }
+/// ```
+pub fn with_netns<T>(path: &Path, f: impl FnOnce() -> T) -> Result<T, CniError> {
+	let original = File::open(THREAD_NETNS)?;
+	let target = File::open(path)?;
+
+	debug!("entering netns {}", path.display());
+	setns(&target)?;
+	let mut guard = Restore {
+		original,
+		done: false,
+	};
+
+	let output = f();
+
+	debug!("restoring original netns");
+	guard.done = true;
+	setns(&guard.original)?;
+	Ok(output)
+}
+
+/// Restores the original namespace if the closure panics.
+struct Restore {
+	original: File,
+	done: bool,
+}
+
+impl Drop for Restore {
+	fn drop(&mut self) {
+		if !self.done {
+			if let Err(err) = setns(&self.original) {
+				error!("could not restore original netns: {}", err);
+			}
+		}
+	}
+}
+
+fn setns(ns: &File) -> io::Result<()> {
+	// SAFETY: the fd is valid for the duration of the call, and setns doesn't
+	// retain it
+	if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } == -1 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+#[test]
+fn test_with_netns_missing() {
+	let mut ran = false;
+	assert!(matches!(
+		with_netns(Path::new("/var/run/netns/does-not-exist"), || ran = true),
+		Err(CniError::Io(_))
+	));
+	assert!(!ran);
+}
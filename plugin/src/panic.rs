@@ -0,0 +1,38 @@
+use std::panic;
+
+use log::error;
+
+use crate::{error::CniError, reply::reply, CniVersion};
+
+/// Install a panic hook which replies with a CNI error.
+///
+/// Without this, a panicking plugin exits with a Rust backtrace on STDERR and
+/// nothing on STDOUT, which runtimes handle poorly. With it, a panic anywhere
+/// in the plugin replies with a [`CniError::Generic`] (code 100) containing the
+/// panic message, and exits with that code.
+///
+/// The previously-installed hook (by default, the one printing the panic and
+/// backtrace to STDERR) is still called first, and the panic is also logged.
+///
+/// This should be installed right after [`logger::install`][crate::logger::install],
+/// before [`Cni::load()`][crate::Cni::load()]. As the config isn't read yet at
+/// that point, pass the latest spec version the plugin supports; it's only
+/// used for the `cniVersion` of the error reply.
+///
+/// ```no_run
+/// use cni_plugin::{install_panic_hook, logger, Cni};
+/// use semver::Version;
+///
+/// logger::install(env!("CARGO_PKG_NAME"));
+/// install_panic_hook(Version::new(1, 0, 0));
+/// let cni = Cni::load();
+/// ```
+pub fn install_panic_hook(cni_version: impl Into<CniVersion>) {
+	let cni_version = cni_version.into();
+	let previous = panic::take_hook();
+	panic::set_hook(Box::new(move |info| {
+		previous(info);
+		error!("{}", info);
+		reply(CniError::Generic(info.to_string()).into_reply(cni_version.clone()));
+	}));
+}
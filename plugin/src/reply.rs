@@ -1,16 +1,29 @@
 //! Reply types and helpers.
 
-use std::{collections::HashMap, io::stdout, net::IpAddr, path::PathBuf, process::exit};
+use std::{
+	collections::HashMap,
+	io::{self, stdout, ErrorKind, Write},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	path::PathBuf,
+	process::exit,
+};
 
 use ipnetwork::IpNetwork;
-use log::debug;
+use log::{debug, warn};
 use semver::Version;
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 use serde_json::Value;
 
-pub use crate::dns::Dns;
-use crate::macaddr::MacAddr;
+pub use crate::dns::{Dns, DnsMergeMode};
 pub use crate::version::VersionReply;
+use crate::{config::NetworkConfig, error::CniError, macaddr::MacAddr, CniVersion};
+
+/// DNS information in a reply.
+///
+/// This is the same type as in the network configuration, as the spec uses the
+/// same shape for both. It's given its own name here so that reply-building
+/// code reads naturally; use whichever name you prefer.
+pub type DnsReply = Dns;
 
 /// Trait for a reply type to be handled by the [`reply()`] function.
 ///
@@ -30,9 +43,7 @@ pub trait ReplyPayload<'de>: std::fmt::Debug + Serialize + Deserialize<'de> {
 #[serde(rename_all = "camelCase")]
 pub struct ErrorReply<'msg> {
 	/// The CNI version of the plugin input config.
-	#[serde(deserialize_with = "crate::version::deserialize_version")]
-	#[serde(serialize_with = "crate::version::serialize_version")]
-	pub cni_version: Version,
+	pub cni_version: CniVersion,
 
 	/// A code for the error.
 	///
@@ -58,14 +69,58 @@ impl<'de> ReplyPayload<'de> for ErrorReply<'de> {
 	}
 }
 
+/// Error codes defined by the spec in the reserved 1-99 range.
+const SPEC_ERROR_CODES: &[i32] = &[1, 2, 3, 4, 5, 6, 7, 11, 50, 51];
+
+impl<'msg> ErrorReply<'msg> {
+	/// Create an error reply, checking the code.
+	///
+	/// The fields of [`ErrorReply`] are public, but prefer this constructor as
+	/// it enforces that the code can be used as an exit code: the `code` must
+	/// be between 1 and 255, as exit codes are truncated to a byte.
+	///
+	/// Codes in the 1-99 range which are not defined by the spec are accepted
+	/// but logged as a warning, as plugins' own codes should be 100 or above.
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::InvalidField`] if the code is out of range.
+	pub fn new(
+		cni_version: impl Into<CniVersion>,
+		code: i32,
+		msg: &'msg str,
+		details: String,
+	) -> Result<Self, CniError> {
+		if !(1..=255).contains(&code) {
+			return Err(CniError::InvalidField {
+				field: "code",
+				expected: "an error code from 1 to 255",
+				value: code.into(),
+			});
+		}
+
+		if code < 100 && !SPEC_ERROR_CODES.contains(&code) {
+			warn!(
+				"error code {} is reserved by the spec but not defined by it, plugin codes should be 100 or above",
+				code
+			);
+		}
+
+		Ok(Self {
+			cni_version: cni_version.into(),
+			code,
+			msg,
+			details,
+		})
+	}
+}
+
 /// The reply structure used when returning a success.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SuccessReply {
 	/// The CNI version of the plugin input config.
-	#[serde(deserialize_with = "crate::version::deserialize_version")]
-	#[serde(serialize_with = "crate::version::serialize_version")]
-	pub cni_version: Version,
+	pub cni_version: CniVersion,
 
 	/// The list of all interfaces created by this plugin.
 	///
@@ -100,19 +155,218 @@ pub struct SuccessReply {
 
 impl<'de> ReplyPayload<'de> for SuccessReply {}
 
+impl Serialize for SuccessReply {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		SuccessFields {
+			cni_version: &self.cni_version,
+			interfaces: Some(&self.interfaces),
+			ips: &self.ips,
+			routes: &self.routes,
+			dns: &self.dns,
+			pool: None,
+			specific: &self.specific,
+		}
+		.serialize(serializer)
+	}
+}
+
 impl SuccessReply {
+	/// Obtain the previous result from the config, or an empty reply.
+	///
+	/// This deserializes the `prev_result` of the config as a success reply if
+	/// it is present, and otherwise returns a success reply with no interfaces,
+	/// IPs, routes, nor DNS, and with the config’s `cni_version`.
+	///
+	/// This is the usual starting point for post-plugins which need to add to
+	/// the previous result.
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::Json`] if the `prev_result` is not a valid success
+	/// reply. See [`from_prev_result()`][SuccessReply::from_prev_result()] for
+	/// more.
+	pub fn from_prev_or_default(config: &NetworkConfig) -> Result<Self, CniError> {
+		if let Some(prev) = &config.prev_result {
+			Self::from_prev_result(prev.clone(), &config.cni_version)
+		} else {
+			Ok(Self {
+				cni_version: config.cni_version.clone(),
+				interfaces: Default::default(),
+				ips: Default::default(),
+				routes: Default::default(),
+				dns: Default::default(),
+				specific: Default::default(),
+			})
+		}
+	}
+
+	/// Obtain the reply for a post-plugin which doesn't change the result.
+	///
+	/// Post-plugins must output the previous result, so one which only acts on
+	/// the system (or for commands where it has nothing to add) replies with
+	/// this. It is the same as
+	/// [`from_prev_or_default()`][SuccessReply::from_prev_or_default()], named
+	/// for intent.
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::Json`] if the `prev_result` is not a valid success
+	/// reply.
+	pub fn passthrough(config: &NetworkConfig) -> Result<Self, CniError> {
+		Self::from_prev_or_default(config)
+	}
+
+	/// Deserialize a previous result given in the shape of an older spec.
+	///
+	/// The `prev_result` is in the format of the config's `cni_version`. Before
+	/// 1.0.0, entries in `ips` have a `version` field (`"4"` or `"6"`), and the
+	/// `dns` field may be missing; these are converted to the current shape.
+	/// From 1.0.0, this is the same as deserializing directly.
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::Json`] if the value is not a valid success reply, and
+	/// [`CniError::InvalidField`] if an IP's `version` doesn't match its address.
+	pub fn from_prev_result(mut value: Value, cni_version: &Version) -> Result<Self, CniError> {
+		if cni_version.major == 0 {
+			if let Value::Object(map) = &mut value {
+				if let Some(Value::Array(ips)) = map.get_mut("ips") {
+					for ip in ips {
+						strip_ip_version(ip)?;
+					}
+				}
+
+				map.entry("dns")
+					.or_insert_with(|| Value::Object(Default::default()));
+			}
+		}
+
+		Ok(serde_json::from_value(value)?)
+	}
+
+	/// Check that the reply is internally consistent.
+	///
+	/// This is recommended before [`reply()`]ing, to catch plugin bugs which
+	/// would otherwise only confuse the runtime. It checks that:
+	/// - every IP's `interface` is an index into the `interfaces` list;
+	/// - every IP's `gateway` is of the same family as its address;
+	/// - every route's `gw` is of the same family as its `dst`, and is neither
+	///   unspecified nor multicast.
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::InvalidField`] for the first problem found.
+	pub fn validate(&self) -> Result<(), CniError> {
+		for ip in &self.ips {
+			if let Some(index) = ip.interface {
+				if index >= self.interfaces.len() {
+					return Err(CniError::InvalidField {
+						field: "ips[].interface",
+						expected: "an index into interfaces",
+						value: index.into(),
+					});
+				}
+			}
+
+			if let Some(gateway) = ip.gateway {
+				if gateway.is_ipv4() != ip.address.is_ipv4() {
+					return Err(CniError::InvalidField {
+						field: "ips[].gateway",
+						expected: "an address of the same family as the ip",
+						value: gateway.to_string().into(),
+					});
+				}
+			}
+		}
+
+		for route in &self.routes {
+			if let Some(gw) = route.gw {
+				if gw.is_ipv4() != route.dst.is_ipv4() || gw.is_unspecified() || gw.is_multicast() {
+					return Err(CniError::InvalidField {
+						field: "routes[].gw",
+						expected: "a unicast address of the same family as the destination",
+						value: gw.to_string().into(),
+					});
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Adds the IPs, routes, and DNS of a delegated IPAM result.
+	///
+	/// This is for main plugins which create an interface and then delegate to
+	/// IPAM: the IPAM result's IPs have no `interface`, so they're added here
+	/// pointing at `iface_index` in the `interfaces` list. As with
+	/// [`IpamSuccessReply::merge()`], IPs are skipped if this reply already has
+	/// the same address, routes if they're already present, and DNS is merged in
+	/// [`DnsMergeMode::Append`] mode.
+	///
+	/// The index isn't checked here; use [`validate()`][SuccessReply::validate()]
+	/// before replying. The IPAM result's version, pool, and specific fields are
+	/// discarded.
+	pub fn attach_ips_to_interface(&mut self, ipam: IpamSuccessReply, iface_index: usize) {
+		for ip in ipam.ips {
+			if !self.ips.iter().any(|known| known.address == ip.address) {
+				self.ips.push(Ip {
+					interface: Some(iface_index),
+					..ip
+				});
+			}
+		}
+
+		for route in ipam.routes {
+			if !self.routes.contains(&route) {
+				self.routes.push(route);
+			}
+		}
+
+		self.dns.merge(ipam.dns, DnsMergeMode::Append);
+	}
+
 	/// Cast into an abbreviated success reply if the interface list is empty.
+	///
+	/// Also see [`try_into_ipam()`][SuccessReply::try_into_ipam()], which
+	/// returns a descriptive error instead of `None`.
 	pub fn into_ipam(self) -> Option<IpamSuccessReply> {
+		self.try_into_ipam().ok()
+	}
+
+	/// Cast into an abbreviated success reply, erroring if there are interfaces.
+	///
+	/// # Errors
+	///
+	/// Returns [`CniError::Generic`] if the interface list is not empty, as an
+	/// IPAM reply must not contain interfaces.
+	pub fn try_into_ipam(self) -> Result<IpamSuccessReply, CniError> {
 		if self.interfaces.is_empty() {
-			Some(IpamSuccessReply {
+			let mut specific = self.specific;
+			let pool = match specific.remove("pool") {
+				Some(Value::String(pool)) => Some(pool),
+				Some(other) => {
+					specific.insert("pool".into(), other);
+					None
+				}
+				None => None,
+			};
+
+			Ok(IpamSuccessReply {
 				cni_version: self.cni_version,
 				ips: self.ips,
 				routes: self.routes,
 				dns: self.dns,
-				specific: self.specific,
+				pool,
+				specific,
 			})
 		} else {
-			None
+			Err(CniError::Generic(format!(
+				"IPAM reply must not contain interfaces, got {}",
+				self.interfaces.len()
+			)))
 		}
 	}
 }
@@ -121,13 +375,11 @@ impl SuccessReply {
 ///
 /// It is identical to [`SuccessReply`] except for the lack of the `interfaces`
 /// field.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct IpamSuccessReply {
 	/// The CNI version of the plugin input config.
-	#[serde(deserialize_with = "crate::version::deserialize_version")]
-	#[serde(serialize_with = "crate::version::serialize_version")]
-	pub cni_version: Version,
+	pub cni_version: CniVersion,
 
 	/// The list of all IPs assigned by this plugin.
 	///
@@ -147,6 +399,14 @@ pub struct IpamSuccessReply {
 	#[serde(default)]
 	pub dns: Dns,
 
+	/// The name of the pool the IPs were allocated from, if known.
+	///
+	/// This lets later plugins, and the DEL of the allocating plugin, know the
+	/// pool without working it out again. It's serialised as a `pool` field if
+	/// present, which is off-spec and may be discarded by libcni.
+	#[serde(default)]
+	pub pool: Option<String>,
+
 	/// Custom reply fields.
 	///
 	/// Note that these are off-spec and may be discarded by libcni.
@@ -156,6 +416,239 @@ pub struct IpamSuccessReply {
 
 impl<'de> ReplyPayload<'de> for IpamSuccessReply {}
 
+impl IpamSuccessReply {
+	/// Builds a reply for a single allocated address.
+	///
+	/// This is the common case for IPAM plugins. The `ips` list has the one
+	/// address with its gateway, and if `default_route` is true, a default
+	/// route (`0.0.0.0/0` or `::/0`, depending on the address) is added via the
+	/// gateway. Everything else is left empty; set the fields directly if more
+	/// is needed.
+	pub fn single(
+		cni_version: impl Into<CniVersion>,
+		ip: IpNetwork,
+		gateway: Option<IpAddr>,
+		default_route: bool,
+	) -> Self {
+		let mut routes = Vec::new();
+		if default_route {
+			let any = match ip {
+				IpNetwork::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+				IpNetwork::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+			};
+
+			routes.push(Route {
+				// UNWRAP: a zero prefix is always valid
+				dst: IpNetwork::new(any, 0).unwrap(),
+				gw: gateway,
+			});
+		}
+
+		Self {
+			cni_version: cni_version.into(),
+			ips: vec![Ip {
+				address: ip,
+				gateway,
+				interface: None,
+			}],
+			routes,
+			dns: Dns::default(),
+			pool: None,
+			specific: HashMap::new(),
+		}
+	}
+
+	/// Merges a later result into this one.
+	///
+	/// This is for chaining IPAM plugins, where a later plugin may or may not
+	/// carry the IPs and routes of the `prevResult` through. IPs are added
+	/// unless this result already has the same address, routes unless they're
+	/// already present, DNS is merged in [`DnsMergeMode::Append`] mode, and the
+	/// `specific` fields of the other result replace those of the same name.
+	/// The pool is only taken from the other result if this one has none.
+	///
+	/// The `cni_version` of this result is kept.
+	pub fn merge(&mut self, other: IpamSuccessReply) {
+		for ip in other.ips {
+			if !self.ips.iter().any(|known| known.address == ip.address) {
+				self.ips.push(ip);
+			}
+		}
+
+		for route in other.routes {
+			if !self.routes.contains(&route) {
+				self.routes.push(route);
+			}
+		}
+
+		self.dns.merge(other.dns, DnsMergeMode::Append);
+
+		if self.pool.is_none() {
+			self.pool = other.pool;
+		}
+
+		self.specific.extend(other.specific);
+	}
+}
+
+impl Serialize for IpamSuccessReply {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		SuccessFields {
+			cni_version: &self.cni_version,
+			interfaces: None,
+			ips: &self.ips,
+			routes: &self.routes,
+			dns: &self.dns,
+			pool: self.pool.as_deref(),
+			specific: &self.specific,
+		}
+		.serialize(serializer)
+	}
+}
+
+/// The fields of either success reply, serialized in the shape of `cni_version`.
+///
+/// Before 1.0.0, entries in `ips` have a `version` field.
+struct SuccessFields<'r> {
+	cni_version: &'r CniVersion,
+	interfaces: Option<&'r [Interface]>,
+	ips: &'r [Ip],
+	routes: &'r [Route],
+	dns: &'r Dns,
+	pool: Option<&'r str>,
+	specific: &'r HashMap<String, Value>,
+}
+
+impl Serialize for SuccessFields<'_> {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		let SuccessFields {
+			cni_version,
+			interfaces,
+			ips,
+			routes,
+			dns,
+			pool,
+			specific,
+		} = *self;
+
+		let mut map = serializer.serialize_map(None)?;
+		map.serialize_entry("cniVersion", &cni_version.to_string())?;
+		if let Some(interfaces) = interfaces {
+			map.serialize_entry("interfaces", interfaces)?;
+		}
+
+		if cni_version.major == 0 {
+			let ips: Vec<LegacyIp> = ips
+				.iter()
+				.map(|ip| LegacyIp {
+					version: if ip.address.is_ipv4() { "4" } else { "6" },
+					ip,
+				})
+				.collect();
+			map.serialize_entry("ips", &ips)?;
+		} else {
+			map.serialize_entry("ips", ips)?;
+		}
+
+		map.serialize_entry("routes", routes)?;
+		map.serialize_entry("dns", dns)?;
+		if let Some(pool) = pool {
+			map.serialize_entry("pool", pool)?;
+		}
+		for (key, value) in specific {
+			map.serialize_entry(key, value)?;
+		}
+		map.end()
+	}
+}
+
+/// An IP entry in the pre-1.0.0 shape.
+#[derive(Serialize)]
+struct LegacyIp<'ip> {
+	version: &'static str,
+	#[serde(flatten)]
+	ip: &'ip Ip,
+}
+
+/// Either of the success reply types, for when the shape isn't known ahead.
+///
+/// This is deserialised as a [`SuccessReply`] if the `interfaces` field is
+/// present, and as an [`IpamSuccessReply`] otherwise, as the types are
+/// otherwise identical. It serialises as the contained reply.
+///
+/// See [`delegate_any()`][crate::delegation::delegate_any].
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum AnyReply {
+	/// A full success reply.
+	Success(SuccessReply),
+
+	/// An abbreviated IPAM success reply.
+	Ipam(IpamSuccessReply),
+}
+
+impl AnyReply {
+	/// The CNI version of the reply.
+	pub fn cni_version(&self) -> &CniVersion {
+		match self {
+			Self::Success(r) => &r.cni_version,
+			Self::Ipam(r) => &r.cni_version,
+		}
+	}
+
+	/// The IPs of the reply.
+	pub fn ips(&self) -> &[Ip] {
+		match self {
+			Self::Success(r) => &r.ips,
+			Self::Ipam(r) => &r.ips,
+		}
+	}
+
+	/// The routes of the reply.
+	pub fn routes(&self) -> &[Route] {
+		match self {
+			Self::Success(r) => &r.routes,
+			Self::Ipam(r) => &r.routes,
+		}
+	}
+
+	/// The DNS section of the reply.
+	pub fn dns(&self) -> &Dns {
+		match self {
+			Self::Success(r) => &r.dns,
+			Self::Ipam(r) => &r.dns,
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for AnyReply {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		use serde::de::Error;
+
+		let value = Value::deserialize(deserializer)?;
+		if value.get("interfaces").is_some() {
+			serde_json::from_value(value)
+				.map(Self::Success)
+				.map_err(Error::custom)
+		} else {
+			serde_json::from_value(value)
+				.map(Self::Ipam)
+				.map_err(Error::custom)
+		}
+	}
+}
+
+impl<'de> ReplyPayload<'de> for AnyReply {}
+
 /// Interface structure for success reply types.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -176,7 +669,7 @@ pub struct Interface {
 }
 
 /// IP structure for success reply types.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct Ip {
 	/// The IP address.
@@ -195,7 +688,7 @@ pub struct Ip {
 }
 
 /// Route structure for success reply types.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct Route {
 	/// The destination of the route.
@@ -209,14 +702,364 @@ pub struct Route {
 	pub gw: Option<IpAddr>,
 }
 
+/// Removes the `version` field of a pre-1.0.0 IP entry, checking it first.
+fn strip_ip_version(ip: &mut Value) -> Result<(), CniError> {
+	let version = match ip.as_object_mut().and_then(|ip| ip.remove("version")) {
+		Some(version) => version,
+		None => return Ok(()),
+	};
+
+	let address = ip
+		.get("address")
+		.and_then(Value::as_str)
+		.unwrap_or_default();
+	let matches = match version.as_str() {
+		Some("4") => !address.contains(':'),
+		Some("6") => address.contains(':'),
+		_ => false,
+	};
+
+	if matches {
+		Ok(())
+	} else {
+		Err(CniError::InvalidField {
+			field: "prevResult.ips[].version",
+			expected: "\"4\" or \"6\", matching the address",
+			value: version,
+		})
+	}
+}
+
 /// Output the reply as JSON on STDOUT and exit.
+///
+/// For success replies, consider calling
+/// [`SuccessReply::validate()`] first.
 pub fn reply<'de, T>(result: T) -> !
 where
 	T: ReplyPayload<'de>,
 {
 	debug!("replying with {:#?}", result);
-	serde_json::to_writer(stdout(), &result)
-		.expect("Error writing result to stdout... chances are you won't get this either");
+	if let Err(err) = write_reply(&result) {
+		if err.kind() == ErrorKind::BrokenPipe {
+			// the runtime has gone away or already has what it needs
+			debug!("stdout closed before the reply was written: {}", err);
+		} else {
+			eprintln!("Error writing result to stdout: {}", err);
+		}
+	}
 
 	exit(result.code());
 }
+
+/// Writes the reply to STDOUT in one go.
+///
+/// The JSON is serialized fully before anything is written, so that a failure
+/// to serialize doesn't leave a truncated reply, and so that the reply isn't
+/// interleaved with other writes to the same file.
+fn write_reply<T: Serialize>(result: &T) -> io::Result<()> {
+	let json = serde_json::to_vec(result)?;
+	let mut stdout = stdout().lock();
+	stdout.write_all(&json)?;
+	stdout.flush()
+}
+
+#[test]
+fn test_from_prev_or_default() {
+	let config: NetworkConfig = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"name": "test",
+		"type": "test",
+	}))
+	.unwrap();
+	let reply = SuccessReply::from_prev_or_default(&config).unwrap();
+	assert_eq!(reply.cni_version, config.cni_version);
+	assert!(reply.ips.is_empty());
+
+	let config: NetworkConfig = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"name": "test",
+		"type": "test",
+		"prevResult": { "ips": "not an array" },
+	}))
+	.unwrap();
+	assert!(matches!(
+		SuccessReply::from_prev_or_default(&config),
+		Err(CniError::Json(_))
+	));
+}
+
+#[test]
+fn test_reply_version_spelling() {
+	let config: NetworkConfig = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0",
+		"name": "test",
+		"type": "test",
+	}))
+	.unwrap();
+	let reply = SuccessReply::from_prev_or_default(&config).unwrap();
+	assert_eq!(serde_json::to_value(&reply).unwrap()["cniVersion"], "1.0");
+	let reply = CniError::MissingInput.into_reply(config.cni_version.clone());
+	assert_eq!(serde_json::to_value(&reply).unwrap()["cniVersion"], "1.0");
+
+	// replies which aren't built from that input are unaffected
+	let reply = IpamSuccessReply::single(
+		Version::new(1, 0, 0),
+		"10.0.0.2/24".parse().unwrap(),
+		None,
+		false,
+	);
+	assert_eq!(serde_json::to_value(&reply).unwrap()["cniVersion"], "1.0.0");
+}
+
+#[test]
+fn test_dns_reply_default_is_empty() {
+	assert_eq!(serde_json::to_string(&DnsReply::default()).unwrap(), "{}");
+}
+
+#[test]
+fn test_any_reply() {
+	let reply: AnyReply = serde_json::from_str(
+		r#"{"cniVersion": "1.0.0", "interfaces": [{"name": "eth0", "sandbox": "/var/run/netns/test"}], "dns": {}, "ips": [{"address": "10.0.0.2/24", "interface": 0}]}"#,
+	)
+	.unwrap();
+	assert!(matches!(reply, AnyReply::Success(_)));
+	assert_eq!(reply.ips().len(), 1);
+
+	let reply: AnyReply = serde_json::from_str(
+		r#"{"cniVersion": "1.0.0", "ips": [{"address": "10.0.0.2/24"}], "routes": [{"dst": "0.0.0.0/0"}]}"#,
+	)
+	.unwrap();
+	assert!(matches!(reply, AnyReply::Ipam(_)));
+	assert_eq!(reply.routes().len(), 1);
+}
+
+#[test]
+fn test_error_reply_new() {
+	let reply = ErrorReply::new(Version::new(1, 0, 0), 120, "oops", "details".into()).unwrap();
+	assert_eq!(reply.code(), 120);
+	assert!(ErrorReply::new(Version::new(1, 0, 0), 7, "bad config", String::new()).is_ok());
+	assert!(matches!(
+		ErrorReply::new(Version::new(1, 0, 0), 0, "success?", String::new()),
+		Err(CniError::InvalidField { field: "code", .. })
+	));
+	assert!(ErrorReply::new(Version::new(1, 0, 0), 256, "too big", String::new()).is_err());
+	assert!(ErrorReply::new(Version::new(1, 0, 0), -1, "negative", String::new()).is_err());
+}
+
+#[test]
+fn test_from_prev_result_legacy() {
+	let version = Version::new(0, 4, 0);
+	let reply = SuccessReply::from_prev_result(
+		serde_json::json!({
+			"cniVersion": "0.4.0",
+			"interfaces": [{"name": "eth0", "sandbox": "/var/run/netns/test"}],
+			"ips": [
+				{"version": "4", "address": "10.1.0.5/16", "gateway": "10.1.0.1", "interface": 0},
+				{"version": "6", "address": "2001:db8::5/64", "interface": 0},
+			],
+		}),
+		&version,
+	)
+	.unwrap();
+	assert_eq!(reply.ips.len(), 2);
+	assert_eq!(reply.ips[0].gateway, Some("10.1.0.1".parse().unwrap()));
+	assert!(reply.ips[1].address.is_ipv6());
+
+	assert!(matches!(
+		SuccessReply::from_prev_result(
+			serde_json::json!({
+				"cniVersion": "0.4.0",
+				"ips": [{"version": "6", "address": "10.1.0.5/16"}],
+			}),
+			&version,
+		),
+		Err(CniError::InvalidField { .. })
+	));
+}
+
+#[test]
+fn test_legacy_ip_output() {
+	let mut reply = IpamSuccessReply {
+		cni_version: Version::new(0, 4, 0).into(),
+		ips: vec![Ip {
+			address: "10.1.0.5/16".parse().unwrap(),
+			gateway: None,
+			interface: None,
+		}],
+		routes: Vec::new(),
+		dns: Default::default(),
+		pool: None,
+		specific: Default::default(),
+	};
+	let json = serde_json::to_value(&reply).unwrap();
+	assert_eq!(
+		json["ips"],
+		serde_json::json!([{"version": "4", "address": "10.1.0.5/16"}])
+	);
+	assert!(SuccessReply::from_prev_result(json, &reply.cni_version).is_ok());
+
+	reply.cni_version = Version::new(1, 0, 0).into();
+	assert_eq!(
+		serde_json::to_value(&reply).unwrap()["ips"],
+		serde_json::json!([{"address": "10.1.0.5/16"}])
+	);
+}
+
+#[test]
+fn test_success_reply_validate() {
+	let mut reply: SuccessReply = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"interfaces": [{"name": "eth0", "sandbox": "/var/run/netns/test"}],
+		"ips": [{"address": "10.0.0.2/24", "gateway": "10.0.0.1", "interface": 0}],
+		"routes": [{"dst": "0.0.0.0/0", "gw": "10.0.0.1"}],
+		"dns": {},
+	}))
+	.unwrap();
+	assert!(reply.validate().is_ok());
+
+	reply.ips[0].interface = Some(1);
+	assert!(matches!(
+		reply.validate(),
+		Err(CniError::InvalidField {
+			field: "ips[].interface",
+			..
+		})
+	));
+
+	reply.ips[0].interface = Some(0);
+	reply.routes[0].gw = Some("fe80::1".parse().unwrap());
+	assert!(matches!(
+		reply.validate(),
+		Err(CniError::InvalidField {
+			field: "routes[].gw",
+			..
+		})
+	));
+}
+
+#[test]
+fn test_ipam_pool() {
+	let reply: SuccessReply = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"ips": [{"address": "10.1.0.5/16"}],
+		"dns": {},
+		"pool": "default",
+	}))
+	.unwrap();
+	let mut ipam = reply.try_into_ipam().unwrap();
+	assert_eq!(ipam.pool.as_deref(), Some("default"));
+	assert!(ipam.specific.is_empty());
+
+	let json = serde_json::to_value(&ipam).unwrap();
+	assert_eq!(json["pool"], "default");
+	let parsed: IpamSuccessReply = serde_json::from_value(json).unwrap();
+	assert_eq!(parsed.pool.as_deref(), Some("default"));
+
+	ipam.pool = None;
+	let json = serde_json::to_value(&ipam).unwrap();
+	assert!(json.get("pool").is_none());
+}
+
+#[test]
+fn test_ipam_merge() {
+	let mut first: IpamSuccessReply = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"ips": [{"address": "10.1.0.5/16", "gateway": "10.1.0.1"}],
+		"routes": [{"dst": "0.0.0.0/0", "gw": "10.1.0.1"}],
+		"dns": {"nameservers": ["10.1.0.1"]},
+		"pool": "first",
+		"source": "first",
+	}))
+	.unwrap();
+
+	// carries the first IP through, with a different gateway
+	let second: IpamSuccessReply = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"ips": [
+			{"address": "10.1.0.5/16"},
+			{"address": "fd00::5/64"},
+		],
+		"routes": [
+			{"dst": "0.0.0.0/0", "gw": "10.1.0.1"},
+			{"dst": "::/0"},
+		],
+		"dns": {"nameservers": ["10.1.0.1", "fd00::1"]},
+		"pool": "second",
+		"source": "second",
+	}))
+	.unwrap();
+
+	first.merge(second);
+	assert_eq!(
+		first
+			.ips
+			.iter()
+			.map(|ip| (ip.address.to_string(), ip.gateway))
+			.collect::<Vec<_>>(),
+		vec![
+			("10.1.0.5/16".to_string(), Some("10.1.0.1".parse().unwrap())),
+			("fd00::5/64".to_string(), None),
+		]
+	);
+	assert_eq!(first.routes.len(), 2);
+	assert_eq!(first.dns.nameservers.len(), 2);
+	assert_eq!(first.pool.as_deref(), Some("first"));
+	assert_eq!(first.specific["source"], "second");
+}
+
+#[test]
+fn test_ipam_single() {
+	let reply = IpamSuccessReply::single(
+		Version::new(1, 0, 0),
+		"fd00::5/64".parse().unwrap(),
+		Some("fd00::1".parse().unwrap()),
+		true,
+	);
+	assert_eq!(reply.ips.len(), 1);
+	assert_eq!(reply.ips[0].gateway, Some("fd00::1".parse().unwrap()));
+	assert_eq!(
+		reply.routes,
+		vec![Route {
+			dst: "::/0".parse().unwrap(),
+			gw: Some("fd00::1".parse().unwrap()),
+		}]
+	);
+
+	let reply = IpamSuccessReply::single(
+		Version::new(1, 0, 0),
+		"10.0.0.5/24".parse().unwrap(),
+		None,
+		false,
+	);
+	assert!(reply.routes.is_empty());
+}
+
+#[test]
+fn test_attach_ips_to_interface() {
+	let mut reply: SuccessReply = serde_json::from_value(serde_json::json!({
+		"cniVersion": "1.0.0",
+		"interfaces": [
+			{"name": "lo", "sandbox": "/var/run/netns/test"},
+			{"name": "eth0", "sandbox": "/var/run/netns/test"},
+		],
+		"ips": [{"address": "127.0.0.1/8", "interface": 0}],
+		"dns": {},
+	}))
+	.unwrap();
+
+	let mut ipam = IpamSuccessReply::single(
+		Version::new(1, 0, 0),
+		"10.0.0.5/24".parse().unwrap(),
+		Some("10.0.0.1".parse().unwrap()),
+		true,
+	);
+	ipam.dns.nameservers.push("10.0.0.1".parse().unwrap());
+
+	reply.attach_ips_to_interface(ipam, 1);
+	assert_eq!(reply.ips.len(), 2);
+	assert_eq!(reply.ips[1].address, "10.0.0.5/24".parse().unwrap());
+	assert_eq!(reply.ips[1].interface, Some(1));
+	assert_eq!(reply.routes.len(), 1);
+	assert_eq!(reply.dns.nameservers.len(), 1);
+	assert!(reply.validate().is_ok());
+}
@@ -1,25 +1,130 @@
 //! Mostly internal types for handling versions.
 
-use std::{collections::HashSet, str::FromStr};
+use std::{collections::HashSet, fmt, ops::Deref, str::FromStr};
 
-use semver::{Version, VersionReq};
+use semver::{SemVerError, Version, VersionReq};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::{
 	error::CniError,
 	reply::{reply, ReplyPayload},
-	Cni,
+	Cni, Command,
 };
 
 pub const COMPATIBLE_VERSIONS: &str = "=0.4.0||^1.0.0";
 pub const SUPPORTED_VERSIONS: &[&str] = &["0.4.0", "1.0.0"];
 
+/// A CNI spec version, as spelled in the input.
+///
+/// Some runtimes send two-component versions like `1.0`, which are not valid
+/// semver but are parsed here as `1.0.0`. As replies must echo the version the
+/// runtime sent, and some runtimes compare it literally, the original spelling
+/// is kept alongside the version, and used when serializing it.
+///
+/// This dereferences to the [`Version`], and compares equal to it regardless
+/// of the spelling.
+#[derive(Clone, Debug)]
+pub struct CniVersion {
+	/// The parsed version.
+	pub version: Version,
+
+	/// The spelling of the version, if it differs from the canonical one.
+	pub spelling: Option<String>,
+}
+
+impl CniVersion {
+	/// Parses a version, accepting two-component versions like `0.3` as `0.3.0`.
+	///
+	/// The spelling is kept if it is not the canonical one.
+	pub fn parse(s: &str) -> Result<Self, SemVerError> {
+		let version = parse_version(s)?;
+		let spelling = if version.to_string() == s {
+			None
+		} else {
+			Some(s.to_owned())
+		};
+		Ok(Self { version, spelling })
+	}
+}
+
+impl From<Version> for CniVersion {
+	fn from(version: Version) -> Self {
+		Self {
+			version,
+			spelling: None,
+		}
+	}
+}
+
+impl From<CniVersion> for Version {
+	fn from(v: CniVersion) -> Self {
+		v.version
+	}
+}
+
+impl Deref for CniVersion {
+	type Target = Version;
+
+	fn deref(&self) -> &Self::Target {
+		&self.version
+	}
+}
+
+impl PartialEq for CniVersion {
+	fn eq(&self, other: &Self) -> bool {
+		self.version == other.version
+	}
+}
+
+impl Eq for CniVersion {}
+
+impl PartialEq<Version> for CniVersion {
+	fn eq(&self, other: &Version) -> bool {
+		&self.version == other
+	}
+}
+
+impl fmt::Display for CniVersion {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.spelling {
+			Some(spelling) => f.write_str(spelling),
+			None => self.version.fmt(f),
+		}
+	}
+}
+
+impl FromStr for CniVersion {
+	type Err = SemVerError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::parse(s)
+	}
+}
+
+impl Serialize for CniVersion {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		self.to_string().serialize(serializer)
+	}
+}
+
+impl<'de> Deserialize<'de> for CniVersion {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		use serde::de::Error;
+		let j = String::deserialize(deserializer)?;
+		Self::parse(&j).map_err(Error::custom)
+	}
+}
+
 #[derive(Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct VersionPayload {
-	#[serde(deserialize_with = "deserialize_version")]
-	#[serde(serialize_with = "serialize_version")]
-	pub cni_version: Version,
+	pub cni_version: CniVersion,
 }
 
 impl Cni {
@@ -34,21 +139,27 @@ impl Cni {
 		}
 	}
 
-	pub(crate) fn handle_version(version: Version) -> ! {
-		let mut supported_versions = SUPPORTED_VERSIONS
-			.iter()
-			.map(|v| Version::parse(*v))
-			.collect::<Result<HashSet<_>, _>>()
-			.unwrap();
+	pub(crate) fn handle_version(version: CniVersion) -> ! {
+		let mut supported_versions: HashSet<_> = supported_versions().into_iter().collect();
 
 		let supported = Self::check_version(&version).is_ok();
 		if supported {
-			supported_versions.insert(version.clone());
+			supported_versions.insert(version.version.clone());
 		}
 
+		let supported_commands = if *version >= Version::new(1, 0, 0) {
+			[Command::Add, Command::Del, Command::Check, Command::Version]
+				.iter()
+				.map(|c| c.as_ref().to_owned())
+				.collect()
+		} else {
+			Vec::new()
+		};
+
 		reply(VersionReply {
 			cni_version: version,
 			supported_versions: supported_versions.into_iter().collect(),
+			supported_commands,
 		});
 	}
 }
@@ -64,23 +175,51 @@ impl Cni {
 #[serde(rename_all = "camelCase")]
 pub struct VersionReply {
 	/// The CNI version of the plugin input config.
-	#[serde(deserialize_with = "deserialize_version")]
-	#[serde(serialize_with = "serialize_version")]
-	pub cni_version: Version,
+	pub cni_version: CniVersion,
 
 	/// The versions this plugin supports.
 	#[serde(deserialize_with = "deserialize_version_list")]
 	#[serde(serialize_with = "serialize_version_list")]
 	pub supported_versions: Vec<Version>,
+
+	/// The commands this plugin supports.
+	///
+	/// This is not in the spec, but lets runtimes know which commands they can
+	/// skip sending (e.g. GC and STATUS, which this library doesn't support).
+	/// It is only filled in for spec versions 1.0.0 and up, and omitted when
+	/// empty, so the reply is unchanged for older versions.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub supported_commands: Vec<String>,
 }
 
 impl<'de> ReplyPayload<'de> for VersionReply {}
 
-pub(crate) fn serialize_version<S>(version: &Version, serializer: S) -> Result<S::Ok, S::Error>
-where
-	S: Serializer,
-{
-	version.to_string().serialize(serializer)
+impl VersionReply {
+	/// Picks the highest of the `offered` versions which the plugin supports.
+	///
+	/// This is the negotiation of spec 1.1, where a configuration list offers
+	/// several versions in `cniVersions`, and the runtime uses the highest one
+	/// it and the plugin both support. Pass the versions the runtime supports
+	/// out of those offered.
+	///
+	/// Returns `None` if there's no version in common.
+	pub fn negotiate(&self, offered: &[Version]) -> Option<Version> {
+		offered
+			.iter()
+			.filter(|version| self.supported_versions.contains(version))
+			.max()
+			.cloned()
+	}
+}
+
+/// The [`SUPPORTED_VERSIONS`], parsed.
+pub(crate) fn supported_versions() -> Vec<Version> {
+	SUPPORTED_VERSIONS
+		.iter()
+		.map(|v| Version::parse(*v))
+		.collect::<Result<_, _>>()
+		// UNWRAP: static list of versions which are known to be valid
+		.unwrap()
 }
 
 pub(crate) fn serialize_version_list<S>(list: &[Version], serializer: S) -> Result<S::Ok, S::Error>
@@ -93,13 +232,24 @@ where
 		.serialize(serializer)
 }
 
-pub(crate) fn deserialize_version<'de, D>(deserializer: D) -> Result<Version, D::Error>
-where
-	D: Deserializer<'de>,
-{
-	use serde::de::Error;
-	let j = String::deserialize(deserializer)?;
-	Version::from_str(&j).map_err(Error::custom)
+/// Parses a version, accepting two-component versions like `0.3` as `0.3.0`.
+///
+/// Some runtimes send those, which are not valid semver but valid in practice.
+/// Use [`CniVersion::parse()`] to also keep the original spelling.
+pub(crate) fn parse_version(s: &str) -> Result<Version, SemVerError> {
+	Version::from_str(s).or_else(|err| {
+		let mut parts = s.split('.');
+		match (parts.next(), parts.next(), parts.next()) {
+			(Some(major), Some(minor), None)
+				if [major, minor]
+					.iter()
+					.all(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())) =>
+			{
+				Version::from_str(&format!("{}.0", s))
+			}
+			_ => Err(err),
+		}
+	})
 }
 
 pub(crate) fn deserialize_version_list<'de, D>(deserializer: D) -> Result<Vec<Version>, D::Error>
@@ -109,6 +259,55 @@ where
 	use serde::de::Error;
 	let j = Vec::<String>::deserialize(deserializer)?;
 	j.iter()
-		.map(|s| Version::from_str(s).map_err(Error::custom))
+		.map(|s| parse_version(s).map_err(Error::custom))
 		.collect()
 }
+
+#[test]
+fn test_parse_version() {
+	assert_eq!(parse_version("1.0.0").unwrap(), Version::new(1, 0, 0));
+	assert_eq!(parse_version("0.3").unwrap(), Version::new(0, 3, 0));
+	assert_eq!(parse_version("1.0").unwrap(), Version::new(1, 0, 0));
+	assert_eq!(
+		parse_version("1.0.0-rc1").unwrap(),
+		Version::parse("1.0.0-rc1").unwrap()
+	);
+	assert!(parse_version("1").is_err());
+	assert!(parse_version("1.").is_err());
+	assert!(parse_version("1.0-rc1").is_err());
+	assert!(parse_version("latest").is_err());
+}
+
+#[test]
+fn test_version_spelling() {
+	let payload: VersionPayload =
+		serde_json::from_value(serde_json::json!({ "cniVersion": "0.9" })).unwrap();
+	assert_eq!(payload.cni_version, Version::new(0, 9, 0));
+	assert_eq!(payload.cni_version.to_string(), "0.9");
+	assert_eq!(
+		serde_json::to_value(&payload.cni_version).unwrap(),
+		serde_json::json!("0.9")
+	);
+
+	// the spelling stays with the value it was parsed into
+	assert_eq!(Version::new(0, 9, 0).to_string(), "0.9.0");
+	assert_eq!(CniVersion::from(Version::new(0, 9, 0)).to_string(), "0.9.0");
+	assert_eq!(CniVersion::parse("0.9.0").unwrap().spelling, None);
+}
+
+#[test]
+fn test_negotiate() {
+	let v = |s| Version::parse(s).unwrap();
+	let reply = VersionReply {
+		cni_version: v("1.0.0").into(),
+		supported_versions: vec![v("0.4.0"), v("1.0.0")],
+		supported_commands: Vec::new(),
+	};
+
+	assert_eq!(
+		reply.negotiate(&[v("0.4.0"), v("1.0.0"), v("1.1.0")]),
+		Some(v("1.0.0"))
+	);
+	assert_eq!(reply.negotiate(&[v("0.4.0"), v("0.3.1")]), Some(v("0.4.0")));
+	assert_eq!(reply.negotiate(&[v("1.1.0")]), None);
+}
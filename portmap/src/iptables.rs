@@ -0,0 +1,127 @@
+//! Port mappings with iptables, as a fallback for hosts without nftables.
+//!
+//! Each container gets its own chain in the `nat` table, which is jumped to
+//! from the PREROUTING (for traffic from outside) and OUTPUT (for traffic from
+//! the host itself) chains, with a comment of the container ID.
+
+use cni_plugin::error::CniError;
+use log::debug;
+
+use crate::{exec, Mapping};
+
+const FAMILIES: &[(&str, bool)] = &[("iptables", false), ("ip6tables", true)];
+const HOOKS: &[&str] = &["PREROUTING", "OUTPUT"];
+
+/// Chain names are limited to 28 characters, this is 23.
+fn chain_name(name: u64) -> String {
+	format!("CNI-PM-{:016X}", name)
+}
+
+fn jump<'a>(op: &'a str, hook: &'a str, chain: &'a str, comment: &'a str) -> [&'a str; 14] {
+	[
+		"-t",
+		"nat",
+		op,
+		hook,
+		"-m",
+		"addrtype",
+		"--dst-type",
+		"LOCAL",
+		"-m",
+		"comment",
+		"--comment",
+		comment,
+		"-j",
+		chain,
+	]
+}
+
+pub fn add(name: u64, container_id: &str, mappings: &[Mapping]) -> Result<(), CniError> {
+	let chain = chain_name(name);
+	del(name, container_id);
+
+	for (bin, v6) in FAMILIES {
+		let mappings: Vec<&Mapping> = mappings
+			.iter()
+			.filter(|m| m.target.is_ipv6() == *v6)
+			.collect();
+		if mappings.is_empty() {
+			continue;
+		}
+
+		exec(bin, &["-t", "nat", "-N", &chain], None)?;
+		for m in mappings {
+			exec(
+				bin,
+				&[
+					"-t",
+					"nat",
+					"-A",
+					&chain,
+					"-p",
+					m.protocol,
+					"--dport",
+					&m.host_port.to_string(),
+					"-j",
+					"DNAT",
+					"--to-destination",
+					&m.target.to_string(),
+				],
+				None,
+			)?;
+		}
+
+		for hook in HOOKS {
+			exec(bin, &jump("-A", hook, &chain, container_id), None)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Best-effort, as the rules may not (all) exist.
+pub fn del(name: u64, container_id: &str) {
+	let chain = chain_name(name);
+	for (bin, _) in FAMILIES {
+		for hook in HOOKS {
+			if let Err(err) = exec(bin, &jump("-D", hook, &chain, container_id), None) {
+				debug!("while removing jump: {}", err);
+			}
+		}
+
+		for op in &["-F", "-X"] {
+			if let Err(err) = exec(bin, &["-t", "nat", op, &chain], None) {
+				debug!("while removing chain: {}", err);
+			}
+		}
+	}
+}
+
+pub fn check(name: u64, mappings: &[Mapping]) -> Result<(), CniError> {
+	let chain = chain_name(name);
+	for (bin, v6) in FAMILIES {
+		let expected: Vec<&Mapping> = mappings
+			.iter()
+			.filter(|m| m.target.is_ipv6() == *v6)
+			.collect();
+		if expected.is_empty() {
+			continue;
+		}
+
+		let listing = exec(bin, &["-t", "nat", "-S", &chain], None)?;
+		for m in expected {
+			let rule = format!(
+				"-p {} -m {} --dport {} ",
+				m.protocol, m.protocol, m.host_port
+			);
+			if !listing.contains(&rule) {
+				return Err(CniError::Generic(format!(
+					"missing port mapping in {} chain {}: {}",
+					bin, chain, rule
+				)));
+			}
+		}
+	}
+
+	Ok(())
+}
@@ -0,0 +1,242 @@
+use std::{
+	io::{ErrorKind, Write},
+	net::{IpAddr, SocketAddr},
+	process::{Command as Process, Stdio},
+};
+
+use cni_plugin::{
+	config::{NetworkConfig, PortProtocol},
+	error::CniError,
+	logger,
+	reply::{reply, SuccessReply},
+	Cni, Command, Inputs,
+};
+use log::{debug, error, info, warn};
+
+mod iptables;
+mod nftables;
+
+fn main() {
+	logger::install(env!("CARGO_PKG_NAME"));
+	debug!(
+		"{} (CNI post plugin) version {}",
+		env!("CARGO_PKG_NAME"),
+		env!("CARGO_PKG_VERSION")
+	);
+
+	// UNWRAP: unreachable due to using load()
+	let Inputs {
+		command,
+		container_id,
+		ifname,
+		config,
+		..
+	} = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
+
+	let cni_version = config.cni_version.clone(); // for error
+	info!(
+		"{} serving spec v{} for command={}",
+		env!("CARGO_PKG_NAME"),
+		cni_version,
+		command
+	);
+
+	match run(command, &container_id, ifname.as_deref(), &config) {
+		Ok(res) => reply(res),
+		Err(res) => {
+			error!("error: {}", res);
+			reply(res.into_reply(cni_version))
+		}
+	}
+}
+
+fn run(
+	command: Command,
+	container_id: &str,
+	ifname: Option<&str>,
+	config: &NetworkConfig,
+) -> Result<SuccessReply, CniError> {
+	let reply = SuccessReply::passthrough(config)?;
+	let name = rules_name(container_id, &config.name, ifname);
+	debug!("rules name={:016x}", name);
+
+	if matches!(command, Command::Del) {
+		// runtime config may not be provided on delete, so always clean up
+		match Backend::detect()? {
+			Backend::Nftables => nftables::del(name)?,
+			Backend::Iptables => iptables::del(name, container_id),
+		}
+
+		info!("removed port mappings");
+		return Ok(reply);
+	}
+
+	let ports = config
+		.runtime
+		.as_ref()
+		.map(|rt| rt.port_mappings.as_slice())
+		.unwrap_or_default();
+	if ports.is_empty() {
+		info!("no port mappings, nothing to do");
+		return Ok(reply);
+	}
+
+	let ips: Vec<IpAddr> = reply.ips.iter().map(|ip| ip.address.ip()).collect();
+	let targets: Vec<IpAddr> = [
+		ips.iter().find(|ip| ip.is_ipv4()),
+		ips.iter().find(|ip| ip.is_ipv6()),
+	]
+	.iter()
+	.filter_map(|ip| ip.copied())
+	.collect();
+	if targets.is_empty() {
+		return Err(CniError::MissingField("prevResult.ips"));
+	}
+	debug!("container ips={:?}", targets);
+
+	let mut mappings = Vec::with_capacity(ports.len() * targets.len());
+	for port in ports {
+		let protocol = match &port.protocol {
+			None | Some(PortProtocol::Tcp) => "tcp",
+			Some(PortProtocol::Udp) => "udp",
+			Some(other) => {
+				return Err(CniError::InvalidField {
+					field: "runtimeConfig.portMappings[].protocol",
+					expected: "tcp or udp",
+					value: serde_json::to_value(other)?,
+				})
+			}
+		};
+
+		for ip in &targets {
+			mappings.push(Mapping {
+				host_port: port.host_port,
+				protocol,
+				target: SocketAddr::new(*ip, port.container_port),
+			});
+		}
+	}
+	debug!("mappings={:?}", mappings);
+
+	let backend = Backend::detect()?;
+	match command {
+		Command::Add => {
+			match backend {
+				Backend::Nftables => nftables::add(name, &mappings)?,
+				Backend::Iptables => iptables::add(name, container_id, &mappings)?,
+			}
+			info!("installed {} port mappings", mappings.len());
+		}
+		Command::Check => {
+			match backend {
+				Backend::Nftables => nftables::check(name, &mappings)?,
+				Backend::Iptables => iptables::check(name, &mappings)?,
+			}
+			info!("port mapping rules are present");
+		}
+		Command::Del | Command::Version => unreachable!(),
+	}
+
+	Ok(reply)
+}
+
+/// A single forwarding from a host port to a container address.
+#[derive(Clone, Debug)]
+pub struct Mapping {
+	pub host_port: u16,
+	pub protocol: &'static str,
+	pub target: SocketAddr,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Backend {
+	Nftables,
+	Iptables,
+}
+
+impl Backend {
+	/// Prefers nftables, and falls back to iptables if `nft` isn't installed.
+	fn detect() -> Result<Self, CniError> {
+		match Process::new("nft")
+			.arg("--version")
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.status()
+		{
+			Ok(_) => {
+				debug!("using nftables");
+				Ok(Self::Nftables)
+			}
+			Err(err) if err.kind() == ErrorKind::NotFound => {
+				warn!("nft not found, falling back to iptables");
+				Ok(Self::Iptables)
+			}
+			Err(err) => Err(err.into()),
+		}
+	}
+}
+
+/// Derives a stable name for the container's rules on a network.
+///
+/// A container may be attached to several networks, each with its own
+/// mappings, so this hashes the network name and the interface name (if
+/// known) along with the container ID. The parts are NUL-separated so they
+/// can't run into each other.
+///
+/// This is a 64-bit FNV-1a hash, so it fits in iptables' chain name limit and
+/// doesn't change between builds (unlike std's hasher).
+fn rules_name(container_id: &str, network: &str, ifname: Option<&str>) -> u64 {
+	[container_id, network, ifname.unwrap_or_default()]
+		.join("\0")
+		.bytes()
+		.fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+			(hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+		})
+}
+
+/// Runs a program to completion, erroring with its STDERR if it fails.
+fn exec(prog: &str, args: &[&str], stdin: Option<&str>) -> Result<String, CniError> {
+	debug!("running {} {}", prog, args.join(" "));
+	let mut child = Process::new(prog)
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()?;
+
+	if let Some(input) = stdin {
+		debug!("with input: {}", input);
+		// UNWRAP: stdin is piped above
+		child.stdin.take().unwrap().write_all(input.as_bytes())?;
+	}
+
+	let output = child.wait_with_output()?;
+	if output.status.success() {
+		Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+	} else {
+		Err(CniError::Generic(format!(
+			"{} {} failed with {}: {}",
+			prog,
+			args.join(" "),
+			output.status,
+			String::from_utf8_lossy(&output.stderr).trim()
+		)))
+	}
+}
+
+#[test]
+fn test_rules_name() {
+	let name = rules_name("abc", "net1", Some("eth0"));
+	assert_eq!(name, rules_name("abc", "net1", Some("eth0")));
+
+	// another network or interface for the same container gets its own rules
+	assert_ne!(name, rules_name("abc", "net2", Some("eth0")));
+	assert_ne!(name, rules_name("abc", "net1", Some("eth1")));
+	assert_ne!(name, rules_name("abd", "net1", Some("eth0")));
+	assert_ne!(
+		rules_name("abc", "net1", None),
+		rules_name("abcnet", "1", None)
+	);
+}
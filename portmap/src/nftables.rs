@@ -0,0 +1,155 @@
+//! Port mappings with nftables.
+//!
+//! Each container gets its own `ip` and `ip6` tables, with NAT chains hooked at
+//! prerouting (for traffic from outside) and output (for traffic from the host
+//! itself), such that removing the mappings is deleting the tables.
+
+use std::fmt::Write;
+
+use cni_plugin::error::CniError;
+
+use crate::{exec, Mapping};
+
+const FAMILIES: &[(&str, bool)] = &[("ip", false), ("ip6", true)];
+
+fn table_name(name: u64) -> String {
+	format!("cni_portmap_{:016x}", name)
+}
+
+pub fn add(name: u64, mappings: &[Mapping]) -> Result<(), CniError> {
+	exec("nft", &["-f", "-"], Some(&add_script(name, mappings))).map(drop)
+}
+
+/// The nft script which replaces the tables with the mappings.
+fn add_script(name: u64, mappings: &[Mapping]) -> String {
+	let table = table_name(name);
+	let mut script = String::new();
+
+	for (family, v6) in FAMILIES {
+		// adding then deleting clears out any previous rules without erroring
+		// if there weren't any, and it's all one transaction
+		writeln!(script, "add table {} {}", family, table).unwrap();
+		writeln!(script, "delete table {} {}", family, table).unwrap();
+
+		let rules: Vec<String> = mappings
+			.iter()
+			.filter(|m| m.target.is_ipv6() == *v6)
+			.map(|m| {
+				format!(
+					"fib daddr type local {} dport {} dnat to {}",
+					m.protocol, m.host_port, m.target
+				)
+			})
+			.collect();
+		if rules.is_empty() {
+			continue;
+		}
+
+		writeln!(script, "table {} {} {{", family, table).unwrap();
+		for hook in &["prerouting", "output"] {
+			writeln!(script, "\tchain {} {{", hook).unwrap();
+			writeln!(
+				script,
+				"\t\ttype nat hook {} priority -100; policy accept;",
+				hook
+			)
+			.unwrap();
+			for rule in &rules {
+				writeln!(script, "\t\t{}", rule).unwrap();
+			}
+			writeln!(script, "\t}}").unwrap();
+		}
+		writeln!(script, "}}").unwrap();
+	}
+	// UNWRAPs: writing to a String can't fail
+
+	script
+}
+
+pub fn del(name: u64) -> Result<(), CniError> {
+	let table = table_name(name);
+	let mut script = String::new();
+	for (family, _) in FAMILIES {
+		writeln!(script, "add table {} {}", family, table).unwrap();
+		writeln!(script, "delete table {} {}", family, table).unwrap();
+	}
+	// UNWRAPs: writing to a String can't fail
+
+	exec("nft", &["-f", "-"], Some(&script)).map(drop)
+}
+
+pub fn check(name: u64, mappings: &[Mapping]) -> Result<(), CniError> {
+	let table = table_name(name);
+	for (family, v6) in FAMILIES {
+		let expected: Vec<&Mapping> = mappings
+			.iter()
+			.filter(|m| m.target.is_ipv6() == *v6)
+			.collect();
+		if expected.is_empty() {
+			continue;
+		}
+
+		let listing = exec("nft", &["list", "table", family, &table], None)?;
+		for m in expected {
+			let rule = format!("{} dport {} ", m.protocol, m.host_port);
+			if !listing.contains(&rule) {
+				return Err(CniError::Generic(format!(
+					"missing port mapping in table {} {}: {}",
+					family, table, rule
+				)));
+			}
+		}
+	}
+
+	Ok(())
+}
+
+#[test]
+fn test_add_script() {
+	let mapping = |host_port, target: &str| Mapping {
+		host_port,
+		protocol: "tcp",
+		target: target.parse().unwrap(),
+	};
+
+	let script = add_script(
+		0xabc,
+		&[mapping(8080, "10.0.0.2:80"), mapping(8080, "[fd00::2]:80")],
+	);
+	let chains = |family: &str, target: &str| {
+		format!(
+			"table {} cni_portmap_0000000000000abc {{
+	chain prerouting {{
+		type nat hook prerouting priority -100; policy accept;
+		fib daddr type local tcp dport 8080 dnat to {}
+	}}
+	chain output {{
+		type nat hook output priority -100; policy accept;
+		fib daddr type local tcp dport 8080 dnat to {}
+	}}
+}}
+",
+			family, target, target
+		)
+	};
+	assert_eq!(
+		script,
+		format!(
+			"add table ip cni_portmap_0000000000000abc
+delete table ip cni_portmap_0000000000000abc
+{}add table ip6 cni_portmap_0000000000000abc
+delete table ip6 cni_portmap_0000000000000abc
+{}",
+			chains("ip", "10.0.0.2:80"),
+			chains("ip6", "[fd00::2]:80")
+		)
+	);
+
+	// a family without mappings is only cleared
+	let script = add_script(0xabc, &[mapping(53, "10.0.0.2:53")]);
+	assert!(script.ends_with(
+		"add table ip6 cni_portmap_0000000000000abc
+delete table ip6 cni_portmap_0000000000000abc
+"
+	));
+}
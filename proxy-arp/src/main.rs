@@ -0,0 +1,206 @@
+#[cfg(not(target_os = "linux"))]
+compile_error!("proxy-arp uses netlink, which is only available on Linux");
+
+use std::{fs, net::IpAddr, path::PathBuf};
+
+use async_std::task::{block_on, spawn};
+use cni_plugin::{
+	error::CniError,
+	logger,
+	netlink::{nlerror, resolve_link},
+	reply::{reply, SuccessReply},
+	Cni, Command, Inputs,
+};
+use log::{debug, error, info, warn};
+use rtnetlink::{
+	packet::{rtnl::neighbour::nlas::Nla, NeighbourMessage},
+	Handle, NeighbourHandle,
+};
+
+/// Neighbour flag for proxy entries, from `linux/neighbour.h`.
+const NTF_PROXY: u8 = 0x08;
+
+/// Neighbour state for entries which don't expire, from `linux/neighbour.h`.
+const NUD_PERMANENT: u16 = 0x80;
+
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+
+fn main() {
+	let mut logconfig = logger::default_config();
+	logconfig.add_filter_ignore_str("netlink_proto");
+	logger::with_config(env!("CARGO_PKG_NAME"), logconfig.build());
+
+	debug!(
+		"{} (CNI post plugin) version {}",
+		env!("CARGO_PKG_NAME"),
+		env!("CARGO_PKG_VERSION")
+	);
+
+	// UNWRAP: unreachable due to using load()
+	let Inputs {
+		command, config, ..
+	} = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
+
+	let cni_version = config.cni_version.clone(); // for error
+	info!(
+		"{} serving spec v{} for command={}",
+		env!("CARGO_PKG_NAME"),
+		cni_version,
+		command
+	);
+
+	let res: Result<SuccessReply, CniError> = block_on(async move {
+		let uplink = config
+			.specific
+			.get("uplink")
+			.ok_or(CniError::MissingField("uplink"))
+			.and_then(|val| {
+				val.as_str().ok_or_else(|| CniError::InvalidField {
+					field: "uplink",
+					expected: "string",
+					value: val.clone(),
+				})
+			})?
+			.to_owned();
+		debug!("uplink={:?}", uplink);
+
+		let reply = SuccessReply::passthrough(&config)?;
+		let addresses: Vec<IpAddr> = reply.ips.iter().map(|ip| ip.address.ip()).collect();
+		debug!("container addresses={:?}", addresses);
+		if addresses.is_empty() {
+			if matches!(command, Command::Del) {
+				info!("no addresses in prevResult, nothing to remove");
+				return Ok(reply);
+			}
+
+			return Err(CniError::MissingField("prevResult.ips"));
+		}
+
+		debug!("initialising netlink");
+		let (nlconn, nl, _) = rtnetlink::new_connection()?;
+		spawn(nlconn);
+
+		let link = resolve_link(&nl, &uplink).await?.index;
+		debug!("uplink index={}", link);
+
+		for address in addresses {
+			match command {
+				Command::Add => {
+					enable_sysctl(&uplink, address)?;
+					add_proxy(&nl, link, address).await?;
+					info!("proxying {} on {}", address, uplink);
+				}
+				Command::Del => {
+					if let Err(err) = del_proxy(&nl, link, address).await {
+						warn!("could not remove proxy for {}: {}", address, err);
+					} else {
+						info!("stopped proxying {} on {}", address, uplink);
+					}
+				}
+				Command::Check => {
+					check_sysctl(&uplink, address)?;
+					info!("proxying is enabled for {} on {}", address, uplink);
+				}
+				Command::Version => unreachable!(),
+			}
+		}
+
+		Ok(reply)
+	});
+
+	match res {
+		Ok(res) => reply(res),
+		Err(res) => {
+			error!("error: {}", res);
+			reply(res.into_reply(cni_version))
+		}
+	}
+}
+
+/// The sysctl which enables proxying on the uplink for this address family.
+fn sysctl_path(uplink: &str, address: IpAddr) -> PathBuf {
+	match address {
+		IpAddr::V4(_) => format!("/proc/sys/net/ipv4/conf/{}/proxy_arp", uplink),
+		IpAddr::V6(_) => format!("/proc/sys/net/ipv6/conf/{}/proxy_ndp", uplink),
+	}
+	.into()
+}
+
+fn enable_sysctl(uplink: &str, address: IpAddr) -> Result<(), CniError> {
+	let path = sysctl_path(uplink, address);
+	debug!("writing 1 to {}", path.display());
+	fs::write(path, "1")?;
+	Ok(())
+}
+
+fn check_sysctl(uplink: &str, address: IpAddr) -> Result<(), CniError> {
+	let path = sysctl_path(uplink, address);
+	let value = fs::read_to_string(&path)?;
+	debug!("read {:?} from {}", value, path.display());
+	if value.trim() == "1" {
+		Ok(())
+	} else {
+		Err(CniError::Generic(format!(
+			"{} is not enabled (value={})",
+			path.display(),
+			value.trim()
+		)))
+	}
+}
+
+async fn add_proxy(nl: &Handle, link: u32, address: IpAddr) -> Result<(), CniError> {
+	debug!("first, attempting to delete proxy for {}", address);
+	if let Err(err) = del_proxy(nl, link, address).await {
+		debug!(
+			"pre-emptive delete of proxy for {} failed: {}",
+			address, err
+		);
+	}
+
+	debug!("adding proxy for {}", address);
+	NeighbourHandle::new(nl.clone())
+		.add(link, address)
+		.flags(NTF_PROXY)
+		.state(NUD_PERMANENT)
+		.execute()
+		.await
+		.map_err(nlerror)
+}
+
+async fn del_proxy(nl: &Handle, link: u32, address: IpAddr) -> Result<(), CniError> {
+	let mut neigh = NeighbourMessage::default();
+	neigh.header.ifindex = link;
+	neigh.header.flags = NTF_PROXY;
+	match address {
+		IpAddr::V4(v4) => {
+			neigh.header.family = AF_INET;
+			neigh.nlas.push(Nla::Destination(v4.octets().to_vec()));
+		}
+		IpAddr::V6(v6) => {
+			neigh.header.family = AF_INET6;
+			neigh.nlas.push(Nla::Destination(v6.octets().to_vec()));
+		}
+	}
+
+	debug!("deleting proxy for {}", address);
+	NeighbourHandle::new(nl.clone())
+		.del(neigh)
+		.execute()
+		.await
+		.map_err(nlerror)
+}
+
+#[test]
+fn test_sysctl_path() {
+	assert_eq!(
+		sysctl_path("eth0", "10.0.0.2".parse().unwrap()),
+		PathBuf::from("/proc/sys/net/ipv4/conf/eth0/proxy_arp")
+	);
+	assert_eq!(
+		sysctl_path("eth0", "2001:db8::2".parse().unwrap()),
+		PathBuf::from("/proc/sys/net/ipv6/conf/eth0/proxy_ndp")
+	);
+}
@@ -0,0 +1,256 @@
+#[cfg(not(target_os = "linux"))]
+compile_error!("sysctl uses network namespaces, which are only available on Linux");
+
+use std::{
+	collections::BTreeMap,
+	env::VarError,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use cni_plugin::{
+	config::NetworkConfig,
+	error::CniError,
+	logger,
+	netns::with_netns,
+	reply::{reply, SuccessReply},
+	Cni, Command, Inputs,
+};
+use log::{debug, error, info, warn};
+use serde_json::{Map, Value};
+
+fn main() {
+	logger::install(env!("CARGO_PKG_NAME"));
+	debug!(
+		"{} (CNI post plugin) version {}",
+		env!("CARGO_PKG_NAME"),
+		env!("CARGO_PKG_VERSION")
+	);
+
+	// UNWRAP: unreachable due to using load()
+	let Inputs {
+		command,
+		ifname,
+		netns,
+		config,
+		..
+	} = Cni::load_with_version(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+		.into_inputs()
+		.unwrap();
+
+	let cni_version = config.cni_version.clone(); // for error
+	info!(
+		"{} serving spec v{} for command={}",
+		env!("CARGO_PKG_NAME"),
+		cni_version,
+		command
+	);
+
+	match run(command, ifname.as_deref(), netns.as_deref(), &config) {
+		Ok(res) => reply(res),
+		Err(res) => {
+			error!("error: {}", res);
+			reply(res.into_reply(cni_version))
+		}
+	}
+}
+
+fn run(
+	command: Command,
+	ifname: Option<&str>,
+	netns: Option<&Path>,
+	config: &NetworkConfig,
+) -> Result<SuccessReply, CniError> {
+	let mut reply = SuccessReply::passthrough(config)?;
+
+	if matches!(command, Command::Del) {
+		// restore what was recorded on add, if the runtime kept it
+		let original = match reply.specific.get("sysctls") {
+			Some(Value::Object(original)) if !original.is_empty() => original.clone(),
+			_ => {
+				info!("no original sysctl values in prevResult, nothing to restore");
+				return Ok(reply);
+			}
+		};
+
+		let netns = match netns {
+			Some(netns) => netns,
+			None => {
+				info!("no netns given, nothing to restore");
+				return Ok(reply);
+			}
+		};
+
+		let restored = with_netns(netns, || {
+			for (key, value) in original {
+				let value = value.as_str().unwrap_or_default();
+				if let Err(err) = write_sysctl(&key, value) {
+					warn!("could not restore {} to {:?}: {}", key, value, err);
+				} else {
+					info!("restored {} to {:?}", key, value);
+				}
+			}
+		});
+		if let Err(err) = restored {
+			warn!("cannot enter netns, it may be gone already: {}", err);
+		}
+
+		return Ok(reply);
+	}
+
+	let sysctls: BTreeMap<String, String> = config
+		.specific
+		.get("sysctls")
+		.map(|v| serde_json::from_value(v.clone()))
+		.transpose()?
+		.unwrap_or_default();
+	debug!("sysctls={:?}", sysctls);
+	if sysctls.is_empty() {
+		info!("no sysctls, nothing to do");
+		return Ok(reply);
+	}
+
+	let keys = sysctls
+		.into_iter()
+		.map(|(key, value)| Ok((sysctl_key(&key, ifname)?, value)))
+		.collect::<Result<Vec<(String, String)>, CniError>>()?;
+
+	let netns = netns.ok_or(CniError::MissingEnv {
+		var: "CNI_NETNS",
+		err: VarError::NotPresent,
+	})?;
+
+	if matches!(command, Command::Check) {
+		with_netns(netns, || check_all(&keys))??;
+		info!("all {} sysctls are set", keys.len());
+		return Ok(reply);
+	}
+
+	let original = with_netns(netns, || apply_all(keys))??;
+
+	let existing = reply
+		.specific
+		.entry("sysctls".into())
+		.or_insert_with(|| Value::Object(Map::new()));
+	if let Some(existing) = existing.as_object_mut() {
+		// keep the earliest original value if the same sysctl was set before
+		for (key, value) in original {
+			existing.entry(key).or_insert(value);
+		}
+	} else {
+		return Err(CniError::InvalidField {
+			field: "prevResult.sysctls",
+			expected: "object",
+			value: existing.clone(),
+		});
+	}
+
+	Ok(reply)
+}
+
+fn check_all(keys: &[(String, String)]) -> Result<(), CniError> {
+	for (key, value) in keys {
+		let current = read_sysctl(key)?;
+		if &current != value {
+			return Err(CniError::Generic(format!(
+				"sysctl {} is {:?}, expected {:?}",
+				key, current, value
+			)));
+		}
+	}
+
+	Ok(())
+}
+
+/// Sets the sysctls, returning their original values.
+fn apply_all(keys: Vec<(String, String)>) -> Result<Map<String, Value>, CniError> {
+	let mut original = Map::with_capacity(keys.len());
+	for (key, value) in keys {
+		let current = read_sysctl(&key)?;
+		write_sysctl(&key, &value)?;
+		info!("set {} to {:?} (was {:?})", key, value, current);
+		original.insert(key, Value::String(current));
+	}
+
+	Ok(original)
+}
+
+/// Normalises a sysctl name to slash-separated form, with `<if>` replaced.
+///
+/// Dots are replaced before the interface name is inserted, so interface names
+/// containing dots (like VLANs) are kept intact.
+///
+/// Only `net` sysctls are accepted, as the others aren't namespaced: writing
+/// them would change them for the whole host.
+fn sysctl_key(key: &str, ifname: Option<&str>) -> Result<String, CniError> {
+	let mut normal = key.replace('.', "/");
+	if normal.contains("<if>") {
+		let ifname = ifname.ok_or(CniError::MissingEnv {
+			var: "CNI_IFNAME",
+			err: VarError::NotPresent,
+		})?;
+		normal = normal.replace("<if>", ifname);
+	}
+
+	if !normal.starts_with("net/")
+		|| normal
+			.split('/')
+			.any(|part| part.is_empty() || part == "." || part == "..")
+	{
+		return Err(CniError::InvalidField {
+			field: "sysctls",
+			expected: "net sysctl names",
+			value: key.into(),
+		});
+	}
+
+	Ok(normal)
+}
+
+fn sysctl_path(key: &str) -> PathBuf {
+	Path::new("/proc/sys").join(key)
+}
+
+fn read_sysctl(key: &str) -> Result<String, CniError> {
+	let value = fs::read_to_string(sysctl_path(key))?;
+	Ok(value.trim().to_owned())
+}
+
+fn write_sysctl(key: &str, value: &str) -> Result<(), CniError> {
+	debug!("writing {:?} to {}", value, key);
+	fs::write(sysctl_path(key), value)?;
+	Ok(())
+}
+
+#[test]
+fn test_sysctl_key() {
+	assert_eq!(
+		sysctl_key("net.ipv6.conf.<if>.accept_ra", Some("eth0.100")).unwrap(),
+		"net/ipv6/conf/eth0.100/accept_ra"
+	);
+	assert_eq!(
+		sysctl_key("net/ipv4/ip_forward", None).unwrap(),
+		"net/ipv4/ip_forward"
+	);
+	assert!(matches!(
+		sysctl_key("net.ipv6.conf.<if>.accept_ra", None),
+		Err(CniError::MissingEnv { .. })
+	));
+	assert!(matches!(
+		sysctl_key("net/../../etc/passwd", None),
+		Err(CniError::InvalidField { .. })
+	));
+
+	// not namespaced, so would be set on the host
+	for key in &[
+		"kernel.hostname",
+		"vm/swappiness",
+		"fs.file-max",
+		"network.x",
+	] {
+		assert!(matches!(
+			sysctl_key(key, None),
+			Err(CniError::InvalidField { .. })
+		));
+	}
+}